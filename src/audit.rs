@@ -0,0 +1,400 @@
+use crate::config::Audit;
+use anyhow::{bail, Result};
+use glob::glob;
+use serde::{Deserialize, Serialize};
+use std::fs::{create_dir_all, remove_file, rename, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::error;
+
+/// Basename of the current audit log file. Rotated files have a timestamp added.
+const AUDIT_LOG_BASENAME: &str = "audit";
+
+/// One line of the audit log: everything `m1000` decided and did about one event.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    #[serde(with = "time::serde::rfc3339")]
+    pub timestamp: OffsetDateTime,
+    /// Webhook event type, such as `status.created`.
+    pub event: String,
+    pub account_id: String,
+    pub acct: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_id: Option<String>,
+    /// Names of all rules that matched.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rspamd: Option<String>,
+    /// rspamd's total score for the post.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rspamd_score: Option<f64>,
+    /// Why we didn't act on an account that matched rules.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exemption: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub actions: Vec<AuditAction>,
+    /// Error that stopped us from handling the event, if any.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// How long handling the event took, including API calls.
+    pub duration_ms: u64,
+    #[serde(skip, default = "Instant::now")]
+    started: Instant,
+}
+
+/// One moderation action that we attempted.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditAction {
+    /// What we tried to do, such as `report` or `restrict`.
+    pub action: String,
+    /// What we did it with or to, such as a report ID or a blocked domain.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    pub ok: bool,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(event: &str, account_id: &str, acct: &str, status_id: Option<&str>) -> Self {
+        Self {
+            timestamp: OffsetDateTime::now_utc(),
+            event: event.to_string(),
+            account_id: account_id.to_string(),
+            acct: acct.to_string(),
+            status_id: status_id.map(str::to_string),
+            rules: vec![],
+            rspamd: None,
+            rspamd_score: None,
+            exemption: None,
+            actions: vec![],
+            error: None,
+            duration_ms: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// Record the outcome of a moderation action.
+    pub fn action<T>(
+        &mut self,
+        action: impl Into<String>,
+        detail: Option<String>,
+        result: &Result<T>,
+    ) -> &mut Self {
+        self.actions.push(AuditAction {
+            action: action.into(),
+            detail,
+            ok: result.is_ok(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+        self
+    }
+
+    /// Record how handling the event went overall, and how long it took.
+    pub fn finish(&mut self, result: &Result<()>) -> &mut Self {
+        self.error = result.as_ref().err().map(|e| e.to_string());
+        self.duration_ms = self.started.elapsed().as_millis() as u64;
+        self
+    }
+}
+
+/// Append-only JSONL audit log for one domain user, with size- and time-based rotation.
+#[derive(Clone, Debug)]
+pub struct AuditLog {
+    dir: PathBuf,
+    rotation: Audit,
+    file: Arc<Mutex<Option<OpenAuditLog>>>,
+}
+
+#[derive(Debug)]
+struct OpenAuditLog {
+    file: File,
+    size: u64,
+    opened_at: OffsetDateTime,
+}
+
+impl AuditLog {
+    pub fn new(config_dir: &Path, domain: &str, username: &str, rotation: Option<&Audit>) -> Self {
+        Self {
+            dir: user_dir(config_dir, domain, username),
+            rotation: rotation.cloned().unwrap_or_default(),
+            file: Default::default(),
+        }
+    }
+
+    /// Append an entry. Logs errors instead of returning them,
+    /// since failing to write the audit log shouldn't stop moderation.
+    pub async fn record(&self, entry: &AuditEntry) {
+        let dir = self.dir.to_string_lossy().to_string();
+        let mut line = match serde_json::to_string(entry) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Couldn't serialize audit log entry for {dir}: {e}");
+                return;
+            }
+        };
+        line.push('\n');
+
+        // File I/O blocks, so do it off the async runtime.
+        let audit_log = self.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let mut file = audit_log.file.lock().unwrap();
+            let result = audit_log.write(&mut file, &line);
+            if result.is_err() {
+                // Try opening the file again next time.
+                *file = None;
+            }
+            result
+        })
+        .await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("Couldn't write audit log in {dir}: {e}"),
+            Err(e) => error!("Couldn't write audit log in {dir}: {e}"),
+        }
+    }
+
+    fn write(&self, file: &mut Option<OpenAuditLog>, line: &str) -> Result<()> {
+        if let Some(open) = file.as_ref() {
+            let too_big = open.size + line.len() as u64 > self.rotation.max_bytes;
+            let too_old = OffsetDateTime::now_utc() - open.opened_at
+                > Duration::from_secs(self.rotation.max_age_secs);
+            if open.size > 0 && (too_big || too_old) {
+                *file = None;
+                self.rotate()?;
+            }
+        }
+
+        if file.is_none() {
+            *file = Some(self.open()?);
+        }
+        let Some(open) = file.as_mut() else {
+            bail!("Audit log isn't open");
+        };
+        open.file.write_all(line.as_bytes())?;
+        open.file.flush()?;
+        open.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Open the current log file, picking up where a previous run left off.
+    fn open(&self) -> Result<OpenAuditLog> {
+        create_dir_all(&self.dir)?;
+        let path = current_path(&self.dir);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        // Time-based rotation counts from the oldest entry in the file, which is the first one.
+        let opened_at = read_first_entry(&path)?
+            .map(|entry| entry.timestamp)
+            .unwrap_or_else(OffsetDateTime::now_utc);
+        Ok(OpenAuditLog {
+            file,
+            size,
+            opened_at,
+        })
+    }
+
+    /// Move the current log file aside and delete the oldest rotated files past the limit.
+    fn rotate(&self) -> Result<()> {
+        let stamp = OffsetDateTime::now_utc().unix_timestamp_nanos();
+        rename(
+            current_path(&self.dir),
+            self.dir.join(format!("{AUDIT_LOG_BASENAME}.{stamp}.jsonl")),
+        )?;
+
+        let rotated = rotated_paths(&self.dir)?;
+        let excess = rotated.len().saturating_sub(self.rotation.keep);
+        for path in rotated.into_iter().take(excess) {
+            remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Criteria for the `audit` subcommand. Unset criteria match everything.
+#[derive(Clone, Debug, Default)]
+pub struct AuditQuery {
+    /// Matches account ID or handle.
+    pub account: Option<String>,
+    pub rule: Option<String>,
+    pub since: Option<OffsetDateTime>,
+    pub until: Option<OffsetDateTime>,
+}
+
+impl AuditQuery {
+    pub fn is_match(&self, entry: &AuditEntry) -> bool {
+        if let Some(account) = self.account.as_ref() {
+            let account = account.trim_start_matches('@');
+            if entry.account_id != account && !entry.acct.eq_ignore_ascii_case(account) {
+                return false;
+            }
+        }
+        if let Some(rule) = self.rule.as_ref() {
+            if !entry.rules.contains(rule) {
+                return false;
+            }
+        }
+        if let Some(since) = self.since {
+            if entry.timestamp < since {
+                return false;
+            }
+        }
+        if let Some(until) = self.until {
+            if entry.timestamp >= until {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Parse a query date: either a full RFC 3339 timestamp or a `YYYY-MM-DD` date, which means midnight UTC.
+pub fn parse_query_date(s: &str) -> Result<OffsetDateTime> {
+    if let Ok(timestamp) = OffsetDateTime::parse(s, &Rfc3339) {
+        return Ok(timestamp);
+    }
+    Ok(OffsetDateTime::parse(&format!("{s}T00:00:00Z"), &Rfc3339)?)
+}
+
+/// Print all audit log entries for a domain user matching a query, oldest first, as JSONL.
+pub fn audit(config_dir: &Path, domain: &str, username: &str, query: &AuditQuery) -> Result<()> {
+    let dir = user_dir(config_dir, domain, username);
+    let mut paths = rotated_paths(&dir)?;
+    paths.push(current_path(&dir));
+    for path in paths {
+        if !path.exists() {
+            continue;
+        }
+        for entry in read_entries(&path)? {
+            if query.is_match(&entry) {
+                println!("{}", serde_json::to_string(&entry)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn user_dir(config_dir: &Path, domain: &str, username: &str) -> PathBuf {
+    config_dir.join(domain).join(username)
+}
+
+fn current_path(dir: &Path) -> PathBuf {
+    dir.join(format!("{AUDIT_LOG_BASENAME}.jsonl"))
+}
+
+/// Rotated log files, oldest first. The Unix timestamps in their names sort chronologically.
+fn rotated_paths(dir: &Path) -> Result<Vec<PathBuf>> {
+    let pattern = dir.join(format!("{AUDIT_LOG_BASENAME}.*.jsonl"));
+    let Some(pattern) = pattern.to_str() else {
+        bail!(
+            "{glob} couldn't be converted to a string for globbing",
+            glob = pattern.to_string_lossy()
+        );
+    };
+    let mut paths = glob(pattern)?.collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+    Ok(paths)
+}
+
+/// Read all entries from one log file, skipping lines that don't parse,
+/// such as a partial line from a crash.
+fn read_entries(path: &Path) -> Result<Vec<AuditEntry>> {
+    let file = File::open(path)?;
+    let mut entries = vec![];
+    for line in BufReader::new(file).lines() {
+        if let Ok(entry) = serde_json::from_str(&line?) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Read the first entry from a log file without reading the rest,
+/// or `None` if the file is empty or its first line doesn't parse.
+fn read_first_entry(path: &Path) -> Result<Option<AuditEntry>> {
+    let file = File::open(path)?;
+    let Some(line) = BufReader::new(file).lines().next() else {
+        return Ok(None);
+    };
+    Ok(serde_json::from_str(&line?).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{block_on, TempDir};
+
+    #[test]
+    fn test_query() {
+        let mut entry = AuditEntry::new("status.created", "1", "spammer@spam.test", Some("2"));
+        entry.rules.push("casino".to_string());
+        entry.timestamp = parse_query_date("2023-05-02T12:00:00Z").unwrap();
+
+        assert!(AuditQuery::default().is_match(&entry));
+        assert!(AuditQuery {
+            account: Some("@Spammer@spam.test".to_string()),
+            rule: Some("casino".to_string()),
+            since: Some(parse_query_date("2023-05-02").unwrap()),
+            until: Some(parse_query_date("2023-05-03").unwrap()),
+        }
+        .is_match(&entry));
+        assert!(!AuditQuery {
+            rule: Some("poker".to_string()),
+            ..Default::default()
+        }
+        .is_match(&entry));
+        assert!(!AuditQuery {
+            since: Some(parse_query_date("2023-05-03").unwrap()),
+            ..Default::default()
+        }
+        .is_match(&entry));
+    }
+
+    #[test]
+    fn test_rotation() {
+        let config_dir = TempDir::new("audit");
+        let log = AuditLog::new(
+            &config_dir,
+            "example.test",
+            "automod",
+            Some(&Audit {
+                max_bytes: 1,
+                max_age_secs: 60,
+                keep: 1,
+            }),
+        );
+        let entry = AuditEntry::new("status.created", "1", "spammer@spam.test", Some("2"));
+
+        block_on(async {
+            for _ in 0..3 {
+                log.record(&entry).await;
+            }
+        });
+
+        // Every write after the first rotates, and only one rotated file is kept.
+        assert_eq!(1, rotated_paths(&log.dir).unwrap().len());
+        assert_eq!(1, read_entries(&current_path(&log.dir)).unwrap().len());
+
+        // Reopening picks up the size and age of the current file.
+        let open = log.open().unwrap();
+        assert_eq!(
+            std::fs::metadata(current_path(&log.dir)).unwrap().len(),
+            open.size
+        );
+        assert_eq!(entry.timestamp, open.opened_at);
+    }
+}
@@ -0,0 +1,328 @@
+use crate::audit::AuditEntry;
+use crate::checkpoint::Checkpoint;
+use crate::event::account::handle_account;
+use crate::event::status::handle_status;
+use crate::health::Health;
+use crate::interop::api::ApiClient;
+use crate::setup::{ensure_registered, ensure_settings};
+use crate::{init_user_handler, UserHandler};
+use anyhow::Result;
+use clap::Subcommand;
+use mastodon_async::entities::admin;
+use mastodon_async::entities::status::Status;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use time::OffsetDateTime;
+use tracing::{error, info};
+
+/// Maximum page size for timelines and account statuses.
+const STATUSES_PAGE_SIZE: usize = 40;
+
+/// Where `m1000 backfill` gets statuses or accounts from, newest first.
+#[derive(Clone, Debug, Subcommand)]
+pub enum BackfillSource {
+    /// Statuses from the public timeline.
+    Public {
+        /// Only statuses from local accounts.
+        #[arg(long)]
+        local: bool,
+        /// Only statuses from remote accounts.
+        #[arg(long, conflicts_with = "local")]
+        remote: bool,
+    },
+    /// Public statuses with a hashtag.
+    Hashtag {
+        /// Hashtag, without the leading `#`.
+        tag: String,
+    },
+    /// Statuses by one account.
+    Account {
+        /// ID of the account.
+        id: String,
+    },
+    /// Local accounts from the admin account list, for rules about signups.
+    Accounts,
+}
+
+impl BackfillSource {
+    /// Name of this source's checkpoint file. Different sources can be backfilled independently.
+    fn checkpoint_name(&self) -> String {
+        match self {
+            Self::Public { local: true, .. } => "public-local".to_string(),
+            Self::Public { remote: true, .. } => "public-remote".to_string(),
+            Self::Public { .. } => "public".to_string(),
+            Self::Hashtag { tag } => format!(
+                "hashtag-{tag}",
+                tag = tag
+                    .to_lowercase()
+                    .chars()
+                    .filter(|c| c.is_alphanumeric() || *c == '_')
+                    .collect::<String>()
+            ),
+            Self::Account { id } => format!("account-{id}"),
+            Self::Accounts => "accounts".to_string(),
+        }
+    }
+
+    /// Get the page of items just older than `max_id`, or the newest page.
+    async fn page(&self, api: &ApiClient, max_id: Option<&str>) -> Result<Vec<BackfillItem>> {
+        let (path, mut query) = match self {
+            Self::Public { local, remote } => (
+                "/api/v1/timelines/public".to_string(),
+                vec![("local", local.to_string()), ("remote", remote.to_string())],
+            ),
+            Self::Hashtag { tag } => (
+                format!(
+                    "/api/v1/timelines/tag/{tag}",
+                    tag = url::form_urlencoded::byte_serialize(tag.as_bytes()).collect::<String>()
+                ),
+                vec![],
+            ),
+            Self::Account { id } => (format!("/api/v1/accounts/{id}/statuses"), vec![]),
+            Self::Accounts => {
+                return Ok(api
+                    .local_accounts(max_id)
+                    .await?
+                    .into_iter()
+                    .map(|account| BackfillItem::Account(Box::new(account)))
+                    .collect());
+            }
+        };
+        query.push(("limit", STATUSES_PAGE_SIZE.to_string()));
+        if let Some(max_id) = max_id {
+            query.push(("max_id", max_id.to_string()));
+        }
+        Ok(api
+            .statuses(&path, &query)
+            .await?
+            .into_iter()
+            .map(|status| BackfillItem::Status(Box::new(status)))
+            .collect())
+    }
+}
+
+enum BackfillItem {
+    Status(Box<Status>),
+    Account(Box<admin::Account>),
+}
+
+impl BackfillItem {
+    fn id(&self) -> String {
+        match self {
+            Self::Status(status) => status.id.to_string(),
+            Self::Account(account) => account.id.to_string(),
+        }
+    }
+
+    fn created_at(&self) -> OffsetDateTime {
+        match self {
+            Self::Status(status) => status.created_at,
+            Self::Account(account) => account.created_at,
+        }
+    }
+}
+
+/// Everything about a backfill run except where it's from.
+#[derive(Clone, Debug)]
+pub struct BackfillOptions {
+    /// Log and audit what rules would have done, without doing it.
+    pub shadow: bool,
+    /// Stop at items created before this.
+    pub since: Option<OffsetDateTime>,
+    /// Skip items created at or after this.
+    pub until: Option<OffsetDateTime>,
+    /// Stop after handling this many items in this run.
+    pub limit: Option<usize>,
+    /// Wait this long between pages, to leave API capacity for the running server.
+    pub pause: Duration,
+    /// Ignore any checkpoint and start from the newest item.
+    pub restart: bool,
+}
+
+/// How far a backfill has gotten, so that it can resume after being interrupted.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct BackfillCheckpoint {
+    /// ID of the oldest item handled so far. The next page starts after it.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_id: Option<String>,
+    /// Items handled so far, across all runs.
+    handled: usize,
+    /// Items that matched at least one rule, across all runs.
+    matched: usize,
+    /// Whether we reached the end of the source or the `since` date.
+    done: bool,
+}
+
+impl BackfillCheckpoint {
+    fn path(config_dir: &Path, domain: &str, username: &str, source: &BackfillSource) -> PathBuf {
+        config_dir
+            .join(domain)
+            .join(username)
+            .join("backfill")
+            .join(format!("{name}.json", name = source.checkpoint_name()))
+    }
+}
+
+impl Checkpoint for BackfillCheckpoint {}
+
+/// Run a domain user's rules over statuses or accounts that arrived before the rules existed,
+/// the same way as if they'd come from webhook events.
+/// Saves a checkpoint after each page, and resumes from it unless told to restart.
+pub async fn backfill(
+    config_dir: &Path,
+    client: &Client,
+    domain: &str,
+    username: &str,
+    source: &BackfillSource,
+    options: &BackfillOptions,
+) -> Result<()> {
+    let settings = ensure_settings(config_dir)?;
+    let registered = ensure_registered(config_dir, client, domain, None, None).await?;
+    let mut handler = init_user_handler(
+        config_dir,
+        client,
+        &settings,
+        registered,
+        domain,
+        username,
+        &Health::default(),
+    )
+    .await?;
+    handler.shadow = options.shadow;
+
+    let checkpoint_path = BackfillCheckpoint::path(config_dir, domain, username, source);
+    let mut checkpoint = if options.restart {
+        BackfillCheckpoint::default()
+    } else {
+        BackfillCheckpoint::load(&checkpoint_path)?
+    };
+    if checkpoint.done {
+        info!(
+            "{username}@{domain}: Backfill from {name} already finished after {handled} items. Use --restart to run it again.",
+            name = source.checkpoint_name(),
+            handled = checkpoint.handled
+        );
+        return Ok(());
+    }
+    if checkpoint.max_id.is_some() {
+        info!(
+            "{username}@{domain}: Resuming backfill from {name} after {handled} items",
+            name = source.checkpoint_name(),
+            handled = checkpoint.handled
+        );
+    }
+
+    let mut handled_this_run = 0;
+    'pages: loop {
+        let page = source
+            .page(&handler.api, checkpoint.max_id.as_deref())
+            .await?;
+        if page.is_empty() {
+            checkpoint.done = true;
+            break;
+        }
+        for item in page {
+            if options.limit.is_some_and(|limit| handled_this_run >= limit) {
+                break 'pages;
+            }
+            let created_at = item.created_at();
+            if options.since.is_some_and(|since| created_at < since) {
+                checkpoint.done = true;
+                break 'pages;
+            }
+            let id = item.id();
+            let too_new = options.until.is_some_and(|until| created_at >= until);
+            if !too_new {
+                if handle_item(&handler, item).await {
+                    checkpoint.matched += 1;
+                }
+                checkpoint.handled += 1;
+                handled_this_run += 1;
+            }
+            checkpoint.max_id = Some(id);
+        }
+        checkpoint.save(&checkpoint_path)?;
+        tokio::time::sleep(options.pause).await;
+    }
+    checkpoint.save(&checkpoint_path)?;
+
+    info!(
+        "{username}@{domain}: Backfill from {name} {state}: {handled} items handled, {matched} matched rules{shadow}",
+        name = source.checkpoint_name(),
+        state = if checkpoint.done { "finished" } else { "paused" },
+        handled = checkpoint.handled,
+        matched = checkpoint.matched,
+        shadow = if options.shadow { " (shadow mode)" } else { "" },
+    );
+    Ok(())
+}
+
+/// Handle one item like the corresponding webhook event, and record it in the audit log.
+/// Returns whether any rules matched.
+async fn handle_item(handler: &UserHandler, item: BackfillItem) -> bool {
+    let domain = &handler.config.domain;
+    let username = &handler.config.username;
+    let (mut audit_entry, result) = match item {
+        BackfillItem::Status(status) => {
+            let mut audit_entry = AuditEntry::new(
+                "backfill.status",
+                &status.account.id.to_string(),
+                &status.account.acct,
+                Some(&status.id.to_string()),
+            );
+            let result = handle_status(handler, &status, &mut audit_entry).await;
+            (audit_entry, result)
+        }
+        BackfillItem::Account(account) => {
+            let mut audit_entry = AuditEntry::new(
+                "backfill.account",
+                &account.id.to_string(),
+                &account.account.acct,
+                None,
+            );
+            let result = handle_account(handler, &account, false, &mut audit_entry).await;
+            (audit_entry, result)
+        }
+    };
+    if let Err(e) = result.as_ref() {
+        error!(
+            "{username}@{domain}: Error backfilling {acct}: {e}",
+            acct = audit_entry.acct
+        );
+    }
+    audit_entry.finish(&result);
+    handler.audit.record(&audit_entry).await;
+    !audit_entry.rules.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_checkpoint() {
+        let config_dir = TempDir::new("backfill");
+        let source = BackfillSource::Hashtag {
+            tag: "Casino#".to_string(),
+        };
+        let path = BackfillCheckpoint::path(&config_dir, "example.test", "automod", &source);
+        assert!(path.ends_with("example.test/automod/backfill/hashtag-casino.json"));
+        assert_eq!(
+            BackfillCheckpoint::default(),
+            BackfillCheckpoint::load(&path).unwrap()
+        );
+
+        let checkpoint = BackfillCheckpoint {
+            max_id: Some("109876543210".to_string()),
+            handled: 40,
+            matched: 3,
+            done: false,
+        };
+        checkpoint.save(&path).unwrap();
+        assert_eq!(checkpoint, BackfillCheckpoint::load(&path).unwrap());
+    }
+}
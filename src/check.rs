@@ -0,0 +1,932 @@
+use crate::config::{self, Config, Settings};
+use crate::pattern::EmptyPattern;
+use crate::{CompiledConfig, CompiledRule};
+use anyhow::{bail, Result};
+use regex::Regex;
+use schemars::schema::{InstanceType, RootSchema, Schema, SchemaObject, SingleOrVec};
+use schemars::schema_for;
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+use url::Host;
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::{Marker, TScalarStyle};
+
+/// Which kind of config file we're checking, since each has its own schema.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigFile {
+    /// `global.yaml`
+    Settings,
+    /// A domain user's `config.yaml`
+    Config,
+}
+
+/// A problem found in a config file, with its location.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// 1-based.
+    pub line: usize,
+    /// 1-based.
+    pub column: usize,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Error => "error",
+            Self::Warning => "warning",
+        })
+    }
+}
+
+impl Diagnostic {
+    fn at(severity: Severity, marker: Marker, message: impl Into<String>) -> Self {
+        Self {
+            severity,
+            line: marker.line(),
+            // yaml-rust columns are 0-based.
+            column: marker.col() + 1,
+            message: message.into(),
+        }
+    }
+
+    /// Format like a compiler message: `path:line:column: severity: message`.
+    pub fn display<'a>(&'a self, path: &'a Path) -> impl fmt::Display + 'a {
+        DiagnosticDisplay {
+            diagnostic: self,
+            path,
+        }
+    }
+}
+
+struct DiagnosticDisplay<'a> {
+    diagnostic: &'a Diagnostic,
+    path: &'a Path,
+}
+
+impl fmt::Display for DiagnosticDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{path}:{line}:{column}: {severity}: {message}",
+            path = self.path.to_string_lossy(),
+            line = self.diagnostic.line,
+            column = self.diagnostic.column,
+            severity = self.diagnostic.severity,
+            message = self.diagnostic.message,
+        )
+    }
+}
+
+/// `m1000 check-config`: check one config file, or if none is given,
+/// the global settings and every domain user's config under the config dir.
+/// Fails if any file has errors.
+pub fn check_config(config_dir: &Path, config: Option<&Path>) -> Result<()> {
+    let mut files: Vec<(ConfigFile, PathBuf)> = vec![];
+    if let Some(config) = config {
+        files.push((ConfigFile::Config, config.to_path_buf()));
+    } else {
+        files.push((ConfigFile::Settings, Settings::file_path(config_dir)));
+        for (domain, usernames) in config::configured_domains_and_usernames(config_dir)? {
+            for username in usernames {
+                files.push((
+                    ConfigFile::Config,
+                    Config::file_path(config_dir, &domain, &username),
+                ));
+            }
+        }
+    }
+
+    let mut errors = 0;
+    for (kind, path) in files {
+        if !path.exists() {
+            continue;
+        }
+        let diagnostics = check_file(kind, &path)?;
+        for diagnostic in diagnostics.iter() {
+            println!("{}", diagnostic.display(&path));
+        }
+        let file_errors = diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.severity == Severity::Error)
+            .count();
+        if file_errors == 0 {
+            println!(
+                "{path}: OK{warnings}",
+                path = path.to_string_lossy(),
+                warnings = if diagnostics.is_empty() {
+                    String::new()
+                } else {
+                    format!(" with {n} warnings", n = diagnostics.len())
+                }
+            );
+        }
+        errors += file_errors;
+    }
+    if errors > 0 {
+        bail!("Found {errors} errors");
+    }
+    Ok(())
+}
+
+/// Check a config file before the server loads it, so that mistakes come with locations.
+/// Logs warnings, and fails with all errors at once. Does nothing if the file doesn't exist yet.
+pub fn check_before_loading(kind: ConfigFile, path: &Path) -> Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let mut errors = vec![];
+    for diagnostic in check_file(kind, path)? {
+        match diagnostic.severity {
+            Severity::Warning => warn!("{}", diagnostic.display(path)),
+            Severity::Error => errors.push(diagnostic.display(path).to_string()),
+        }
+    }
+    if !errors.is_empty() {
+        bail!(
+            "Config file {path} has errors:\n{errors}",
+            path = path.to_string_lossy(),
+            errors = errors.join("\n")
+        );
+    }
+    Ok(())
+}
+
+/// Check a config file and return everything wrong with it, in file order.
+/// Only fails if the file can't be read.
+pub fn check_file(kind: ConfigFile, path: &Path) -> Result<Vec<Diagnostic>> {
+    Ok(check_source(kind, &read_to_string(path)?))
+}
+
+fn check_source(kind: ConfigFile, source: &str) -> Vec<Diagnostic> {
+    let root = match parse_marked(source) {
+        Ok(Some(root)) => root,
+        Ok(None) => {
+            return vec![Diagnostic {
+                severity: Severity::Error,
+                line: 1,
+                column: 1,
+                message: "File is empty".to_string(),
+            }]
+        }
+        Err(diagnostic) => return vec![diagnostic],
+    };
+
+    let schema = match kind {
+        ConfigFile::Settings => schema_for!(Settings),
+        ConfigFile::Config => schema_for!(Config),
+    };
+    let mut diagnostics = vec![];
+    SchemaChecker {
+        root: &schema,
+        diagnostics: &mut diagnostics,
+    }
+    .check(&root, &Schema::Object(schema.schema.clone()));
+
+    if kind == ConfigFile::Config {
+        check_values(&root, &mut diagnostics);
+    }
+
+    // Structural errors usually cause confusing deserialization errors, so stop here if there are any.
+    if diagnostics
+        .iter()
+        .all(|diagnostic| diagnostic.severity != Severity::Error)
+    {
+        match kind {
+            ConfigFile::Settings => {
+                deserialize::<Settings>(source, &mut diagnostics);
+            }
+            ConfigFile::Config => {
+                if let Some(config) = deserialize::<Config>(source, &mut diagnostics) {
+                    check_compile(&config, &root, &mut diagnostics);
+                }
+            }
+        }
+    }
+
+    diagnostics.sort_by(|a, b| (a.line, a.column, &a.message).cmp(&(b.line, b.column, &b.message)));
+    // Problems inside an aliased node show up once for the anchor and again for each alias.
+    diagnostics.dedup();
+    diagnostics
+}
+
+fn deserialize<T: DeserializeOwned>(source: &str, diagnostics: &mut Vec<Diagnostic>) -> Option<T> {
+    match serde_yaml::from_str::<T>(source) {
+        Ok(data) => Some(data),
+        Err(e) => {
+            let (line, column) = e
+                .location()
+                .map(|location| (location.line(), location.column()))
+                .unwrap_or((1, 1));
+            diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                line,
+                column,
+                message: e.to_string(),
+            });
+            None
+        }
+    }
+}
+
+/// Compile each rule separately, so that errors point at the rule that caused them.
+fn check_compile(config: &Config, root: &Node, diagnostics: &mut Vec<Diagnostic>) {
+    let rule_nodes = match root.get("rules").map(|rules| &rules.kind) {
+        Some(NodeKind::Seq(rule_nodes)) => rule_nodes.as_slice(),
+        _ => &[],
+    };
+
+    let mut names = HashSet::new();
+    let mut rules_ok = true;
+    for (index, rule) in config.rules.iter().enumerate() {
+        let marker = rule_nodes
+            .get(index)
+            .map(|node| node.marker)
+            .unwrap_or(root.marker);
+        if !names.insert(rule.name.as_str()) {
+            diagnostics.push(Diagnostic::at(
+                Severity::Warning,
+                marker,
+                format!(
+                    "Another rule is already named `{name}`, so metrics and logs can't tell them apart",
+                    name = rule.name
+                ),
+            ));
+        }
+        if let Err(e) = CompiledRule::try_from(rule) {
+            rules_ok = false;
+            let message = if e.chain().any(|cause| cause.is::<EmptyPattern>()) {
+                format!(
+                    "Rule `{name}` has a pattern that would never match anything: {EmptyPattern}",
+                    name = rule.name
+                )
+            } else {
+                format!("Rule `{name}`: {e:#}", name = rule.name)
+            };
+            diagnostics.push(Diagnostic::at(Severity::Error, marker, message));
+        }
+    }
+
+    // Config-wide checks, which only make sense once the rules are OK.
+    if rules_ok {
+        if let Err(e) = CompiledConfig::try_from(config) {
+            diagnostics.push(Diagnostic::at(
+                Severity::Error,
+                root.marker,
+                format!("{e:#}"),
+            ));
+        }
+    }
+}
+
+/// Checks values that the schema can't: regexes, domains, instance rule IDs, and empty pattern lists.
+fn check_values(node: &Node, diagnostics: &mut Vec<Diagnostic>) {
+    match &node.kind {
+        NodeKind::Map(entries) => {
+            for (key, value) in entries {
+                match (key.as_str(), &value.kind) {
+                    (Some("regex"), NodeKind::Scalar(regex)) => {
+                        if let Err(e) = Regex::new(regex) {
+                            diagnostics.push(Diagnostic::at(
+                                Severity::Error,
+                                value.marker,
+                                format!("Invalid regex: {e}"),
+                            ));
+                        }
+                    }
+                    (Some("domain"), NodeKind::Scalar(domain)) => {
+                        if let Some(problem) = domain_problem(domain) {
+                            diagnostics.push(Diagnostic::at(
+                                Severity::Error,
+                                value.marker,
+                                format!("Invalid domain `{domain}`: {problem}"),
+                            ));
+                        }
+                    }
+                    (Some("rule_ids"), NodeKind::Seq(rule_ids)) => {
+                        for rule_id in rule_ids {
+                            if let NodeKind::Scalar(id) = &rule_id.kind {
+                                if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit()) {
+                                    diagnostics.push(Diagnostic::at(
+                                        Severity::Error,
+                                        rule_id.marker,
+                                        format!(
+                                            "Invalid instance rule ID `{id}`: rule IDs are numbers. \
+                                            Your instance lists them at /api/v1/instance/rules"
+                                        ),
+                                    ));
+                                }
+                            }
+                        }
+                    }
+                    (Some(operator @ ("any" | "all")), NodeKind::Seq(children))
+                        if children.is_empty() =>
+                    {
+                        diagnostics.push(Diagnostic::at(
+                            Severity::Warning,
+                            key.marker,
+                            format!("Empty `{operator}` pattern is ignored"),
+                        ));
+                    }
+                    _ => {}
+                }
+                check_values(value, diagnostics);
+            }
+        }
+        NodeKind::Seq(items) => {
+            for item in items {
+                check_values(item, diagnostics);
+            }
+        }
+        NodeKind::Scalar(_) | NodeKind::Null => {}
+    }
+}
+
+/// What's wrong with a domain, if anything. Catches URLs and handles pasted where a bare domain belongs.
+fn domain_problem(domain: &str) -> Option<&'static str> {
+    if domain.contains("://") {
+        return Some("use just the domain, not a URL");
+    }
+    if domain.contains('@') {
+        return Some("use just the domain, not an account handle or email address");
+    }
+    if domain.contains('/') {
+        return Some("use just the domain, without a path");
+    }
+    match Host::parse(domain) {
+        Ok(Host::Domain(_)) => None,
+        Ok(_) => Some("IP addresses aren't domains"),
+        Err(_) => Some("not a valid domain name"),
+    }
+}
+
+/// Walks a YAML tree alongside the JSON Schema for the type it should deserialize to.
+struct SchemaChecker<'a> {
+    root: &'a RootSchema,
+    diagnostics: &'a mut Vec<Diagnostic>,
+}
+
+/// One possible shape for a value. Untagged enums and optional fields have several.
+enum Alternative<'a> {
+    /// Anything goes, such as for a `serde_json::Value`.
+    Any,
+    Object(&'a SchemaObject),
+}
+
+impl<'a> SchemaChecker<'a> {
+    fn check(&mut self, node: &Node, schema: &'a Schema) {
+        let mut alternatives = vec![];
+        self.alternatives(schema, &mut alternatives);
+        let mut objects = vec![];
+        for alternative in alternatives {
+            match alternative {
+                Alternative::Any => return,
+                Alternative::Object(object) => objects.push(object),
+            }
+        }
+
+        match &node.kind {
+            NodeKind::Null => {
+                if !objects
+                    .iter()
+                    .any(|object| has_type(object, InstanceType::Null))
+                {
+                    self.error(node.marker, "Expected a value here, found nothing");
+                }
+            }
+            NodeKind::Scalar(value) => self.check_scalar(node, value, &objects),
+            NodeKind::Seq(items) => {
+                let Some(item_schema) = objects.iter().find_map(|object| {
+                    match object.array.as_ref().and_then(|array| array.items.as_ref()) {
+                        Some(SingleOrVec::Single(item_schema)) => Some(item_schema.as_ref()),
+                        _ => None,
+                    }
+                }) else {
+                    self.error(
+                        node.marker,
+                        format!("Expected {}, found a list", describe_types(&objects)),
+                    );
+                    return;
+                };
+                for item in items {
+                    self.check(item, item_schema);
+                }
+            }
+            NodeKind::Map(entries) => self.check_map(node, entries, &objects),
+        }
+    }
+
+    fn check_scalar(&mut self, node: &Node, value: &str, objects: &[&'a SchemaObject]) {
+        let scalar_objects = objects
+            .iter()
+            .filter(|object| {
+                object.enum_values.is_some()
+                    || [
+                        InstanceType::String,
+                        InstanceType::Boolean,
+                        InstanceType::Integer,
+                        InstanceType::Number,
+                    ]
+                    .into_iter()
+                    .any(|instance_type| has_type(object, instance_type))
+            })
+            .copied()
+            .collect::<Vec<_>>();
+        if scalar_objects.is_empty() {
+            self.error(
+                node.marker,
+                format!("Expected {}, found `{value}`", describe_types(objects)),
+            );
+            return;
+        }
+        if scalar_objects
+            .iter()
+            .any(|object| object.enum_values.is_none() && has_type(object, InstanceType::String))
+        {
+            return;
+        }
+        let allowed = scalar_objects
+            .iter()
+            .flat_map(|object| object.enum_values.iter().flatten())
+            .filter_map(|value| value.as_str())
+            .collect::<Vec<_>>();
+        if !allowed.is_empty() {
+            if !allowed.contains(&value) {
+                self.error(
+                    node.marker,
+                    format!(
+                        "Unknown value `{value}`{suggestion}. Expected one of: {allowed}",
+                        suggestion = suggestion(value, allowed.iter().copied()),
+                        allowed = allowed.join(", ")
+                    ),
+                );
+            }
+            return;
+        }
+        let ok = scalar_objects.iter().any(|object| {
+            (has_type(object, InstanceType::Boolean) && (value == "true" || value == "false"))
+                || (has_type(object, InstanceType::Integer) && value.parse::<i64>().is_ok())
+                || (has_type(object, InstanceType::Number) && value.parse::<f64>().is_ok())
+        });
+        if !ok {
+            self.error(
+                node.marker,
+                format!(
+                    "Expected {}, found `{value}`",
+                    describe_types(&scalar_objects)
+                ),
+            );
+        }
+    }
+
+    fn check_map(&mut self, node: &Node, entries: &[(Node, Node)], objects: &[&'a SchemaObject]) {
+        let candidates = objects
+            .iter()
+            .filter_map(|object| {
+                object
+                    .object
+                    .as_ref()
+                    .map(|validation| (object, validation))
+            })
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            self.error(
+                node.marker,
+                format!("Expected {}, found a mapping", describe_types(objects)),
+            );
+            return;
+        }
+
+        let keys = entries
+            .iter()
+            .filter_map(|(key, _)| key.as_str())
+            .collect::<Vec<_>>();
+        // Pick the shape that fits best: fewest unknown keys, then fewest missing keys.
+        let (_, best) = candidates
+            .iter()
+            .min_by_key(|(_, validation)| {
+                let unknown = if validation.additional_properties.is_some() {
+                    0
+                } else {
+                    keys.iter()
+                        .filter(|key| !validation.properties.contains_key(**key))
+                        .count()
+                };
+                let missing = validation
+                    .required
+                    .iter()
+                    .filter(|required| !keys.contains(&required.as_str()))
+                    .count();
+                (unknown, missing)
+            })
+            .copied()
+            .expect("At least one candidate");
+
+        let known_keys = candidates
+            .iter()
+            .flat_map(|(_, validation)| validation.properties.keys().map(String::as_str))
+            .collect::<BTreeSet<_>>();
+        let mut any_unknown = false;
+        for (key, value) in entries {
+            let Some(name) = key.as_str() else {
+                self.error(key.marker, "Keys must be strings");
+                continue;
+            };
+            if let Some(property) = best.properties.get(name) {
+                self.check(value, property);
+            } else if let Some(additional) = best.additional_properties.as_ref() {
+                self.check(value, additional);
+            } else {
+                any_unknown = true;
+                self.error(
+                    key.marker,
+                    format!(
+                        "Unknown key `{name}`{suggestion}",
+                        suggestion = suggestion(name, known_keys.iter().copied())
+                    ),
+                );
+            }
+        }
+
+        // Missing keys are usually a symptom of a misspelled key, so only mention them otherwise.
+        if !any_unknown {
+            for required in best.required.iter() {
+                if !keys.contains(&required.as_str()) {
+                    self.error(node.marker, format!("Missing key `{required}`"));
+                }
+            }
+        }
+    }
+
+    /// Flatten references, `anyOf`, `oneOf`, and single-item `allOf` into a list of concrete shapes.
+    fn alternatives(&self, schema: &'a Schema, alternatives: &mut Vec<Alternative<'a>>) {
+        let object = match schema {
+            Schema::Bool(true) => {
+                alternatives.push(Alternative::Any);
+                return;
+            }
+            Schema::Bool(false) => return,
+            Schema::Object(object) => object,
+        };
+        if let Some(reference) = object.reference.as_ref() {
+            let name = reference.trim_start_matches("#/definitions/");
+            match self.root.definitions.get(name) {
+                Some(definition) => self.alternatives(definition, alternatives),
+                None => alternatives.push(Alternative::Any),
+            }
+            return;
+        }
+        if let Some(subschemas) = object.subschemas.as_ref() {
+            let mut found = false;
+            for list in [&subschemas.any_of, &subschemas.one_of]
+                .into_iter()
+                .flatten()
+            {
+                found = true;
+                for subschema in list {
+                    self.alternatives(subschema, alternatives);
+                }
+            }
+            if let Some([single]) = subschemas.all_of.as_deref() {
+                found = true;
+                self.alternatives(single, alternatives);
+            }
+            if found {
+                return;
+            }
+        }
+        if object.instance_type.is_none()
+            && object.enum_values.is_none()
+            && object.object.is_none()
+            && object.array.is_none()
+        {
+            alternatives.push(Alternative::Any);
+            return;
+        }
+        alternatives.push(Alternative::Object(object));
+    }
+
+    fn error(&mut self, marker: Marker, message: impl Into<String>) {
+        self.diagnostics
+            .push(Diagnostic::at(Severity::Error, marker, message));
+    }
+}
+
+fn has_type(object: &SchemaObject, instance_type: InstanceType) -> bool {
+    match object.instance_type.as_ref() {
+        Some(SingleOrVec::Single(single)) => **single == instance_type,
+        Some(SingleOrVec::Vec(types)) => types.contains(&instance_type),
+        None => false,
+    }
+}
+
+/// Human-readable list of the kinds of value a set of schemas allows.
+fn describe_types(objects: &[&SchemaObject]) -> String {
+    let mut kinds = BTreeSet::new();
+    for object in objects {
+        if object.object.is_some() || has_type(object, InstanceType::Object) {
+            kinds.insert("a mapping");
+        }
+        if object.array.is_some() || has_type(object, InstanceType::Array) {
+            kinds.insert("a list");
+        }
+        if object.enum_values.is_some() || has_type(object, InstanceType::String) {
+            kinds.insert("a string");
+        }
+        if has_type(object, InstanceType::Boolean) {
+            kinds.insert("true or false");
+        }
+        if has_type(object, InstanceType::Integer) || has_type(object, InstanceType::Number) {
+            kinds.insert("a number");
+        }
+    }
+    if kinds.is_empty() {
+        "something else".to_string()
+    } else {
+        kinds.into_iter().collect::<Vec<_>>().join(" or ")
+    }
+}
+
+/// `, did you mean `x`?` for the closest candidate, if any are close enough.
+fn suggestion<'b>(name: &str, candidates: impl Iterator<Item = &'b str>) -> String {
+    let max_distance = (name.chars().count() / 3).max(1);
+    candidates
+        .map(|candidate| (strsim::levenshtein(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, candidate)| format!(" (did you mean `{candidate}`?)"))
+        .unwrap_or_default()
+}
+
+/// A YAML node with the location where it starts.
+#[derive(Clone, Debug)]
+struct Node {
+    kind: NodeKind,
+    marker: Marker,
+}
+
+#[derive(Clone, Debug)]
+enum NodeKind {
+    Null,
+    Scalar(String),
+    Seq(Vec<Node>),
+    Map(Vec<(Node, Node)>),
+}
+
+impl Node {
+    fn as_str(&self) -> Option<&str> {
+        match &self.kind {
+            NodeKind::Scalar(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&Node> {
+        match &self.kind {
+            NodeKind::Map(entries) => entries
+                .iter()
+                .find(|(k, _)| k.as_str() == Some(key))
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+}
+
+/// Parse YAML into a tree of [`Node`]s, keeping locations that `serde_yaml` would throw away.
+fn parse_marked(source: &str) -> Result<Option<Node>, Diagnostic> {
+    let mut builder = TreeBuilder::default();
+    if let Err(e) = Parser::new(source.chars()).load(&mut builder, false) {
+        let message = e.to_string();
+        // The error message repeats the location, which we already have.
+        let message = message
+            .split(" at line ")
+            .next()
+            .unwrap_or(&message)
+            .to_string();
+        return Err(Diagnostic::at(
+            Severity::Error,
+            *e.marker(),
+            format!("YAML syntax error: {message}"),
+        ));
+    }
+    Ok(builder.root)
+}
+
+#[derive(Default)]
+struct TreeBuilder {
+    /// Containers still being built, with pending map keys and anchor IDs.
+    stack: Vec<(Node, Option<Node>, usize)>,
+    root: Option<Node>,
+    /// Anchored nodes by anchor ID, so that aliases can be checked the same as what they refer to.
+    anchors: HashMap<usize, Node>,
+}
+
+impl TreeBuilder {
+    /// Add a finished node to its container. An anchor ID of 0 means it doesn't have one.
+    fn push_value(&mut self, node: Node, anchor_id: usize) {
+        if anchor_id != 0 {
+            self.anchors.insert(anchor_id, node.clone());
+        }
+        match self.stack.last_mut() {
+            None => self.root = Some(node),
+            Some((container, pending_key, _)) => match &mut container.kind {
+                NodeKind::Seq(items) => items.push(node),
+                NodeKind::Map(entries) => match pending_key.take() {
+                    None => *pending_key = Some(node),
+                    Some(key) => entries.push((key, node)),
+                },
+                _ => {}
+            },
+        }
+    }
+}
+
+impl MarkedEventReceiver for TreeBuilder {
+    fn on_event(&mut self, event: Event, marker: Marker) {
+        match event {
+            Event::Scalar(value, style, anchor_id, _) => {
+                let is_null = style == TScalarStyle::Plain
+                    && matches!(value.as_str(), "~" | "null" | "Null" | "NULL" | "");
+                self.push_value(
+                    Node {
+                        kind: if is_null {
+                            NodeKind::Null
+                        } else {
+                            NodeKind::Scalar(value)
+                        },
+                        marker,
+                    },
+                    anchor_id,
+                );
+            }
+            Event::SequenceStart(anchor_id) => self.stack.push((
+                Node {
+                    kind: NodeKind::Seq(vec![]),
+                    marker,
+                },
+                None,
+                anchor_id,
+            )),
+            Event::MappingStart(anchor_id) => self.stack.push((
+                Node {
+                    kind: NodeKind::Map(vec![]),
+                    marker,
+                },
+                None,
+                anchor_id,
+            )),
+            Event::SequenceEnd | Event::MappingEnd => {
+                if let Some((node, _, anchor_id)) = self.stack.pop() {
+                    self.push_value(node, anchor_id);
+                }
+            }
+            // The parser has already rejected aliases to anchors that don't exist.
+            Event::Alias(anchor_id) => {
+                if let Some(node) = self.anchors.get(&anchor_id).cloned() {
+                    self.push_value(node, 0);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(source: &str) -> Vec<(usize, usize, String)> {
+        check_source(ConfigFile::Config, source)
+            .into_iter()
+            .map(|diagnostic| (diagnostic.line, diagnostic.column, diagnostic.message))
+            .collect()
+    }
+
+    #[test]
+    fn test_unknown_key() {
+        let source = r#"
+domain: example.test
+username: automod
+rules:
+- name: spam
+  restrict: suspend
+  patterns:
+  - post:
+      text:
+        worf: spam
+"#;
+        assert_eq!(
+            vec![(
+                10,
+                9,
+                "Unknown key `worf` (did you mean `word`?)".to_string()
+            )],
+            messages(source)
+        );
+    }
+
+    #[test]
+    fn test_aliases() {
+        let source = r#"
+domain: example.test
+username: automod
+rules:
+- name: casino spam
+  restrict: silence
+  patterns:
+  - &casino
+    post:
+      text:
+        word: casino
+- name: casino spam from new accounts
+  restrict: suspend
+  patterns:
+  - all:
+    - *casino
+    - account:
+        email:
+          disposable: true
+"#;
+        assert_eq!(Vec::<(usize, usize, String)>::new(), messages(source));
+
+        // Problems inside an anchored node are only reported where it's anchored.
+        let source = source.replace("word: casino", "worf: casino");
+        assert_eq!(
+            vec![(
+                11,
+                9,
+                "Unknown key `worf` (did you mean `word`?)".to_string()
+            )],
+            messages(&source)
+        );
+    }
+
+    #[test]
+    fn test_unknown_enum_value() {
+        let source = r#"
+domain: example.test
+username: automod
+rules:
+- name: spam
+  restrict: suspnd
+  patterns: []
+"#;
+        let messages = messages(source);
+        assert_eq!(1, messages.len());
+        assert_eq!((6, 13), (messages[0].0, messages[0].1));
+        assert!(messages[0].2.contains("did you mean `suspend`?"));
+    }
+
+    #[test]
+    fn test_values() {
+        let source = r#"
+domain: https://example.test
+username: automod
+rules:
+- name: spam
+  report:
+    rule_ids: [one]
+  patterns:
+  - post:
+      text:
+        regex: "(unclosed"
+  - post:
+      all: []
+"#;
+        let messages = messages(source);
+        assert_eq!(
+            vec![(2, 9), (7, 16), (11, 16), (13, 7)],
+            messages
+                .iter()
+                .map(|(line, column, _)| (*line, *column))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_valid() {
+        let source = r#"
+domain: example.test
+username: automod
+rules:
+- name: spam
+  restrict: suspend
+  patterns:
+  - post:
+      text:
+        word: spam
+"#;
+        assert!(messages(source).is_empty());
+    }
+}
@@ -0,0 +1,28 @@
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{create_dir_all, rename, File};
+use std::path::Path;
+
+/// A JSON file recording how far a job such as a backfill or polling has gotten,
+/// so that it can pick up where it left off after a restart.
+pub trait Checkpoint: Default + Serialize + DeserializeOwned {
+    /// Load a checkpoint, or start from the beginning if there isn't one yet.
+    fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_reader(File::open(path)?)?)
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
+        // Write and rename so that an interrupted save doesn't lose our place.
+        let temp_path = path.with_extension("json.tmp");
+        serde_json::to_writer(File::create(&temp_path)?, self)?;
+        rename(&temp_path, path)?;
+        Ok(())
+    }
+}
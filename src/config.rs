@@ -1,12 +1,18 @@
-use anyhow::{bail, Result};
+use crate::template;
+use anyhow::{anyhow, bail, Result};
 use glob::glob;
 use mastodon_async::entities::auth::Scopes;
+use schemars::gen::SchemaSettings;
+use schemars::schema::RootSchema;
 use schemars::JsonSchema;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs::{create_dir_all, File};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::{info, warn};
 
 pub const CLIENT_NAME: &str = env!("CARGO_PKG_NAME");
 
@@ -28,9 +34,9 @@ pub const REQUIRED_SCOPES: &[&str] = &["read", "write", "push", "admin:read", "a
 
 pub const DEFAULT_PORT: u16 = 1337;
 
-// TODO: implement JSON/YAML schema dump for config files.
-/// Schemas for types that don't have them.
+/// Schemas for types that don't have them, and examples for schema docs.
 mod schema {
+    use super::{Report, Restrict, Rule, RulePattern, TextPattern};
     use schemars::gen::SchemaGenerator;
     use schemars::schema::{Schema, SchemaObject};
     use schemars::JsonSchema;
@@ -40,41 +46,306 @@ mod schema {
         schema.format = Some("scopes".to_owned());
         schema.into()
     }
+
+    pub fn listen() -> Vec<String> {
+        vec!["[::]:1337".to_owned()]
+    }
+
+    pub fn metrics_listen() -> Vec<String> {
+        vec!["127.0.0.1:9337".to_owned()]
+    }
+
+    pub fn rspamc_command() -> Vec<String> {
+        vec![
+            "ssh".to_owned(),
+            "rspamd.example.test".to_owned(),
+            "rspamc".to_owned(),
+        ]
+    }
+
+    pub fn rspamd_url() -> &'static str {
+        "http://localhost:11334"
+    }
+
+    pub fn domain() -> &'static str {
+        "example.test"
+    }
+
+    pub fn username() -> &'static str {
+        "automod"
+    }
+
+    pub fn rule() -> Rule {
+        Rule {
+            name: "casino spam".to_owned(),
+            report: Some(Report {
+                rule_ids: vec!["1".to_owned()],
+                spam: true,
+                forward: false,
+                template: None,
+            }),
+            restrict: Some(Restrict::Suspend),
+            registration: None,
+            block: None,
+            warn: None,
+            patterns: vec![RulePattern::Post {
+                post: super::PostPattern::Text {
+                    text: TextPattern::Word {
+                        word: "casino".to_owned(),
+                    },
+                },
+            }],
+            examples: None,
+        }
+    }
+
+    pub fn rule_ids() -> Vec<String> {
+        vec!["1".to_owned(), "4".to_owned()]
+    }
+}
+
+/// Name and JSON Schema for each kind of config file, in the order that setup creates them.
+pub fn schemas() -> Vec<(&'static str, RootSchema)> {
+    let gen = SchemaSettings::draft07().into_generator();
+    let mut schemas = vec![
+        (
+            <Settings as private::StoredOnce>::basename(),
+            gen.clone().into_root_schema_for::<Settings>(),
+        ),
+        (
+            <App as private::StoredPerDomain>::basename(),
+            gen.clone().into_root_schema_for::<App>(),
+        ),
+        (
+            <Webhook as private::StoredPerDomain>::basename(),
+            gen.clone().into_root_schema_for::<Webhook>(),
+        ),
+        (
+            <Credentials as private::StoredPerDomainUser>::basename(),
+            gen.clone().into_root_schema_for::<Credentials>(),
+        ),
+        (
+            <Config as private::StoredPerDomainUser>::basename(),
+            gen.into_root_schema_for::<Config>(),
+        ),
+    ];
+    for (basename, schema) in schemas.iter_mut() {
+        let metadata = schema.schema.metadata();
+        metadata.title = Some(format!("{CLIENT_NAME} {basename}.yaml"));
+        // Schemas change with the config types, so note which version they came from.
+        schema.schema.extensions.insert(
+            "$comment".to_owned(),
+            format!(
+                "Generated by {CLIENT_NAME} {version}",
+                version = env!("CARGO_PKG_VERSION")
+            )
+            .into(),
+        );
+    }
+    schemas
+}
+
+/// Write a JSON Schema for each kind of config file to `{basename}.schema.json` in a directory,
+/// for editors and the YAML language server.
+pub fn write_schemas(out_dir: &Path) -> Result<()> {
+    create_dir_all(out_dir)?;
+    for (basename, schema) in schemas() {
+        let path = out_dir.join(format!("{basename}.schema.json"));
+        serde_json::to_writer_pretty(File::create(&path)?, &schema)?;
+        println!("{path}", path = path.to_string_lossy());
+    }
+    Ok(())
 }
 
 /// Global settings for this program.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct Settings {
-    /// Addresses and ports to listen on.
+    /// Addresses and ports to listen on. `m1000 healthcheck` uses the first one.
+    #[schemars(example = "schema::listen")]
     pub listen: Vec<String>,
+    /// Addresses and ports to serve Prometheus metrics on.
+    /// Metrics aren't served on `listen` addresses, which Mastodon needs to reach, and so might be public.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[schemars(example = "schema::metrics_listen")]
+    pub metrics_listen: Vec<String>,
     /// Rspamd configuration.
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub rspamd: Option<Rspamd>,
+    /// Accounts that no rule for any domain user will report or restrict.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exempt: Option<Exempt>,
+    /// Audit log rotation. The audit log is always written; this only changes when it rotates.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audit: Option<Audit>,
+    /// Webhook replay protection and deduplication.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replay_protection: Option<ReplayProtection>,
+    /// Save incoming webhook requests so that `m1000 replay` can replay them.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub record: Option<Record>,
 }
 
 impl StoredOnce for Settings {}
 
+impl Settings {
+    /// Where the global settings file lives, whether or not it exists yet.
+    pub fn file_path(config_dir: &Path) -> PathBuf {
+        <Self as private::StoredOnce>::path(config_dir)
+    }
+
+    /// Address for connecting to the first listen address from this machine.
+    /// Listening on all addresses, such as `[::]`, becomes the loopback address.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        let addr_str = self.listen.first().ok_or(anyhow!(
+            "Couldn't find any listen addresses in global settings"
+        ))?;
+        let mut addr = SocketAddr::from_str(addr_str)?;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        Ok(addr)
+    }
+}
+
+/// When to rotate each domain user's audit log, and how many old logs to keep.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Audit {
+    /// Rotate when the log would grow past this many bytes.
+    #[serde(default = "Audit::default_max_bytes")]
+    pub max_bytes: u64,
+    /// Rotate when the oldest entry in the log is older than this many seconds.
+    #[serde(default = "Audit::default_max_age_secs")]
+    pub max_age_secs: u64,
+    /// Number of rotated logs to keep. Older ones are deleted.
+    #[serde(default = "Audit::default_keep")]
+    pub keep: usize,
+}
+
+impl Audit {
+    fn default_max_bytes() -> u64 {
+        10 * 1024 * 1024
+    }
+
+    fn default_max_age_secs() -> u64 {
+        7 * 24 * 60 * 60
+    }
+
+    fn default_keep() -> usize {
+        10
+    }
+}
+
+impl Default for Audit {
+    fn default() -> Self {
+        Self {
+            max_bytes: Self::default_max_bytes(),
+            max_age_secs: Self::default_max_age_secs(),
+            keep: Self::default_keep(),
+        }
+    }
+}
+
+/// How old a webhook event can be before we refuse it, and how many recent events to remember
+/// so that we can recognize retries and replays.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ReplayProtection {
+    /// Refuse events whose `created_at` is more than this many seconds in the past or future.
+    #[serde(default = "ReplayProtection::default_max_age_secs")]
+    pub max_age_secs: u64,
+    /// Number of recent events to remember per domain.
+    #[serde(default = "ReplayProtection::default_cache_size")]
+    pub cache_size: usize,
+}
+
+impl ReplayProtection {
+    fn default_max_age_secs() -> u64 {
+        // Mastodon retries failed webhook deliveries for several minutes.
+        15 * 60
+    }
+
+    fn default_cache_size() -> usize {
+        10_000
+    }
+}
+
+impl Default for ReplayProtection {
+    fn default() -> Self {
+        Self {
+            max_age_secs: Self::default_max_age_secs(),
+            cache_size: Self::default_cache_size(),
+        }
+    }
+}
+
+/// Where to save incoming webhook requests. Signatures and other secrets in headers are redacted.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Record {
+    /// Directory for recordings. Relative paths are relative to the config directory.
+    pub dir: PathBuf,
+}
+
 /// Global Rspamd-related settings for this program.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct Rspamd {
     /// Rspamc command. May be a single path or executable name, or an ssh, docker, etc. command in several parts.
-    /// TODO: switch to HTTP interface? See https://rspamd.com/doc/faq.html#can-i-check-a-message-with-rspamd-without-rspamc
+    /// Only used if `url` isn't set.
     #[serde(default)]
+    #[schemars(example = "schema::rspamc_command")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub rspamc_command: Option<Vec<String>>,
+    /// Base URL of rspamd's HTTP interface. Learning and fuzzy hashes need the controller worker,
+    /// which listens on port 11334 by default. Used instead of `rspamc_command` if set.
+    #[serde(default)]
+    #[schemars(example = "schema::rspamd_url")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Controller password, sent with every HTTP request.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<Secret>,
+    /// ID of rspamd settings to apply when checking posts.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub settings_id: Option<String>,
+    /// Give up on an HTTP request or `rspamc` command after this many seconds.
+    #[serde(default = "Rspamd::default_timeout_secs")]
+    pub timeout_secs: u64,
     /// Learn spam and ham from how a spam report is closed.
     #[serde(default)]
     #[serde(skip_serializing_if = "std::ops::Not::not")]
     pub learn_from_reports: bool,
+    /// When learning spam from reports, also add it to fuzzy storage with this flag.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fuzzy_flag: Option<u32>,
+}
+
+impl Rspamd {
+    pub fn default_timeout_secs() -> u64 {
+        10
+    }
 }
 
 /// A registered OAuth application for a given domain.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct App {
+    /// Domain of the instance this app is registered with.
+    #[schemars(example = "schema::domain")]
     pub domain: String,
+    /// OAuth client ID. Written by `m1000 setup`.
     pub client_id: String,
-    pub client_secret: String,
+    /// OAuth client secret. Written by `m1000 setup`.
+    pub client_secret: Secret,
+    /// Space-separated OAuth scopes this app was registered with.
     #[schemars(schema_with = "schema::scopes")]
     pub scopes: Scopes,
 }
@@ -84,34 +355,149 @@ impl StoredPerDomain for App {}
 /// A webhook secret for a given domain.
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct Webhook {
+    /// Domain of the instance that sends these webhooks.
+    #[schemars(example = "schema::domain")]
     pub domain: String,
-    pub secret: String,
+    /// Secret from the instance's webhook settings, used to verify webhook signatures.
+    pub secret: Secret,
 }
 
 impl StoredPerDomain for Webhook {}
 
+impl Webhook {
+    /// Where a domain's webhook file lives, whether or not it exists yet.
+    pub fn file_path(config_dir: &Path, domain: &str) -> PathBuf {
+        <Self as private::StoredPerDomain>::path(config_dir, domain)
+    }
+}
+
 /// Access token for a given user and domain.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct Credentials {
+    /// Domain of the bot user's instance.
+    #[schemars(example = "schema::domain")]
     pub domain: String,
+    /// Bot user's username, without the leading @ or domain.
+    #[schemars(example = "schema::username")]
     pub username: String,
-    pub access_token: String,
+    /// OAuth access token for the bot user. Written by `m1000 setup`.
+    pub access_token: Secret,
 }
 
 impl StoredPerDomainUser for Credentials {}
 
+/// A secret in a config file: either the secret itself,
+/// or where to find it, so that it doesn't have to be stored in plain text.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Secret {
+    /// The secret itself. Files containing one must only be readable by their owner.
+    Plain(String),
+    /// Name of an environment variable containing the secret.
+    Env { env: String },
+    /// Path of a file containing the secret, such as a Docker or Kubernetes secret.
+    /// Surrounding whitespace is ignored.
+    File { file: PathBuf },
+}
+
+impl Secret {
+    /// Get the secret itself.
+    pub fn resolve(&self) -> Result<String> {
+        let secret = match self {
+            Self::Plain(secret) => secret.clone(),
+            Self::Env { env } => std::env::var(env)
+                .map_err(|e| anyhow!("Couldn't read secret from environment variable {env}: {e}"))?
+                .trim()
+                .to_string(),
+            Self::File { file } => std::fs::read_to_string(file)
+                .map_err(|e| {
+                    anyhow!(
+                        "Couldn't read secret from {path}: {e}",
+                        path = file.to_string_lossy()
+                    )
+                })?
+                .trim()
+                .to_string(),
+        };
+        if secret.is_empty() {
+            bail!("Empty secret");
+        }
+        Ok(secret)
+    }
+}
+
+impl From<String> for Secret {
+    fn from(secret: String) -> Self {
+        Self::Plain(secret)
+    }
+}
+
 /// Moderation rules for a given user and domain.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct Config {
+    /// Domain of the bot user's instance.
+    #[schemars(example = "schema::domain")]
     pub domain: String,
+    /// Bot user's username, without the leading @ or domain.
+    #[schemars(example = "schema::username")]
     pub username: String,
+    /// Accounts that this user's rules will never report or restrict.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exempt: Option<Exempt>,
+    /// Block remote domains whose accounts keep triggering rules.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain_policy: Option<DomainPolicy>,
+    /// Templates for report comments. Rules can override these with their own.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub report_template: Option<ReportTemplate>,
+    /// Maximum number of events to handle at once.
+    /// Events for the same account are always handled one at a time, in order.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub concurrency: Option<usize>,
+    /// Handle webhook events for this user, if the domain has a webhook.
+    /// Only worth turning off for users who get events from streaming or polling.
+    #[serde(default = "Config::default_webhooks")]
+    #[serde(skip_serializing_if = "Clone::clone")]
+    pub webhooks: bool,
+    /// Get events from the Mastodon streaming API, in place of or alongside webhooks.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub streaming: Option<Streaming>,
+    /// Get events by polling the Mastodon API, in place of or alongside webhooks.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub polling: Option<Polling>,
+    /// Moderation rules. Each is checked against every event; all matching rules take effect.
     pub rules: Vec<Rule>,
 }
 
 impl StoredPerDomainUser for Config {}
 
+impl Config {
+    fn default_webhooks() -> bool {
+        true
+    }
+
+    /// Load a config file from anywhere, such as a draft that isn't deployed yet.
+    pub fn load_file(path: &Path) -> Result<Self> {
+        load_from(path.to_path_buf())
+    }
+
+    /// Where a domain user's config file lives, whether or not it exists yet.
+    pub fn file_path(config_dir: &Path, domain: &str, username: &str) -> PathBuf {
+        <Self as private::StoredPerDomainUser>::path(config_dir, domain, username)
+    }
+}
+
+/// What to look for, and what to do when it's found.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(example = "schema::rule")]
 pub struct Rule {
+    /// Shown in reports, logs, and metrics. Should be unique.
     pub name: String,
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -119,22 +505,257 @@ pub struct Rule {
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub restrict: Option<Restrict>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub registration: Option<Registration>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block: Option<Block>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warn: Option<Warn>,
+    /// The rule matches if any of these patterns match.
     pub patterns: Vec<RulePattern>,
+    /// Test cases for this rule, checked whenever the config is loaded.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub examples: Option<RuleExamples>,
+}
+
+/// Test cases for a rule. The config won't load if any of them fail.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RuleExamples {
+    /// Examples that the rule must match.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub should_match: Vec<RuleExample>,
+    /// Examples that the rule must not match.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub should_not_match: Vec<RuleExample>,
+}
+
+/// Something that a rule should or shouldn't match.
+/// Partial statuses and accounts have any missing fields filled in with placeholders.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum RuleExample {
+    /// HTML text of a post.
+    Text(String),
+    /// Status in Mastodon API JSON format.
+    Status { status: serde_json::Value },
+    /// Admin account in Mastodon API JSON format, as sent with `account.created` events.
+    Account { account: serde_json::Value },
+}
+
+/// Accounts protected from reports and restrictions, even if they match a rule.
+/// An account is exempt if it matches any of these criteria.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct Exempt {
+    /// Specific accounts: `username` for local accounts, or `username@domain` for remote ones.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub accounts: Vec<String>,
+    /// Accounts matching any of these patterns.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<UserPattern>,
+    /// Local accounts with any staff role (owner, admin, or moderator).
+    #[serde(default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub staff: bool,
+    /// Accounts followed by the bot user.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub following: bool,
+}
+
+/// If this is present, a remote domain will be blocked when enough distinct accounts
+/// from that domain trigger rules within a time window.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct DomainPolicy {
+    /// Number of distinct accounts from one domain that must trigger rules.
+    pub accounts: usize,
+    /// Length of the time window, in seconds.
+    pub window_secs: u64,
+    /// Kind of domain block to create.
+    pub block: DomainBlockSeverity,
+    /// Domains that will never be blocked.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allowlist: Vec<InstancePattern>,
+    /// Log the domain block that would have been created instead of creating it.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub shadow: bool,
+}
+
+/// Kinds of domain block that a [`DomainPolicy`] can create.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum DomainBlockSeverity {
+    RejectMedia,
+    Silence,
+    Suspend,
+}
+
+/// If this is present, the bot user will connect to the instance's streaming API
+/// and handle statuses from it the same way as ones from webhooks.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct Streaming {
+    /// Streams to subscribe to.
+    pub streams: Vec<Stream>,
+    /// Streaming API base URL, such as `wss://streaming.example.test`.
+    /// Defaults to the one that the instance advertises.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+/// If this is present, the bot user will periodically poll the Mastodon API for new accounts, reports,
+/// and statuses, and handle them the same way as ones from webhooks.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct Polling {
+    /// What to poll.
+    pub sources: Vec<PollSource>,
+    /// Seconds between polls.
+    #[serde(default = "Polling::default_interval_secs")]
+    pub interval_secs: u64,
+}
+
+impl Polling {
+    fn default_interval_secs() -> u64 {
+        60
+    }
+}
+
+/// Mastodon API lists that [`Polling`] can read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum PollSource {
+    /// New local accounts from the admin account list, and pending accounts that get approved.
+    #[serde(rename = "accounts")]
+    Accounts,
+    /// New reports from the admin report list.
+    #[serde(rename = "reports")]
+    Reports,
+    /// The federated timeline: all public statuses known to the instance.
+    #[serde(rename = "public")]
+    Public,
+    /// Public statuses from local accounts.
+    #[serde(rename = "public:local")]
+    PublicLocal,
+    /// Public statuses from remote accounts.
+    #[serde(rename = "public:remote")]
+    PublicRemote,
+}
+
+/// Mastodon streaming API streams: either a stream name, or `hashtag: tag` for a hashtag stream.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+#[serde(untagged)]
+pub enum Stream {
+    /// A stream that doesn't take a parameter.
+    Named(NamedStream),
+    /// Public statuses with a hashtag, without the leading `#`.
+    Hashtag { hashtag: String },
+}
+
+/// Mastodon streaming API streams that don't take a parameter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum NamedStream {
+    /// All public statuses known to the instance.
+    #[serde(rename = "public")]
+    Public,
+    /// Public statuses from local accounts.
+    #[serde(rename = "public:local")]
+    PublicLocal,
+    /// Public statuses from remote accounts.
+    #[serde(rename = "public:remote")]
+    PublicRemote,
+    /// New signups and reports, from the bot user's admin notifications. Needs Mastodon 4.0 or later.
+    #[serde(rename = "admin")]
+    Admin,
 }
 
 /// If this is present, the rule will send a report using this metadata.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub struct Report {
+    /// IDs of the instance rules that the account broke, as listed at `/api/v1/instance/rules`.
     #[serde(default)]
     #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[schemars(example = "schema::rule_ids")]
     pub rule_ids: Vec<String>,
+    /// File the report in the spam category instead of as a rule violation.
     #[serde(default)]
     #[serde(skip_serializing_if = "Clone::clone")]
     pub spam: bool,
+    /// Forward the report to the account's home server, if it's a remote account.
     #[serde(default)]
     #[serde(skip_serializing_if = "Clone::clone")]
     pub forward: bool,
+    /// Overrides the config's report templates for reports where this is the first matching rule.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template: Option<ReportTemplate>,
+}
+
+/// Jinja templates for report comments, which are cut off at Mastodon's limit of 1000 characters.
+/// Available variables:
+/// - `rule_names`: names of all rules that matched
+/// - `evidence`: list of the words, links, etc. that matched
+/// - `acct`: the reported account's handle
+/// - `account_url`: link to the reported account
+/// - `account_age_days`: how old the reported account is, in days
+/// - `status_url`: link to the reported status, if there is one
+/// - `language`: language of the reported status, if known
+/// - `rspamd_action`: rspamd's verdict on the reported status, if rspamd is enabled
+/// - `rspamd_score`: rspamd's total score for the reported status, if rspamd is enabled
+/// - `forward`: whether the report will be forwarded to the account's home server
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct ReportTemplate {
+    /// Template for reports that aren't forwarded. Only our own moderators see these.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    /// Templates for forwarded reports, which remote moderators see too, keyed by language code.
+    /// The one matching the reported status's language is used, then `default`, then `comment`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub forward: BTreeMap<String, String>,
+}
+
+impl ReportTemplate {
+    /// Check all of the templates for syntax errors.
+    pub fn validate(&self, what: &str) -> Result<()> {
+        if let Some(comment) = self.comment.as_ref() {
+            template::validate(comment, what)?;
+        }
+        for (language, forward) in self.forward.iter() {
+            template::validate(forward, &format!("{what} forwarded in {language}"))?;
+        }
+        Ok(())
+    }
+
+    /// Pick the template for a report, if this has one that applies.
+    pub fn select(&self, forward: bool, language: Option<&str>) -> Option<&str> {
+        if forward {
+            let forward_template = language
+                .and_then(|language| self.forward.get(language))
+                .or_else(|| self.forward.get("default"));
+            if forward_template.is_some() {
+                return forward_template.map(String::as_str);
+            }
+        }
+        self.comment.as_deref()
+    }
 }
 
 /// If this is present, the rule will restrict the relevant account.
@@ -143,32 +764,151 @@ pub struct Report {
 )]
 #[serde(rename_all = "snake_case")]
 pub enum Restrict {
+    /// Mark the account's media as sensitive.
     Sensitive,
+    /// Freeze the account's login. Only works for local accounts.
     Disable,
+    /// Limit the account's visibility.
     Silence,
+    /// Suspend the account.
     Suspend,
 }
 
+/// If this is present, the rule will send the account a warning (a Mastodon strike) explaining what happened.
+/// If the rule also restricts the account, the warning is attached to the restriction;
+/// otherwise, it's sent on its own without restricting the account.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct Warn {
+    /// Template for the warning text, using Jinja syntax. Available variables:
+    /// - `rule_name`: this rule's name
+    /// - `rule_names`: names of all rules that matched
+    /// - `evidence`: list of the words, links, etc. that matched this rule
+    /// - `instance_rules`: text of the instance rules cited by this rule's `report.rule_ids`
+    pub text: String,
+    /// Also email the warning to the account's owner. Only works for local accounts.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub send_email_notification: bool,
+}
+
+/// If this is present, the rule will approve or reject a pending registration.
+/// Only applies to `account.created` and `account.approved` events for accounts that haven't been approved yet.
+/// If any matching rule rejects a registration, it's rejected.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Registration {
+    Approve,
+    Reject,
+}
+
+/// If this is present, the rule will block the email domain and/or IP address a new local account signed up with.
+/// Only applies to `account.created` and `account.approved` events.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct Block {
+    /// Block future signups from the account's email domain.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub email_domain: bool,
+    /// Block the account's signup IP address.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpBlockSeverity>,
+    /// How long IP blocks last, in seconds. IP addresses get reassigned, so blocks should expire eventually.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip_expires_in_secs: Option<u64>,
+}
+
+/// What an IP block prevents, from least to most restrictive.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum IpBlockSeverity {
+    SignUpRequiresApproval,
+    SignUpBlock,
+    NoAccess,
+}
+
 /// Top level pattern for a rule that matches against a post or the account that created it.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 #[serde(untagged)]
 pub enum RulePattern {
+    /// Matches the account that created a post, or the account from an account event.
     Account { account: AccountPattern },
+    /// Matches a post.
     Post { post: PostPattern },
-    Rspamd { action: String },
+    /// Matches when rspamd's action for a post is exactly this, such as `add header` or `reject`.
+    /// Same as `rspamd: {action: ...}`.
+    RspamdAction { action: String },
+    /// Matches rspamd's result for a post.
+    Rspamd { rspamd: RspamdPattern },
+    /// Matches if any of these patterns match.
     Any { any: Vec<RulePattern> },
+    /// Matches if all of these patterns match.
     All { all: Vec<RulePattern> },
+    /// Matches if this pattern doesn't.
     Not { not: Box<RulePattern> },
 }
 
-/// Patterns that match against an account's username/domain or bio.
+/// Patterns that match against rspamd's result for a post.
+/// Every part that's present must match. Needs at least one part.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RspamdPattern {
+    /// Matches when rspamd's action is exactly this, such as `add header` or `reject`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+    /// Matches when rspamd's total score is in this range.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<ScoreRange>,
+    /// Matches when this symbol fired, such as `URIBL_BLACK`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symbol: Option<SymbolPattern>,
+}
+
+/// An rspamd symbol, optionally with a range its score must be in.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+#[serde(untagged)]
+pub enum SymbolPattern {
+    Name(String),
+    Scored { name: String, score: ScoreRange },
+}
+
+/// A range of rspamd scores. Needs at least one bound.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ScoreRange {
+    /// Matches scores greater than or equal to this.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub at_least: Option<f64>,
+    /// Matches scores less than this.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub below: Option<f64>,
+}
+
+/// Patterns that match against an account's username/domain or bio,
+/// or for new local accounts, the reason given when signing up, their email address, or their IP addresses.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 #[serde(untagged)]
 pub enum AccountPattern {
     User { user: UserPattern },
     Text { text: TextPattern },
+    Reason { reason: TextPattern },
+    Email { email: EmailPattern },
+    Ip { ip: IpPattern },
     Any { any: Vec<AccountPattern> },
     All { all: Vec<AccountPattern> },
     Not { not: Box<AccountPattern> },
@@ -190,14 +930,35 @@ pub enum PostPattern {
 #[serde(rename_all = "snake_case")]
 #[serde(untagged)]
 pub enum TextPattern {
-    Word { word: String },
-    Regex { regex: String },
-    Link { link: LinkPattern },
-    Mention { mention: UserPattern },
-    Hashtag { hashtag: StringPattern },
-    Any { any: Vec<TextPattern> },
-    All { all: Vec<TextPattern> },
-    Not { not: Box<TextPattern> },
+    /// Matches a whole word, ignoring case.
+    Word {
+        word: String,
+    },
+    /// Matches a regular expression, using Rust `regex` syntax.
+    Regex {
+        regex: String,
+    },
+    /// Matches the URL of any link.
+    Link {
+        link: LinkPattern,
+    },
+    /// Matches any mentioned account.
+    Mention {
+        mention: UserPattern,
+    },
+    /// Matches any hashtag, without the leading `#`.
+    Hashtag {
+        hashtag: StringPattern,
+    },
+    Any {
+        any: Vec<TextPattern>,
+    },
+    All {
+        all: Vec<TextPattern>,
+    },
+    Not {
+        not: Box<TextPattern>,
+    },
 }
 
 /// Patterns that apply to the username or domain of an account or mention.
@@ -213,6 +974,58 @@ pub enum UserPattern {
     Not { not: Box<UserPattern> },
 }
 
+/// Patterns that apply to the email address of a new local account.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+#[serde(untagged)]
+pub enum EmailPattern {
+    /// Matches the part before the `@`.
+    Local {
+        local: StringPattern,
+    },
+    /// Matches the part after the `@`.
+    Domain {
+        domain: InstancePattern,
+    },
+    /// Matches email domains from a built-in list of disposable email providers.
+    Disposable {
+        disposable: bool,
+    },
+    Any {
+        any: Vec<EmailPattern>,
+    },
+    All {
+        all: Vec<EmailPattern>,
+    },
+    Not {
+        not: Box<EmailPattern>,
+    },
+}
+
+/// Patterns that apply to the IP addresses a new local account signed up or logged in from.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+#[serde(untagged)]
+pub enum IpPattern {
+    /// A single address or CIDR range, like `192.0.2.0/24` or `2001:db8::/32`.
+    Cidr {
+        cidr: String,
+    },
+    /// Path to a file with one address or CIDR range per line. `#` starts a comment.
+    List {
+        list: PathBuf,
+    },
+    Any {
+        any: Vec<IpPattern>,
+    },
+    All {
+        all: Vec<IpPattern>,
+    },
+    Not {
+        not: Box<IpPattern>,
+    },
+}
+
 /// Patterns that apply to any string.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
@@ -253,7 +1066,15 @@ pub enum InstancePattern {
 
 pub trait StoredOnce: private::StoredOnce {
     fn load(config_dir: &Path) -> Result<Self> {
-        load_from(<Self as private::StoredOnce>::path(config_dir))
+        let path = <Self as private::StoredOnce>::path(config_dir);
+        let data: Self = load_from(path.clone())?;
+        check_secrets(&path, &data.secrets())?;
+        Ok(data)
+    }
+
+    /// Like [`Self::load`], but `None` if the file doesn't exist yet.
+    fn load_if_exists(config_dir: &Path) -> Result<Option<Self>> {
+        not_found_as_none(Self::load(config_dir))
     }
 
     fn path(&self, config_dir: &Path) -> PathBuf {
@@ -261,36 +1082,42 @@ pub trait StoredOnce: private::StoredOnce {
     }
 
     fn save(&self, config_dir: &Path) -> Result<()> {
-        save_to(self, self.path(config_dir))
+        save_to(self, self.path(config_dir), !self.secrets().is_empty())
     }
 }
 
 pub trait StoredPerDomain: private::StoredPerDomain {
     fn load(config_dir: &Path, domain: &str) -> Result<Self> {
-        let data: Self = load_from(<Self as private::StoredPerDomain>::path(config_dir, domain))?;
+        let path = <Self as private::StoredPerDomain>::path(config_dir, domain);
+        let data: Self = load_from(path.clone())?;
         if data.domain() != domain {
             bail!(
                 "Expected domain {domain}, got {data_domain}",
                 data_domain = data.domain()
             );
         }
+        check_secrets(&path, &data.secrets())?;
         Ok(data)
     }
 
+    /// Like [`Self::load`], but `None` if the file doesn't exist yet.
+    fn load_if_exists(config_dir: &Path, domain: &str) -> Result<Option<Self>> {
+        not_found_as_none(Self::load(config_dir, domain))
+    }
+
     fn path(&self, config_dir: &Path) -> PathBuf {
         <Self as private::StoredPerDomain>::path(config_dir, self.domain())
     }
 
     fn save(&self, config_dir: &Path) -> Result<()> {
-        save_to(self, self.path(config_dir))
+        save_to(self, self.path(config_dir), !self.secrets().is_empty())
     }
 }
 
 pub trait StoredPerDomainUser: private::StoredPerDomainUser {
     fn load(config_dir: &Path, domain: &str, username: &str) -> Result<Self> {
-        let data: Self = load_from(<Self as private::StoredPerDomainUser>::path(
-            config_dir, domain, username,
-        ))?;
+        let path = <Self as private::StoredPerDomainUser>::path(config_dir, domain, username);
+        let data: Self = load_from(path.clone())?;
         if data.domain() != domain {
             bail!(
                 "Expected domain {domain}, got {data_domain}",
@@ -303,21 +1130,27 @@ pub trait StoredPerDomainUser: private::StoredPerDomainUser {
                 data_username = data.username()
             );
         }
+        check_secrets(&path, &data.secrets())?;
         Ok(data)
     }
 
+    /// Like [`Self::load`], but `None` if the file doesn't exist yet.
+    fn load_if_exists(config_dir: &Path, domain: &str, username: &str) -> Result<Option<Self>> {
+        not_found_as_none(Self::load(config_dir, domain, username))
+    }
+
     fn path(&self, config_dir: &Path) -> PathBuf {
         <Self as private::StoredPerDomainUser>::path(config_dir, self.domain(), self.username())
     }
 
     fn save(&self, config_dir: &Path) -> Result<()> {
-        save_to(self, self.path(config_dir))
+        save_to(self, self.path(config_dir), !self.secrets().is_empty())
     }
 }
 
 /// There's no easy way to have private trait methods in Rust, so we split these traits.
 pub(crate) mod private {
-    use super::{App, Config, Credentials, Settings, Webhook};
+    use super::{App, Config, Credentials, Secret, Settings, Webhook};
     use serde::de::DeserializeOwned;
     use serde::Serialize;
     use std::path::{Path, PathBuf};
@@ -325,6 +1158,11 @@ pub(crate) mod private {
     pub trait StoredOnce: DeserializeOwned + Serialize {
         fn basename() -> &'static str;
 
+        /// Secrets in this file, which get checked on load.
+        fn secrets(&self) -> Vec<&Secret> {
+            vec![]
+        }
+
         fn path(config_dir: &Path) -> PathBuf {
             config_dir
                 .to_path_buf()
@@ -336,12 +1174,23 @@ pub(crate) mod private {
         fn basename() -> &'static str {
             "global"
         }
+        fn secrets(&self) -> Vec<&Secret> {
+            self.rspamd
+                .iter()
+                .filter_map(|rspamd| rspamd.password.as_ref())
+                .collect()
+        }
     }
 
     pub trait StoredPerDomain: DeserializeOwned + Serialize {
         fn basename() -> &'static str;
         fn domain(&self) -> &str;
 
+        /// Secrets in this file, which get checked on load.
+        fn secrets(&self) -> Vec<&Secret> {
+            vec![]
+        }
+
         fn path(config_dir: &Path, domain: &str) -> PathBuf {
             config_dir
                 .to_path_buf()
@@ -357,6 +1206,9 @@ pub(crate) mod private {
         fn domain(&self) -> &str {
             self.domain.as_str()
         }
+        fn secrets(&self) -> Vec<&Secret> {
+            vec![&self.client_secret]
+        }
     }
 
     impl StoredPerDomain for Webhook {
@@ -366,6 +1218,9 @@ pub(crate) mod private {
         fn domain(&self) -> &str {
             self.domain.as_str()
         }
+        fn secrets(&self) -> Vec<&Secret> {
+            vec![&self.secret]
+        }
     }
 
     pub trait StoredPerDomainUser: DeserializeOwned + Serialize {
@@ -373,6 +1228,11 @@ pub(crate) mod private {
         fn domain(&self) -> &str;
         fn username(&self) -> &str;
 
+        /// Secrets in this file, which get checked on load.
+        fn secrets(&self) -> Vec<&Secret> {
+            vec![]
+        }
+
         fn path(config_dir: &Path, domain: &str, username: &str) -> PathBuf {
             config_dir
                 .to_path_buf()
@@ -392,6 +1252,9 @@ pub(crate) mod private {
         fn username(&self) -> &str {
             self.username.as_str()
         }
+        fn secrets(&self) -> Vec<&Secret> {
+            vec![&self.access_token]
+        }
     }
 
     impl StoredPerDomainUser for Config {
@@ -408,26 +1271,141 @@ pub(crate) mod private {
 }
 
 fn load_from<T: DeserializeOwned>(path: PathBuf) -> Result<T> {
-    let file = File::open(path)?;
-    let data = serde_yaml::from_reader(file)?;
+    let file = File::open(&path)?;
+    let data = serde_yaml::from_reader(file)
+        .map_err(|e| anyhow!("{path}: {e}", path = path.to_string_lossy()))?;
     Ok(data)
 }
 
-fn save_to<T>(data: &T, path: PathBuf) -> Result<()>
+/// Only a missing file means that something isn't configured yet.
+/// Anything else, like bad permissions or a secret that can't be read, needs fixing by hand,
+/// and shouldn't be papered over by registering a new app or overwriting a config file.
+fn not_found_as_none<T>(result: Result<T>) -> Result<Option<T>> {
+    match result {
+        Ok(data) => Ok(Some(data)),
+        Err(e)
+            if e.downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Save a config file. Files with secrets are only readable by their owner.
+fn save_to<T>(data: &T, path: PathBuf, has_secrets: bool) -> Result<()>
 where
     T: Serialize,
 {
     if let Some(dir) = path.parent() {
         create_dir_all(dir)?;
     }
-    let file = File::create(path)?;
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if has_secrets {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let file = options.open(&path)?;
+    if has_secrets {
+        // The mode above only applies to new files.
+        restrict_permissions(&path)?;
+    }
     serde_yaml::to_writer(file, data)?;
     Ok(())
 }
 
+/// Make sure that secrets can be read, and that files containing them aren't readable by other users.
+/// Refuses plain text secrets in a file that any user can read, and warns about other readable files.
+fn check_secrets(path: &Path, secrets: &[&Secret]) -> Result<()> {
+    if secrets
+        .iter()
+        .any(|secret| matches!(secret, Secret::Plain(_)))
+    {
+        check_permissions(path, true)?;
+    }
+    for secret in secrets {
+        if let Secret::File { file } = secret {
+            check_permissions(file, false).map_err(|e| {
+                anyhow!(
+                    "Secret file {file} in {path}: {e}",
+                    file = file.to_string_lossy(),
+                    path = path.to_string_lossy()
+                )
+            })?;
+        }
+        secret
+            .resolve()
+            .map_err(|e| anyhow!("Secret in {path}: {e}", path = path.to_string_lossy()))?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn check_permissions(path: &Path, strict: bool) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = std::fs::metadata(path)?.permissions().mode();
+    let path = path.to_string_lossy();
+    if strict && mode & 0o004 != 0 {
+        bail!(
+            "{path} contains secrets and is readable by any user. \
+            Run `chmod 600 {path}` or `{CLIENT_NAME} setup` to fix this."
+        );
+    }
+    if mode & 0o044 != 0 {
+        warn!("{path} contains secrets and is readable by other users. Run `chmod 600 {path}` to fix this.");
+    }
+    Ok(())
+}
+
+/// Windows file permissions don't map onto Unix modes.
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path, _strict: bool) -> Result<()> {
+    Ok(())
+}
+
+/// Make a file readable and writable only by its owner.
+#[cfg(unix)]
+fn restrict_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    if permissions.mode() & 0o077 != 0 {
+        permissions.set_mode(0o600);
+        std::fs::set_permissions(path, permissions)?;
+        info!(
+            "Made {path} readable only by its owner",
+            path = path.to_string_lossy()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Make existing config files that can hold secrets for a domain user readable only by their owner,
+/// such as ones saved by versions of `m1000` that didn't do that.
+pub fn restrict_secret_files(config_dir: &Path, domain: &str, username: &str) -> Result<()> {
+    for path in [
+        <App as private::StoredPerDomain>::path(config_dir, domain),
+        <Webhook as private::StoredPerDomain>::path(config_dir, domain),
+        <Credentials as private::StoredPerDomainUser>::path(config_dir, domain, username),
+    ] {
+        if path.exists() {
+            restrict_permissions(&path)?;
+        }
+    }
+    Ok(())
+}
+
 /// Map of configured domains and bot account usernames associated with them.
+/// Domains are configured if they have a webhook, or any users, since users can get events from streaming instead.
 pub fn configured_domains_and_usernames(config_dir: &Path) -> Result<HashMap<String, Vec<String>>> {
-    let mut domains_to_usernames = HashMap::new();
+    let mut domains_to_usernames = HashMap::<String, Vec<String>>::new();
 
     let mut webhook_glob_buf = config_dir.to_path_buf();
     webhook_glob_buf.push("*");
@@ -444,32 +1422,158 @@ pub fn configured_domains_and_usernames(config_dir: &Path) -> Result<HashMap<Str
 
     for webhook_entry in glob(webhook_glob)? {
         let webhook = load_from::<Webhook>(webhook_entry?)?;
-        let domain = webhook.domain;
-
-        let mut usernames = Vec::new();
-
-        let mut config_glob_buf = config_dir.to_path_buf();
-        config_glob_buf.push(&domain);
-        config_glob_buf.push("*");
-        config_glob_buf.push(format!(
-            "{basename}.yaml",
-            basename = <Config as private::StoredPerDomainUser>::basename()
-        ));
-        let Some(config_glob) = config_glob_buf.to_str() else {
-            bail!(
-                "{glob} couldn't be converted to a string for globbing",
-                glob = config_glob_buf.to_string_lossy()
-            );
-        };
+        domains_to_usernames.entry(webhook.domain).or_default();
+    }
 
-        for config_entry in glob(config_glob)? {
-            let config = load_from::<Config>(config_entry?)?;
-            let username = config.username;
-            usernames.push(username);
-        }
+    let mut config_glob_buf = config_dir.to_path_buf();
+    config_glob_buf.push("*");
+    config_glob_buf.push("*");
+    config_glob_buf.push(format!(
+        "{basename}.yaml",
+        basename = <Config as private::StoredPerDomainUser>::basename()
+    ));
+    let Some(config_glob) = config_glob_buf.to_str() else {
+        bail!(
+            "{glob} couldn't be converted to a string for globbing",
+            glob = config_glob_buf.to_string_lossy()
+        );
+    };
 
-        domains_to_usernames.insert(domain, usernames);
+    for config_entry in glob(config_glob)? {
+        let config = load_from::<Config>(config_entry?)?;
+        domains_to_usernames
+            .entry(config.domain)
+            .or_default()
+            .push(config.username);
     }
 
     Ok(domains_to_usernames)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_schemas() {
+        let schemas = schemas();
+        assert_eq!(
+            vec!["global", "app", "webhook", "credentials", "config"],
+            schemas.iter().map(|(name, _)| *name).collect::<Vec<_>>()
+        );
+
+        // Examples should be valid config, since editors will offer them as completions.
+        let (_, config_schema) = &schemas[4];
+        let Some(schemars::schema::Schema::Object(rule_schema)) =
+            config_schema.definitions.get("Rule")
+        else {
+            panic!("Config schema should define Rule");
+        };
+        let examples = &rule_schema.metadata.as_ref().unwrap().examples;
+        assert_eq!(1, examples.len());
+        serde_json::from_value::<Rule>(examples[0].clone()).unwrap();
+    }
+
+    #[test]
+    fn test_local_addr() {
+        let local_addr = |listen: &str| {
+            let mut settings: Settings = serde_yaml::from_str("listen: []").unwrap();
+            settings.listen = vec![listen.to_string()];
+            settings.local_addr().unwrap().to_string()
+        };
+        assert_eq!("[::1]:1337", local_addr("[::]:1337"));
+        assert_eq!("127.0.0.1:1337", local_addr("0.0.0.0:1337"));
+        assert_eq!("192.0.2.1:1337", local_addr("192.0.2.1:1337"));
+    }
+
+    #[test]
+    fn test_report_template_select() {
+        let template = ReportTemplate {
+            comment: Some("comment".to_string()),
+            forward: BTreeMap::from([
+                ("de".to_string(), "de".to_string()),
+                ("default".to_string(), "default".to_string()),
+            ]),
+        };
+        assert_eq!(Some("de"), template.select(true, Some("de")));
+        assert_eq!(Some("default"), template.select(true, Some("fr")));
+        assert_eq!(Some("default"), template.select(true, None));
+        // Reports that aren't forwarded never use forwarding templates.
+        assert_eq!(Some("comment"), template.select(false, Some("de")));
+
+        let template = ReportTemplate {
+            comment: Some("comment".to_string()),
+            forward: BTreeMap::from([("de".to_string(), "de".to_string())]),
+        };
+        assert_eq!(Some("comment"), template.select(true, Some("fr")));
+
+        assert_eq!(None, ReportTemplate::default().select(true, Some("de")));
+    }
+
+    #[test]
+    fn test_secrets() {
+        let config_dir = TempDir::new("config");
+
+        let secret_path = config_dir.join("webhook_secret");
+        std::fs::write(&secret_path, "file secret\n").unwrap();
+        let webhook: Webhook = serde_yaml::from_str(&format!(
+            "domain: example.test\nsecret:\n  file: {path}\n",
+            path = secret_path.to_string_lossy()
+        ))
+        .unwrap();
+        assert_eq!("file secret", webhook.secret.resolve().unwrap());
+        assert!(Secret::Env {
+            env: "M1000_CONFIG_TEST_UNSET".to_string()
+        }
+        .resolve()
+        .is_err());
+        std::fs::remove_file(&secret_path).unwrap();
+        std::fs::create_dir_all(config_dir.join("example.test")).unwrap();
+        std::fs::write(
+            config_dir.join("example.test").join("webhook.yaml"),
+            format!(
+                "domain: example.test\nsecret:\n  file: {path}\n",
+                path = secret_path.to_string_lossy()
+            ),
+        )
+        .unwrap();
+        assert!(Webhook::load_if_exists(&config_dir, "example.test").is_err());
+        std::fs::remove_file(config_dir.join("example.test").join("webhook.yaml")).unwrap();
+
+        let webhook = Webhook {
+            domain: "example.test".to_string(),
+            secret: "plain secret".to_string().into(),
+        };
+        assert!(Webhook::load_if_exists(&config_dir, "example.test")
+            .unwrap()
+            .is_none());
+        webhook.save(&config_dir).unwrap();
+        assert_eq!(
+            "plain secret",
+            Webhook::load(&config_dir, "example.test")
+                .unwrap()
+                .secret
+                .resolve()
+                .unwrap()
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let path = webhook.path(&config_dir);
+            let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(0o600, mode(&path));
+
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+            // Callers like `ensure_webhook` must see this as an error, not as a missing file.
+            let error = Webhook::load_if_exists(&config_dir, "example.test")
+                .unwrap_err()
+                .to_string();
+            assert!(error.contains("chmod 600"), "{error}");
+            restrict_secret_files(&config_dir, "example.test", "automod").unwrap();
+            assert_eq!(0o600, mode(&path));
+            assert!(Webhook::load(&config_dir, "example.test").is_ok());
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use crate::interop::api::{ApiClient, ApiError};
+use crate::setup::{ensure_mastodon, ensure_registered};
+use anyhow::Result;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::fs::{create_dir_all, rename, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+/// Basename of the dead-letter queue file for one domain user.
+const DEAD_LETTERS_BASENAME: &str = "dead_letters";
+
+/// A Mastodon API POST that still couldn't reach the server after retrying.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    #[serde(with = "time::serde::rfc3339")]
+    pub failed_at: OffsetDateTime,
+    /// API path, such as `/api/v1/reports`.
+    pub path: String,
+    /// URL-encoded form body.
+    pub form: String,
+    /// Error from the last attempt.
+    pub error: String,
+}
+
+impl DeadLetter {
+    pub fn new(path: &str, form: &str, error: &anyhow::Error) -> Self {
+        Self {
+            failed_at: OffsetDateTime::now_utc(),
+            path: path.to_string(),
+            form: form.to_string(),
+            error: error.to_string(),
+        }
+    }
+}
+
+/// Persistent JSONL queue of failed API POSTs for one domain user.
+#[derive(Clone, Debug)]
+pub struct DeadLetterQueue {
+    path: PathBuf,
+    lock: Arc<Mutex<()>>,
+}
+
+impl DeadLetterQueue {
+    pub fn new(config_dir: &Path, domain: &str, username: &str) -> Self {
+        Self {
+            path: config_dir
+                .join(domain)
+                .join(username)
+                .join(format!("{DEAD_LETTERS_BASENAME}.jsonl")),
+            lock: Default::default(),
+        }
+    }
+
+    /// Append a failed POST. Logs errors instead of returning them,
+    /// since the caller is already handling a failure.
+    pub async fn push(&self, dead_letter: &DeadLetter) {
+        let _lock = self.lock.lock().await;
+        if let Err(e) = self.append(dead_letter) {
+            error!(
+                "Couldn't write dead letter for {path} to {queue}: {e}",
+                path = dead_letter.path,
+                queue = self.path.to_string_lossy()
+            );
+        }
+    }
+
+    fn append(&self, dead_letter: &DeadLetter) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            create_dir_all(dir)?;
+        }
+        let mut line = serde_json::to_string(dead_letter)?;
+        line.push('\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Where queued POSTs wait while `m1000 retry-failed` replays them,
+    /// so that a running server can keep adding to the queue meanwhile.
+    fn retrying_path(&self) -> PathBuf {
+        self.path.with_extension("jsonl.retrying")
+    }
+
+    /// Move everything in the queue aside to be retried, and return it, oldest first.
+    /// Anything left over from an interrupted retry comes first.
+    fn begin_retry(&self) -> Result<Vec<DeadLetter>> {
+        let retrying_path = self.retrying_path();
+        if self.path.exists() {
+            if retrying_path.exists() {
+                let mut dead_letters = read_dead_letters(&retrying_path)?;
+                dead_letters.extend(read_dead_letters(&self.path)?);
+                write_dead_letters(&retrying_path, &dead_letters)?;
+                std::fs::remove_file(&self.path)?;
+            } else {
+                rename(&self.path, &retrying_path)?;
+            }
+        }
+        if !retrying_path.exists() {
+            return Ok(vec![]);
+        }
+        read_dead_letters(&retrying_path)
+    }
+
+    /// Record what's left to retry, so that an interrupted retry doesn't lose it.
+    fn set_outstanding(&self, outstanding: &[DeadLetter]) -> Result<()> {
+        let retrying_path = self.retrying_path();
+        if outstanding.is_empty() {
+            if retrying_path.exists() {
+                std::fs::remove_file(&retrying_path)?;
+            }
+            return Ok(());
+        }
+        write_dead_letters(&retrying_path, outstanding)
+    }
+}
+
+fn read_dead_letters(path: &Path) -> Result<Vec<DeadLetter>> {
+    let mut dead_letters = vec![];
+    for line in BufReader::new(File::open(path)?).lines() {
+        // Skip lines that don't parse, such as a partial line from a crash.
+        if let Ok(dead_letter) = serde_json::from_str(&line?) {
+            dead_letters.push(dead_letter);
+        }
+    }
+    Ok(dead_letters)
+}
+
+/// Write and rename, so that a crash leaves either the old list or the new one.
+fn write_dead_letters(path: &Path, dead_letters: &[DeadLetter]) -> Result<()> {
+    let temp_path = path.with_extension("tmp");
+    let mut file = File::create(&temp_path)?;
+    for dead_letter in dead_letters {
+        let mut line = serde_json::to_string(dead_letter)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+    }
+    file.flush()?;
+    rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Replay all failed POSTs for a domain user, oldest first.
+/// POSTs that fail again go back in the queue if they didn't reach the server.
+pub async fn retry_failed(
+    config_dir: &Path,
+    client: &Client,
+    domain: &str,
+    username: &str,
+) -> Result<()> {
+    let registered = ensure_registered(config_dir, client, domain, None, None).await?;
+    let mastodon = ensure_mastodon(config_dir, registered, domain, username, None, false).await?;
+    let api = ApiClient::new(client, &mastodon);
+    let queue = DeadLetterQueue::new(config_dir, domain, username);
+
+    let dead_letters = queue.begin_retry()?;
+    let mut failed = 0;
+    for (index, dead_letter) in dead_letters.iter().enumerate() {
+        match api.replay(dead_letter).await {
+            Ok(()) => info!(
+                "{username}@{domain}: Replayed POST {path} from {failed_at}",
+                path = dead_letter.path,
+                failed_at = dead_letter.failed_at
+            ),
+            Err(ApiError::Unsent(e)) => {
+                error!(
+                    "{username}@{domain}: POST {path} failed again: {e}",
+                    path = dead_letter.path
+                );
+                failed += 1;
+                queue
+                    .push(&DeadLetter {
+                        failed_at: OffsetDateTime::now_utc(),
+                        error: e.to_string(),
+                        ..dead_letter.clone()
+                    })
+                    .await;
+            }
+            // Mastodon may have acted on it this time, so replaying it again could do it twice.
+            Err(ApiError::Transient(e) | ApiError::Permanent(e)) => {
+                error!(
+                    "{username}@{domain}: POST {path} failed again, not queueing it for another retry: {e}",
+                    path = dead_letter.path
+                );
+                failed += 1;
+            }
+        }
+        queue.set_outstanding(&dead_letters[index + 1..])?;
+    }
+    info!(
+        "{username}@{domain}: Replayed {succeeded} of {total} failed POSTs",
+        succeeded = dead_letters.len() - failed,
+        total = dead_letters.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_queue() {
+        let config_dir = TempDir::new("dead-letter");
+        let queue = DeadLetterQueue::new(&config_dir, "example.test", "automod");
+        assert!(queue.begin_retry().unwrap().is_empty());
+
+        let dead_letter = DeadLetter::new(
+            "/api/v1/reports",
+            "account_id=1",
+            &anyhow!("503 Service Unavailable"),
+        );
+        queue.append(&dead_letter).unwrap();
+        queue.append(&dead_letter).unwrap();
+
+        let newer = DeadLetter::new(
+            "/api/v1/admin/accounts/1/action",
+            "type=suspend",
+            &anyhow!("503 Service Unavailable"),
+        );
+        assert_eq!(
+            vec![dead_letter.clone(), dead_letter.clone()],
+            queue.begin_retry().unwrap()
+        );
+
+        // An interrupted retry keeps what it hadn't got to yet, ahead of anything queued since.
+        queue
+            .set_outstanding(std::slice::from_ref(&dead_letter))
+            .unwrap();
+        queue.append(&newer).unwrap();
+        assert_eq!(
+            vec![dead_letter, newer.clone()],
+            queue.begin_retry().unwrap()
+        );
+
+        queue.set_outstanding(&[]).unwrap();
+        assert!(queue.begin_retry().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,245 @@
+use crate::metrics::EVENTS_LAGGED;
+use crate::webhook;
+use anyhow::Result;
+use futures::FutureExt;
+use mastodon_async::entities::AccountId;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Semaphore;
+use tokio::task::{JoinError, JoinHandle};
+use tracing::{error, info};
+
+/// Most events that can be waiting on other events for the same account.
+/// Beyond this, a backlog stays in the channel, where we can notice it.
+const MAX_QUEUED_EVENTS: usize = 256;
+
+/// How many events a domain user's handler remembers for [`RecentEvents`].
+const RECENT_EVENTS_SIZE: usize = 10_000;
+
+/// Events that one domain user's handler has handled recently, from any source,
+/// so that a status that arrives by both webhook and the streaming API or polling is only handled once.
+#[derive(Debug, Default)]
+struct RecentEvents {
+    order: VecDeque<String>,
+    set: HashSet<String>,
+}
+
+impl RecentEvents {
+    /// Remember an event. Returns false if it's already been handled.
+    /// Events without a [`webhook::Event::source_key`] are always new.
+    fn insert(&mut self, event: &webhook::Event) -> bool {
+        let Some(key) = event.source_key() else {
+            return true;
+        };
+        if !self.set.insert(key.clone()) {
+            return false;
+        }
+        self.order.push_back(key);
+        if self.order.len() > RECENT_EVENTS_SIZE {
+            if let Some(oldest) = self.order.pop_front() {
+                self.set.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Run `handle` on each event from `event_receiver`, up to `concurrency` at once.
+/// Events for the same account are handled one at a time, in the order received,
+/// and events that already arrived from another source are skipped.
+/// Returns once the channel is closed and every event has been handled.
+pub async fn dispatch_events<F, Fut>(
+    mut event_receiver: broadcast::Receiver<webhook::Event>,
+    domain: &str,
+    username: &str,
+    concurrency: usize,
+    handle: F,
+) -> Result<()>
+where
+    F: Fn(webhook::Event) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let queued = Arc::new(Semaphore::new(MAX_QUEUED_EVENTS));
+    let running = Arc::new(Semaphore::new(concurrency));
+    // Most recent task for each account with events in flight.
+    let mut in_flight = HashMap::<AccountId, JoinHandle<()>>::new();
+    let mut recent_events = RecentEvents::default();
+    loop {
+        match event_receiver.recv().await {
+            Ok(event) => {
+                if !recent_events.insert(&event) {
+                    info!(
+                        "{username}@{domain}: Ignoring {name} event for {object_id}, which already arrived from another source",
+                        name = event.name(),
+                        object_id = event.object_id().unwrap_or_default(),
+                    );
+                    continue;
+                }
+                let queued_permit = queued.clone().acquire_owned().await?;
+                in_flight.retain(|_, task| {
+                    if !task.is_finished() {
+                        return true;
+                    }
+                    if let Some(Err(e)) = task.now_or_never() {
+                        log_task_error(domain, username, e);
+                    }
+                    false
+                });
+                let account_id = event.account_id().cloned();
+                let previous = account_id
+                    .as_ref()
+                    .and_then(|account_id| in_flight.remove(account_id));
+                let running = running.clone();
+                let handle = handle.clone();
+                let (domain, username) = (domain.to_string(), username.to_string());
+                let task = tokio::spawn(async move {
+                    if let Some(previous) = previous {
+                        // The previous task logs its own errors, but not its own panics.
+                        if let Err(e) = previous.await {
+                            log_task_error(&domain, &username, e);
+                        }
+                    }
+                    // Only take a slot once it's this event's turn, so that events waiting on another don't hold one.
+                    if let Ok(_running_permit) = running.acquire().await {
+                        handle(event).await;
+                    }
+                    drop(queued_permit);
+                });
+                if let Some(account_id) = account_id {
+                    in_flight.insert(account_id, task);
+                }
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                EVENTS_LAGGED
+                    .with_label_values(&[domain, username])
+                    .inc_by(skipped);
+                error!("{username}@{domain}: Channel error: fell behind event stream. Skipping {skipped} events to catch up.");
+            }
+            Err(RecvError::Closed) => {
+                // Let events already in flight finish.
+                let _ = queued.acquire_many(MAX_QUEUED_EVENTS as u32).await?;
+                for (_, task) in in_flight {
+                    if let Err(e) = task.await {
+                        log_task_error(domain, username, e);
+                    }
+                }
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn log_task_error(domain: &str, username: &str, e: JoinError) {
+    if e.is_panic() {
+        error!("{username}@{domain}: Event handler task panicked: {e}");
+    } else {
+        error!("{username}@{domain}: Event handler task failed: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::example::placeholder_status;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use time::OffsetDateTime;
+
+    fn status_created(status_id: &str, account_id: &str) -> webhook::Event {
+        let mut status = placeholder_status();
+        status["id"] = status_id.into();
+        status["account"]["id"] = account_id.into();
+        webhook::Event::StatusCreated {
+            created_at: OffsetDateTime::now_utc(),
+            status: serde_json::from_value(status).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_dispatch_events() {
+        // Several threads, so that handlers really run at the same time.
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(4)
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let (event_sender, event_receiver) = broadcast::channel(16);
+                let handled = Arc::new(Mutex::new(Vec::<String>::new()));
+                let running = Arc::new(AtomicUsize::new(0));
+                let max_running = Arc::new(AtomicUsize::new(0));
+
+                for (status_id, account_id) in
+                    [("1", "a"), ("2", "a"), ("3", "b"), ("4", "c"), ("5", "a")]
+                {
+                    event_sender
+                        .send(status_created(status_id, account_id))
+                        .unwrap();
+                }
+                drop(event_sender);
+
+                let handle = {
+                    let handled = handled.clone();
+                    let running = running.clone();
+                    let max_running = max_running.clone();
+                    move |event: webhook::Event| {
+                        let handled = handled.clone();
+                        let running = running.clone();
+                        let max_running = max_running.clone();
+                        async move {
+                            let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                            max_running.fetch_max(now_running, Ordering::SeqCst);
+                            let status_id = event.object_id().unwrap();
+                            // Status 1 is slowest, so status 2 would overtake it if they weren't in order.
+                            let delay = if status_id == "1" { 50 } else { 10 };
+                            tokio::time::sleep(Duration::from_millis(delay)).await;
+                            handled.lock().unwrap().push(status_id.clone());
+                            running.fetch_sub(1, Ordering::SeqCst);
+                            if status_id == "4" {
+                                panic!("Handler bug");
+                            }
+                        }
+                    }
+                };
+                dispatch_events(event_receiver, "example.test", "automod", 2, handle)
+                    .await
+                    .unwrap();
+
+                // Everything was handled before returning, despite a panic.
+                let handled = handled.lock().unwrap().clone();
+                assert_eq!(5, handled.len());
+                let position = |status_id: &str| {
+                    handled
+                        .iter()
+                        .position(|handled_id| handled_id == status_id)
+                        .unwrap()
+                };
+                assert!(position("1") < position("2"));
+                assert!(position("2") < position("5"));
+                assert!(max_running.load(Ordering::SeqCst) <= 2);
+            });
+    }
+
+    #[test]
+    fn test_recent_events() {
+        let status_created = |id: &str| {
+            let mut status = placeholder_status();
+            status["id"] = id.into();
+            webhook::Event::StatusCreated {
+                created_at: OffsetDateTime::now_utc(),
+                status: serde_json::from_value(status).unwrap(),
+            }
+        };
+        let mut recent = RecentEvents::default();
+        assert!(recent.insert(&status_created("1")));
+        // The same status from another source, which sets its own timestamp.
+        assert!(!recent.insert(&status_created("1")));
+        assert!(recent.insert(&status_created("2")));
+        assert!(recent.insert(&webhook::Event::Unknown));
+        assert!(recent.insert(&webhook::Event::Unknown));
+    }
+}
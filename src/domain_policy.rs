@@ -0,0 +1,248 @@
+use crate::config::{DomainBlockSeverity, DomainPolicy};
+use crate::interop::api::DomainBlockRequest;
+use crate::pattern::{CompileMatcher, InstanceMatcher, Matcher};
+use anyhow::{bail, Result};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// How long a domain stays blocked as far as the tracker is concerned.
+/// After that, it can trigger a block again, in case moderators lifted the first one.
+const BLOCKED_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Tracks which remote accounts have recently triggered rules, grouped by domain,
+/// and decides when a domain has crossed its [`DomainPolicy`] threshold.
+#[derive(Clone, Debug)]
+pub struct DomainPolicyTracker {
+    policy: DomainPolicy,
+    allowlist: Vec<InstanceMatcher>,
+    state: Arc<Mutex<TrackerState>>,
+}
+
+#[derive(Debug, Default)]
+struct TrackerState {
+    /// Recent rule hits for each domain, oldest first.
+    hits: HashMap<String, VecDeque<Hit>>,
+    /// Domains that we've already blocked, or would have in shadow mode, and when.
+    blocked: HashMap<String, Instant>,
+}
+
+/// One account triggering one or more rules.
+#[derive(Clone, Debug)]
+struct Hit {
+    at: Instant,
+    acct: String,
+    rule_names: Vec<String>,
+}
+
+impl DomainPolicyTracker {
+    pub fn compile(policy: &DomainPolicy) -> Result<Self> {
+        if policy.accounts == 0 {
+            bail!("Domain policy needs at least 1 account to trigger a block");
+        }
+        let mut allowlist = vec![];
+        for pattern in policy.allowlist.iter() {
+            allowlist.push(pattern.compile()?);
+        }
+        Ok(Self {
+            policy: policy.clone(),
+            allowlist,
+            state: Default::default(),
+        })
+    }
+
+    /// If set, domain blocks should be logged but not created.
+    pub fn shadow(&self) -> bool {
+        self.policy.shadow
+    }
+
+    /// Record that an account from a remote domain triggered some rules.
+    /// Returns a domain block request if this pushed the domain over the threshold.
+    pub async fn record(
+        &self,
+        domain: &str,
+        acct: &str,
+        rule_names: &[String],
+    ) -> Option<DomainBlockRequest> {
+        let domain = domain.to_lowercase();
+        if self
+            .allowlist
+            .iter()
+            .any(|matcher| matcher.is_match(&domain))
+        {
+            return None;
+        }
+
+        let hit = Hit {
+            at: Instant::now(),
+            acct: acct.to_string(),
+            rule_names: rule_names.to_vec(),
+        };
+        let hits = self.state.lock().await.record(&self.policy, &domain, hit)?;
+
+        let (severity, reject_media) = match self.policy.block {
+            DomainBlockSeverity::RejectMedia => ("noop", true),
+            DomainBlockSeverity::Silence => ("silence", false),
+            DomainBlockSeverity::Suspend => ("suspend", false),
+        };
+        Some(DomainBlockRequest {
+            private_comment: private_comment(&self.policy, &domain, &hits),
+            domain,
+            severity: severity.to_string(),
+            reject_media,
+        })
+    }
+
+    /// Allow a domain to trigger a block again, for example if creating the block failed.
+    pub async fn forget(&self, domain: &str) {
+        self.state
+            .lock()
+            .await
+            .blocked
+            .remove(&domain.to_lowercase());
+    }
+}
+
+impl TrackerState {
+    /// Add a hit and expire old ones. If the domain crosses the threshold,
+    /// mark it as blocked and return the hits that caused it.
+    fn record(&mut self, policy: &DomainPolicy, domain: &str, hit: Hit) -> Option<Vec<Hit>> {
+        let window = Duration::from_secs(policy.window_secs);
+        let now = hit.at;
+        self.expire(window, now);
+        if self.blocked.contains_key(domain) {
+            return None;
+        }
+
+        let hits = self.hits.entry(domain.to_string()).or_default();
+        hits.push_back(hit);
+        while let Some(oldest) = hits.front() {
+            if now.duration_since(oldest.at) > window {
+                hits.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        let distinct_accounts = hits
+            .iter()
+            .map(|hit| hit.acct.as_str())
+            .collect::<HashSet<_>>();
+        if distinct_accounts.len() < policy.accounts {
+            return None;
+        }
+
+        self.blocked.insert(domain.to_string(), now);
+        self.hits.remove(domain).map(Vec::from)
+    }
+
+    /// Forget blocks older than [`BLOCKED_TTL`] and domains with no hits in the window,
+    /// so that neither grows without bound.
+    fn expire(&mut self, window: Duration, now: Instant) {
+        self.blocked
+            .retain(|_, blocked_at| now.duration_since(*blocked_at) <= BLOCKED_TTL);
+        self.hits.retain(|_, hits| {
+            hits.back()
+                .is_some_and(|newest| now.duration_since(newest.at) <= window)
+        });
+    }
+}
+
+/// Explain to other moderators why this domain block exists.
+fn private_comment(policy: &DomainPolicy, domain: &str, hits: &[Hit]) -> String {
+    let mut rule_names_by_acct = HashMap::<&str, HashSet<&str>>::new();
+    for hit in hits {
+        rule_names_by_acct
+            .entry(&hit.acct)
+            .or_default()
+            .extend(hit.rule_names.iter().map(String::as_str));
+    }
+    let mut lines = rule_names_by_acct
+        .into_iter()
+        .map(|(acct, rule_names)| {
+            let mut rule_names = rule_names.into_iter().collect::<Vec<_>>();
+            rule_names.sort();
+            format!("- {acct}: {rule_names}", rule_names = rule_names.join(", "))
+        })
+        .collect::<Vec<_>>();
+    lines.sort();
+    format!(
+        "Automod: {count} accounts from {domain} triggered rules within {window_secs} seconds:\n{lines}",
+        count = lines.len(),
+        window_secs = policy.window_secs,
+        lines = lines.join("\n"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_threshold_counts_distinct_accounts() {
+        let policy = DomainPolicy {
+            accounts: 2,
+            window_secs: 60,
+            block: DomainBlockSeverity::Suspend,
+            allowlist: vec![],
+            shadow: false,
+        };
+        let mut state = TrackerState::default();
+        let hit = |acct: &str| Hit {
+            at: Instant::now(),
+            acct: acct.to_string(),
+            rule_names: vec!["spam".to_string()],
+        };
+
+        assert!(state
+            .record(&policy, "spam.test", hit("a@spam.test"))
+            .is_none());
+        assert!(state
+            .record(&policy, "spam.test", hit("a@spam.test"))
+            .is_none());
+        assert!(state
+            .record(&policy, "other.test", hit("b@other.test"))
+            .is_none());
+        let hits = state
+            .record(&policy, "spam.test", hit("b@spam.test"))
+            .expect("Threshold should have been reached");
+        assert_eq!(3, hits.len());
+        assert!(state
+            .record(&policy, "spam.test", hit("c@spam.test"))
+            .is_none());
+
+        let comment = private_comment(&policy, "spam.test", &hits);
+        assert!(comment.starts_with("Automod: 2 accounts from spam.test"));
+
+        // Once the block expires, the domain can trigger another.
+        let later = |acct: &str| Hit {
+            at: Instant::now() + BLOCKED_TTL + Duration::from_secs(1),
+            ..hit(acct)
+        };
+        assert!(state
+            .record(&policy, "spam.test", later("c@spam.test"))
+            .is_none());
+        assert!(!state.hits.contains_key("other.test"));
+        assert!(state
+            .record(&policy, "spam.test", later("d@spam.test"))
+            .is_some());
+    }
+
+    #[test]
+    fn test_compile() {
+        let policy = DomainPolicy {
+            accounts: 0,
+            window_secs: 60,
+            block: DomainBlockSeverity::Suspend,
+            allowlist: vec![],
+            shadow: false,
+        };
+        assert!(DomainPolicyTracker::compile(&policy).is_err());
+        assert!(DomainPolicyTracker::compile(&DomainPolicy {
+            accounts: 1,
+            ..policy
+        })
+        .is_ok());
+    }
+}
@@ -0,0 +1,122 @@
+use crate::audit::AuditEntry;
+use crate::config::Registration;
+use crate::event::action::{
+    block_signup, report_account, restrict_or_warn, review_registration, ReportContext, Verdict,
+};
+use crate::pattern::RuleMatcherInput;
+use crate::UserHandler;
+use mastodon_async::entities::admin::Account;
+use tracing::{error, info};
+
+/// Examine one new local account from an `account.created` or `account.approved` webhook event.
+/// If it matches any rules, block its email domain and/or IP addresses,
+/// approve or reject its registration if it's still pending,
+/// and report and/or restrict or warn it, unless the account is exempt.
+/// Blocking and reviewing registrations only apply to accounts that just signed up,
+/// so `signup` is false for existing accounts, such as from backfill.
+/// Records what happened in an audit log entry.
+pub async fn handle_account(
+    handler: &UserHandler,
+    account: &Account,
+    signup: bool,
+    audit: &mut AuditEntry,
+) -> anyhow::Result<()> {
+    let config = &handler.config;
+    let api = &handler.api;
+    let username = &config.username;
+    let domain = &config.domain;
+
+    let verdict = Verdict::evaluate(config, &RuleMatcherInput::from(account));
+    if verdict.is_empty() {
+        return Ok(());
+    }
+    verdict.count_rule_hits(config);
+    audit.rules = verdict.rule_names.clone();
+
+    // Exemptions can take API calls, so skip them if there's nothing to exempt the account from.
+    if !verdict.has_actions(signup) {
+        return Ok(());
+    }
+
+    if let Some(exemption) = handler.exemptions.check(api, &account.account).await? {
+        info!(
+            "{username}@{domain}: Not acting on exempt account {acct} ({exemption}). Rules matched: {rule_names}",
+            acct = account.account.acct,
+            rule_names = verdict.rule_names.join(", "),
+        );
+        audit.exemption = Some(exemption.to_string());
+        return Ok(());
+    }
+
+    if handler.shadow {
+        verdict.record_shadow(
+            handler,
+            &format!("account {acct}", acct = account.account.acct),
+            signup,
+            audit,
+        );
+        return Ok(());
+    }
+
+    if signup {
+        if let Some(block) = verdict.block.as_ref() {
+            // Failing to block shouldn't stop us from rejecting or restricting the account.
+            let _ = block_signup(handler, account, block, &verdict.rule_names, audit).await;
+        }
+    }
+
+    if signup && !account.approved {
+        if let Some(registration) = verdict.registration {
+            let result = review_registration(handler, &account.id, registration).await;
+            audit.action(
+                "registration",
+                Some(format!("{registration:?}").to_lowercase()),
+                &result,
+            );
+            result?;
+            if registration == Registration::Reject {
+                // The account no longer exists, so there's nothing left to report or restrict.
+                return Ok(());
+            }
+        }
+    }
+
+    let report_id = if let Some(report_builder) = verdict.report_builder {
+        let result = report_account(
+            config,
+            api,
+            &account.id,
+            vec![],
+            report_builder,
+            &ReportContext::from(&account.account),
+        )
+        .await;
+        audit.action(
+            "report",
+            result.as_ref().ok().map(ToString::to_string),
+            &result,
+        );
+        if let Some(e) = result.as_ref().err() {
+            error!(
+                "{username}@{domain}: Couldn't create report for account {acct}: {e}",
+                acct = account.account.acct
+            );
+        }
+        result.ok()
+    } else {
+        None
+    };
+
+    restrict_or_warn(
+        handler,
+        &account.id,
+        verdict.highest_restrict,
+        report_id,
+        &verdict.rule_names,
+        &verdict.warnings,
+        audit,
+    )
+    .await?;
+
+    Ok(())
+}
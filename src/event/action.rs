@@ -0,0 +1,827 @@
+use crate::audit::AuditEntry;
+use crate::config::{Block, IpBlockSeverity, Registration, Report, ReportTemplate, Restrict, Warn};
+use crate::domain_policy::DomainPolicyTracker;
+use crate::interop::api::{
+    AccountActionRequest, ApiClient, DomainBlockRequest, IpBlockRequest, ReportRequest,
+};
+use crate::metrics::{REPORTS_FILED, RESTRICTIONS, RULE_HITS};
+use crate::pattern::{
+    admin_account_ips, is_webmail, EmailMatcherInput, Evidence, Matcher, RuleMatcherInput,
+};
+use crate::template;
+use crate::{CompiledConfig, CompiledRule, UserHandler};
+use ipnet::IpNet;
+use mastodon_async::entities::account::Account;
+use mastodon_async::entities::admin;
+use mastodon_async::entities::status::Status;
+use mastodon_async::entities::{AccountId, ReportId, RuleId, StatusId};
+use minijinja::context;
+use std::collections::{HashMap, HashSet};
+use time::OffsetDateTime;
+use tracing::{error, info, warn};
+
+/// Everything the rules decided about one status or account.
+#[derive(Debug, Default)]
+pub struct Verdict {
+    /// Names of all rules that matched, in config order.
+    pub rule_names: Vec<String>,
+    pub report_builder: Option<ReportBuilder>,
+    pub highest_restrict: Option<Restrict>,
+    pub registration: Option<Registration>,
+    pub block: Option<Block>,
+    /// One for each matched rule with a `warn` section, in config order.
+    pub warnings: Vec<PendingWarning>,
+}
+
+/// A warning from one matched rule, with everything needed to render its template
+/// except the instance rules, which require an API call.
+#[derive(Debug)]
+pub struct PendingWarning {
+    rule_name: String,
+    warn: Warn,
+    evidence: Vec<String>,
+    rule_ids: Vec<String>,
+}
+
+/// Rendered warning text for a moderation action.
+#[derive(Debug)]
+pub struct Warning {
+    text: String,
+    send_email_notification: bool,
+}
+
+impl Verdict {
+    /// Run all of a config's rules against an input and combine their actions.
+    pub fn evaluate(config: &CompiledConfig, input: &RuleMatcherInput) -> Self {
+        let mut verdict = Self::default();
+        for rule in config.rules.iter() {
+            if rule.matchers.iter().any(|matcher| matcher.is_match(input)) {
+                verdict.rule_names.push(rule.name.clone());
+
+                // Only needed for templates, so don't bother otherwise.
+                let evidence = if rule.report.is_some() || rule.warn.is_some() {
+                    rule_evidence(rule, input)
+                } else {
+                    vec![]
+                };
+
+                if let Some(report) = rule.report.as_ref() {
+                    verdict
+                        .report_builder
+                        .get_or_insert_with(|| Default::default())
+                        .rule_violation(&rule.name, report, &evidence);
+                }
+
+                // Higher restrictions win, and rejecting a registration wins over approving it.
+                verdict.highest_restrict = verdict.highest_restrict.max(rule.restrict);
+                verdict.registration = verdict.registration.max(rule.registration);
+
+                if let Some(block) = rule.block.as_ref() {
+                    verdict.block = Some(match verdict.block.take() {
+                        Some(merged) => merge_blocks(&merged, block),
+                        None => block.clone(),
+                    });
+                }
+
+                if let Some(warn) = rule.warn.as_ref() {
+                    verdict.warnings.push(PendingWarning {
+                        rule_name: rule.name.clone(),
+                        warn: warn.clone(),
+                        evidence,
+                        rule_ids: rule
+                            .report
+                            .as_ref()
+                            .map(|report| report.rule_ids.clone())
+                            .unwrap_or_default(),
+                    });
+                }
+            }
+        }
+        verdict
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rule_names.is_empty()
+    }
+
+    /// Whether any matched rule calls for an action, as opposed to only being counted.
+    pub fn has_actions(&self, signup: bool) -> bool {
+        !self.planned_actions(signup).is_empty()
+    }
+
+    /// Actions this verdict calls for, with details such as the restriction level, in the order they'd happen.
+    /// `signup` is whether registration review and signup blocks apply, as they do for new local accounts.
+    pub fn planned_actions(&self, signup: bool) -> Vec<(&'static str, Option<String>)> {
+        let mut actions = vec![];
+        if signup {
+            if let Some(block) = self.block.as_ref() {
+                if block.email_domain {
+                    actions.push(("block_email_domain", None));
+                }
+                if let Some(ip) = block.ip {
+                    actions.push(("block_ip", Some(format!("{ip:?}").to_lowercase())));
+                }
+            }
+            if let Some(registration) = self.registration {
+                actions.push((
+                    "registration",
+                    Some(format!("{registration:?}").to_lowercase()),
+                ));
+            }
+        }
+        if self.report_builder.is_some() {
+            actions.push(("report", None));
+        }
+        if let Some(restrict) = self.highest_restrict {
+            actions.push(("restrict", Some(format!("{restrict:?}").to_lowercase())));
+        }
+        for warning in self.warnings.iter() {
+            actions.push(("warn", Some(warning.rule_name.clone())));
+        }
+        actions
+    }
+
+    /// Log and audit what this verdict would have done, for shadow mode.
+    pub fn record_shadow(
+        &self,
+        handler: &UserHandler,
+        subject: &str,
+        signup: bool,
+        audit: &mut AuditEntry,
+    ) {
+        let actions = self.planned_actions(signup);
+        info!(
+            "{username}@{domain}: Shadow mode: would have acted on {subject}: {actions}. Rules matched: {rule_names}",
+            username = handler.config.username,
+            domain = handler.config.domain,
+            actions = describe_actions(&actions),
+            rule_names = self.rule_names.join(", "),
+        );
+        for (action, detail) in actions {
+            audit.action(format!("{action}_shadow"), detail, &Ok(()));
+        }
+    }
+
+    /// Count the matched rules in our metrics.
+    pub fn count_rule_hits(&self, config: &CompiledConfig) {
+        for rule_name in self.rule_names.iter() {
+            RULE_HITS
+                .with_label_values(&[&config.domain, &config.username, rule_name])
+                .inc();
+        }
+    }
+}
+
+/// Human-readable list of planned actions, such as `report, restrict (suspend)`.
+pub fn describe_actions(actions: &[(&str, Option<String>)]) -> String {
+    if actions.is_empty() {
+        return "nothing".to_string();
+    }
+    actions
+        .iter()
+        .map(|(action, detail)| match detail {
+            Some(detail) => format!("{action} ({detail})"),
+            None => action.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Collect evidence from all of a matched rule's matching patterns.
+pub fn rule_evidence(rule: &CompiledRule, input: &RuleMatcherInput) -> Vec<String> {
+    let mut evidence = vec![];
+    for matcher in rule
+        .matchers
+        .iter()
+        .filter(|matcher| matcher.is_match(input))
+    {
+        matcher.evidence(input, &mut evidence);
+    }
+    evidence.sort();
+    evidence.dedup();
+    evidence
+}
+
+/// Mastodon's limit on the length of a report comment, in characters.
+const REPORT_COMMENT_MAX_CHARS: usize = 1000;
+
+/// Facts about the reported account and status, for report comment templates.
+#[derive(Debug)]
+pub struct ReportContext {
+    pub acct: String,
+    pub account_url: String,
+    pub account_age_days: i64,
+    pub status_url: Option<String>,
+    pub language: Option<String>,
+    pub rspamd_action: Option<String>,
+    pub rspamd_score: Option<f64>,
+}
+
+impl From<&Account> for ReportContext {
+    fn from(account: &Account) -> Self {
+        Self {
+            acct: account.acct.clone(),
+            account_url: account.url.to_string(),
+            account_age_days: (OffsetDateTime::now_utc() - account.created_at).whole_days(),
+            status_url: None,
+            language: None,
+            rspamd_action: None,
+            rspamd_score: None,
+        }
+    }
+}
+
+impl From<&Status> for ReportContext {
+    fn from(status: &Status) -> Self {
+        Self {
+            status_url: Some(status.url.as_ref().unwrap_or(&status.uri).to_string()),
+            language: status.language.clone(),
+            ..Self::from(&status.account)
+        }
+    }
+}
+
+/// Report an account and optionally some of its statuses.
+/// Optionally forward that report to the origin server.
+pub async fn report_account(
+    config: &CompiledConfig,
+    api: &ApiClient,
+    account_id: &AccountId,
+    status_ids: Vec<StatusId>,
+    report_builder: ReportBuilder,
+    report_context: &ReportContext,
+) -> anyhow::Result<ReportId> {
+    let comment = match report_builder.comment(config, report_context) {
+        Ok(comment) => comment,
+        Err(e) => {
+            // Reporting the account is more important than explaining why.
+            let username = &config.username;
+            let domain = &config.domain;
+            error!("{username}@{domain}: Couldn't render report comment for account {account_id}, using the default: {e}");
+            report_builder.default_comment()
+        }
+    };
+    let comment = template::truncate(&comment, REPORT_COMMENT_MAX_CHARS);
+
+    let (category, rule_ids) = if !report_builder.rule_ids.is_empty() {
+        // Violation of specific instance rules with IDs.
+        ("violation", report_builder.rule_ids.into_iter().collect())
+    } else if report_builder.spam {
+        // Spam. Lower priority than specific rule violations.
+        ("spam", vec![])
+    } else {
+        // Not related to instance rules or spam.
+        ("other", vec![])
+    };
+
+    let report = api
+        .create_report(&ReportRequest {
+            account_id: account_id.clone(),
+            status_ids,
+            comment,
+            category: category.to_string(),
+            rule_ids,
+            forward: report_builder.forward,
+        })
+        .await?;
+
+    let username = &config.username;
+    let domain = &config.domain;
+    info!("{username}@{domain}: Filed report: {:#?}", report);
+    REPORTS_FILED.with_label_values(&[domain, username]).inc();
+
+    Ok(report.id)
+}
+
+/// Restrict an account if any rules call for it, attaching warnings from any rules that have them.
+/// If there are warnings but no restriction, send the warnings on their own.
+pub async fn restrict_or_warn(
+    handler: &UserHandler,
+    account_id: &AccountId,
+    restrict: Option<Restrict>,
+    report_id: Option<ReportId>,
+    rule_names: &[String],
+    warnings: &[PendingWarning],
+    audit: &mut AuditEntry,
+) -> anyhow::Result<()> {
+    let username = &handler.config.username;
+    let domain = &handler.config.domain;
+    let warning = match render_warning(handler, rule_names, warnings).await {
+        Ok(warning) => warning,
+        Err(e) => {
+            // Restricting the account is more important than explaining why.
+            error!("{username}@{domain}: Couldn't render warning for account {account_id}: {e}");
+            audit.action("render_warning", None, &Err::<(), _>(e));
+            None
+        }
+    };
+
+    match (restrict, warning) {
+        (Some(restrict), warning) => {
+            let result = restrict_account(
+                &handler.api,
+                account_id,
+                restrict,
+                report_id,
+                warning.as_ref(),
+            )
+            .await;
+            let restrict_label = format!("{restrict:?}").to_lowercase();
+            if result.is_ok() {
+                RESTRICTIONS
+                    .with_label_values(&[domain, username, &restrict_label])
+                    .inc();
+            }
+            audit.action("restrict", Some(restrict_label), &result);
+            result
+        }
+        (None, Some(warning)) => {
+            let result = warn_account(&handler.api, account_id, report_id, &warning).await;
+            audit.action("warn", None, &result);
+            result
+        }
+        (None, None) => Ok(()),
+    }
+}
+
+/// Restrict an account: silence, suspend, etc.
+/// Can take a report ID from a previous report for audit trail purposes,
+/// and a warning to show the account's owner.
+pub async fn restrict_account(
+    api: &ApiClient,
+    account_id: &AccountId,
+    restrict: Restrict,
+    report_id: Option<ReportId>,
+    warning: Option<&Warning>,
+) -> anyhow::Result<()> {
+    perform_account_action(
+        api,
+        account_id,
+        match restrict {
+            Restrict::Sensitive => "sensitive",
+            Restrict::Disable => "disable",
+            Restrict::Silence => "silence",
+            Restrict::Suspend => "suspend",
+        },
+        report_id,
+        warning,
+    )
+    .await
+}
+
+/// Send a warning (a strike) to an account without restricting it.
+pub async fn warn_account(
+    api: &ApiClient,
+    account_id: &AccountId,
+    report_id: Option<ReportId>,
+    warning: &Warning,
+) -> anyhow::Result<()> {
+    perform_account_action(api, account_id, "none", report_id, Some(warning)).await
+}
+
+async fn perform_account_action(
+    api: &ApiClient,
+    account_id: &AccountId,
+    action_type: &str,
+    report_id: Option<ReportId>,
+    warning: Option<&Warning>,
+) -> anyhow::Result<()> {
+    api.perform_account_action(
+        account_id,
+        &AccountActionRequest {
+            action_type: action_type.to_string(),
+            report_id,
+            text: warning.map(|warning| warning.text.clone()),
+            send_email_notification: warning.map(|warning| warning.send_email_notification),
+        },
+    )
+    .await
+}
+
+/// Render the warning templates from all matched rules that have them, and combine them into one warning.
+/// Returns `None` if no matched rules have warnings.
+pub async fn render_warning(
+    handler: &UserHandler,
+    rule_names: &[String],
+    warnings: &[PendingWarning],
+) -> anyhow::Result<Option<Warning>> {
+    if warnings.is_empty() {
+        return Ok(None);
+    }
+
+    let instance_rules = if warnings.iter().any(|warning| !warning.rule_ids.is_empty()) {
+        handler
+            .api
+            .instance_rules()
+            .await?
+            .into_iter()
+            .map(|rule| (rule.id, rule.text))
+            .collect::<HashMap<_, _>>()
+    } else {
+        Default::default()
+    };
+
+    let mut texts = vec![];
+    let mut send_email_notification = false;
+    for warning in warnings {
+        let cited_instance_rules = warning
+            .rule_ids
+            .iter()
+            .filter_map(|id| instance_rules.get(id))
+            .collect::<Vec<_>>();
+        texts.push(template::render(
+            &warning.warn.text,
+            context! {
+                rule_name => warning.rule_name,
+                rule_names => rule_names,
+                evidence => warning.evidence,
+                instance_rules => cited_instance_rules,
+            },
+        )?);
+        send_email_notification |= warning.warn.send_email_notification;
+    }
+
+    Ok(Some(Warning {
+        text: texts.join("\n\n"),
+        send_email_notification,
+    }))
+}
+
+/// Approve or reject a pending registration.
+pub async fn review_registration(
+    handler: &UserHandler,
+    account_id: &AccountId,
+    registration: Registration,
+) -> anyhow::Result<()> {
+    let account = match registration {
+        Registration::Approve => handler.api.approve_account(account_id).await?,
+        Registration::Reject => handler.api.reject_account(account_id).await?,
+    };
+    let username = &handler.config.username;
+    let domain = &handler.config.domain;
+    info!(
+        "{username}@{domain}: {registration:?} registration for {account_username}",
+        account_username = account.username
+    );
+    Ok(())
+}
+
+/// Combine blocks from two rules into the strictest and longest-lasting of each.
+fn merge_blocks(a: &Block, b: &Block) -> Block {
+    Block {
+        email_domain: a.email_domain || b.email_domain,
+        ip: a.ip.max(b.ip),
+        // Only blocks that actually block IPs have a say in the expiry.
+        // No expiry means the block is permanent, which outlasts any expiry.
+        ip_expires_in_secs: match (a.ip, b.ip) {
+            (Some(_), None) => a.ip_expires_in_secs,
+            (None, Some(_)) => b.ip_expires_in_secs,
+            _ => match (a.ip_expires_in_secs, b.ip_expires_in_secs) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                _ => None,
+            },
+        },
+    }
+}
+
+/// Block the email domain and/or IP addresses that a new local account signed up with.
+/// Keeps going if one of the blocks fails, but returns the last error.
+pub async fn block_signup(
+    handler: &UserHandler,
+    account: &admin::Account,
+    block: &Block,
+    rule_names: &[String],
+    audit: &mut AuditEntry,
+) -> anyhow::Result<()> {
+    let username = &handler.config.username;
+    let domain = &handler.config.domain;
+    let mut result = Ok(());
+
+    if block.email_domain {
+        let email = EmailMatcherInput::from(account.email.as_str());
+        if is_webmail(email.domain()) {
+            warn!(
+                "{username}@{domain}: Not blocking email domain {email_domain}, since it's a big email provider used by many others",
+                email_domain = email.domain()
+            );
+        } else if !email.domain().is_empty() {
+            let email_domain_block_result =
+                handler.api.create_email_domain_block(email.domain()).await;
+            audit.action(
+                "block_email_domain",
+                Some(email.domain().to_string()),
+                &email_domain_block_result,
+            );
+            match email_domain_block_result {
+                Ok(email_domain_block) => {
+                    info!(
+                        "{username}@{domain}: Created email domain block: {email_domain_block:#?}"
+                    );
+                }
+                Err(e) => {
+                    error!(
+                        "{username}@{domain}: Couldn't block email domain {email_domain}: {e}",
+                        email_domain = email.domain()
+                    );
+                    result = Err(e);
+                }
+            }
+        }
+    }
+
+    if let Some(severity) = block.ip {
+        for ip in admin_account_ips(account) {
+            let request = IpBlockRequest {
+                ip: IpNet::from(ip).to_string(),
+                severity: match severity {
+                    IpBlockSeverity::SignUpRequiresApproval => "sign_up_requires_approval",
+                    IpBlockSeverity::SignUpBlock => "sign_up_block",
+                    IpBlockSeverity::NoAccess => "no_access",
+                }
+                .to_string(),
+                comment: format!(
+                    "Automod: used by {acct}, who triggered rules: {rule_names}",
+                    acct = account.username,
+                    rule_names = rule_names.join(", ")
+                ),
+                expires_in: block.ip_expires_in_secs,
+            };
+            let ip_block_result = handler.api.create_ip_block(&request).await;
+            audit.action("block_ip", Some(request.ip.clone()), &ip_block_result);
+            match ip_block_result {
+                Ok(ip_block) => {
+                    info!("{username}@{domain}: Created IP block: {ip_block:#?}");
+                }
+                Err(e) => {
+                    error!(
+                        "{username}@{domain}: Couldn't block IP {ip}: {e}",
+                        ip = request.ip
+                    );
+                    result = Err(e);
+                }
+            }
+        }
+    }
+
+    result
+}
+
+/// Create a domain block requested by the domain policy, or log it in shadow mode.
+/// If creating it fails, the domain may trigger the policy again later.
+pub async fn block_domain(
+    handler: &UserHandler,
+    domain_policy: &DomainPolicyTracker,
+    request: DomainBlockRequest,
+    audit: &mut AuditEntry,
+) {
+    let username = &handler.config.username;
+    let domain = &handler.config.domain;
+    if domain_policy.shadow() {
+        info!(
+            "{username}@{domain}: Shadow mode: would have blocked {blocked_domain}: {request:#?}",
+            blocked_domain = request.domain
+        );
+        audit.action("block_domain_shadow", Some(request.domain.clone()), &Ok(()));
+        return;
+    }
+    let result = handler.api.create_domain_block(&request).await;
+    audit.action("block_domain", Some(request.domain.clone()), &result);
+    match result {
+        Ok(domain_block) => {
+            info!("{username}@{domain}: Created domain block: {domain_block:#?}");
+        }
+        Err(e) => {
+            error!(
+                "{username}@{domain}: Couldn't block domain {blocked_domain}: {e}",
+                blocked_domain = request.domain
+            );
+            domain_policy.forget(&request.domain).await;
+        }
+    }
+}
+
+/// Accumulate the text and machine-readable info for a report.
+/// Not to be confused with the Mastodon API request builder for a report.
+#[derive(Debug, Default)]
+pub struct ReportBuilder {
+    /// Names from our config file, not the server's rules.
+    rule_names: HashSet<String>,
+    /// These IDs are for the server's rules.
+    rule_ids: HashSet<RuleId>,
+    /// Is this considered spam? Will be ignored if any rule IDs are set.
+    spam: bool,
+    /// Should we forward this report to the user's home server?
+    forward: bool,
+    /// Words, links, etc. that matched any of the rules.
+    evidence: HashSet<String>,
+    /// Template from the first matching rule that has one.
+    template: Option<ReportTemplate>,
+}
+
+impl ReportBuilder {
+    fn rule_violation(
+        &mut self,
+        rule_name: &String,
+        report: &Report,
+        evidence: &[String],
+    ) -> &mut Self {
+        self.rule_names.insert(rule_name.clone());
+        self.rule_ids
+            .extend(report.rule_ids.iter().map(RuleId::new));
+        self.spam |= report.spam;
+        self.forward |= report.forward;
+        self.evidence.extend(evidence.iter().cloned());
+        if self.template.is_none() {
+            self.template = report.template.clone();
+        }
+        self
+    }
+
+    /// Plain list of the names of the rules that were broken.
+    fn default_comment(&self) -> String {
+        let mut rule_names = self.rule_names.iter().collect::<Vec<_>>();
+        rule_names.sort();
+        let rule_names_list = rule_names
+            .iter()
+            .map(|name| format!("- {name}"))
+            .collect::<Vec<_>>();
+        format!("Automod rules broken:\n{}", rule_names_list.join("\n"))
+    }
+
+    /// Render the report comment from the rule's template, the config's template,
+    /// or if neither applies, a plain list of rule names.
+    fn comment(
+        &self,
+        config: &CompiledConfig,
+        report_context: &ReportContext,
+    ) -> anyhow::Result<String> {
+        let language = report_context.language.as_deref();
+        let source = self
+            .template
+            .as_ref()
+            .and_then(|template| template.select(self.forward, language))
+            .or_else(|| {
+                config
+                    .report_template
+                    .as_ref()
+                    .and_then(|template| template.select(self.forward, language))
+            });
+        let Some(source) = source else {
+            return Ok(self.default_comment());
+        };
+
+        let mut rule_names = self.rule_names.iter().collect::<Vec<_>>();
+        rule_names.sort();
+        let mut evidence = self.evidence.iter().collect::<Vec<_>>();
+        evidence.sort();
+        template::render(
+            source,
+            context! {
+                rule_names => rule_names,
+                evidence => evidence,
+                acct => report_context.acct,
+                account_url => report_context.account_url,
+                account_age_days => report_context.account_age_days,
+                status_url => report_context.status_url,
+                language => report_context.language,
+                rspamd_action => report_context.rspamd_action,
+                rspamd_score => report_context.rspamd_score,
+                forward => self.forward,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, RuleExample};
+    use crate::example::example_input;
+    use serde_json::json;
+
+    #[test]
+    fn test_evaluate() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+domain: example.test
+username: automod
+rules:
+- name: casino
+  report: {}
+  restrict: silence
+  patterns:
+  - post: {text: {word: casino}}
+  - account: {reason: {word: casino}}
+- name: casino signups
+  registration: reject
+  block: {email_domain: true}
+  restrict: suspend
+  patterns:
+  - account: {reason: {word: casino}}
+- name: poker
+  restrict: disable
+  patterns:
+  - post: {text: {word: poker}}
+"#,
+        )
+        .unwrap();
+        let config = CompiledConfig::try_from(&config).unwrap();
+
+        let post = example_input(&RuleExample::Text("<p>Casino bonuses!</p>".to_string())).unwrap();
+        let verdict = Verdict::evaluate(&config, &post);
+        assert_eq!(vec!["casino".to_string()], verdict.rule_names);
+        assert!(verdict.report_builder.is_some());
+        assert_eq!(Some(Restrict::Silence), verdict.highest_restrict);
+        assert_eq!(None, verdict.registration);
+        assert!(verdict.has_actions(false));
+
+        let signup = example_input(&RuleExample::Account {
+            account: json!({"invite_request": "casino"}),
+        })
+        .unwrap();
+        let verdict = Verdict::evaluate(&config, &signup);
+        // In config order, with the highest restriction winning.
+        assert_eq!(
+            vec!["casino".to_string(), "casino signups".to_string()],
+            verdict.rule_names
+        );
+        assert_eq!(Some(Restrict::Suspend), verdict.highest_restrict);
+        assert_eq!(Some(Registration::Reject), verdict.registration);
+        assert!(verdict
+            .block
+            .as_ref()
+            .is_some_and(|block| block.email_domain));
+        assert_eq!(
+            vec!["block_email_domain", "registration", "report", "restrict"],
+            verdict
+                .planned_actions(true)
+                .into_iter()
+                .map(|(action, _)| action)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["report", "restrict"],
+            verdict
+                .planned_actions(false)
+                .into_iter()
+                .map(|(action, _)| action)
+                .collect::<Vec<_>>()
+        );
+
+        let verdict = Verdict::evaluate(
+            &config,
+            &example_input(&RuleExample::Text("<p>Hi!</p>".to_string())).unwrap(),
+        );
+        assert!(verdict.is_empty());
+        assert!(!verdict.has_actions(true));
+    }
+
+    #[test]
+    fn test_merge_blocks() {
+        let email_domain = Block {
+            email_domain: true,
+            ip: None,
+            ip_expires_in_secs: None,
+        };
+        let week_ip_block = Block {
+            email_domain: false,
+            ip: Some(IpBlockSeverity::SignUpRequiresApproval),
+            ip_expires_in_secs: Some(604800),
+        };
+        let day_ip_block = Block {
+            email_domain: false,
+            ip: Some(IpBlockSeverity::NoAccess),
+            ip_expires_in_secs: Some(86400),
+        };
+        assert_eq!(
+            Block {
+                email_domain: false,
+                ip: Some(IpBlockSeverity::NoAccess),
+                ip_expires_in_secs: Some(604800),
+            },
+            merge_blocks(&week_ip_block, &day_ip_block)
+        );
+
+        let permanent_ip_block = Block {
+            email_domain: false,
+            ip: Some(IpBlockSeverity::SignUpBlock),
+            ip_expires_in_secs: None,
+        };
+        assert_eq!(
+            Block {
+                email_domain: false,
+                ip: Some(IpBlockSeverity::NoAccess),
+                ip_expires_in_secs: None,
+            },
+            merge_blocks(&day_ip_block, &permanent_ip_block)
+        );
+        assert_eq!(
+            Block {
+                email_domain: true,
+                ip: Some(IpBlockSeverity::SignUpRequiresApproval),
+                ip_expires_in_secs: Some(604800),
+            },
+            merge_blocks(&week_ip_block, &email_domain)
+        );
+    }
+}
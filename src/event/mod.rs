@@ -1,2 +1,4 @@
+pub mod account;
+pub mod action;
 pub mod report;
 pub mod status;
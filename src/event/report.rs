@@ -1,19 +1,24 @@
-use crate::config::Settings;
-use crate::interop::rspamd::{rspamd_learn_ham, rspamd_learn_spam};
+use crate::audit::AuditEntry;
+use crate::interop::rspamd::RspamdClient;
 use anyhow::Result;
 use mastodon_async::entities::admin::Report;
 
 /// Examine one report from a webhook event.
 /// If it's a closed spam report and learning is turned on,
 /// train the spam filter based on the results of the report.
-pub async fn handle_report(settings: &Settings, domain: &str, report: &Report) -> Result<()> {
+pub async fn handle_report(
+    rspamd: Option<&RspamdClient>,
+    domain: &str,
+    report: &Report,
+    audit: &mut AuditEntry,
+) -> Result<()> {
     if !report.action_taken {
         return Ok(());
     }
     if !report.category.is_spam() {
         return Ok(());
     }
-    let Some(rspamd) = settings.rspamd.as_ref() else {
+    let Some(rspamd) = rspamd else {
         return Ok(());
     };
 
@@ -24,11 +29,20 @@ pub async fn handle_report(settings: &Settings, domain: &str, report: &Report) -
         || report.target_account.disabled
     {
         for status in &report.statuses {
-            rspamd_learn_spam(rspamd, domain, status).await?;
+            let result = rspamd.learn_spam(domain, status).await;
+            audit.action("learn_spam", Some(status.id.to_string()), &result);
+            result?;
+            if let Some(flag) = rspamd.settings().fuzzy_flag {
+                let result = rspamd.fuzzy_add(domain, status, flag).await;
+                audit.action("fuzzy_add", Some(status.id.to_string()), &result);
+                result?;
+            }
         }
     } else {
         for status in &report.statuses {
-            rspamd_learn_ham(rspamd, domain, status).await?;
+            let result = rspamd.learn_ham(domain, status).await;
+            audit.action("learn_ham", Some(status.id.to_string()), &result);
+            result?;
         }
     }
 
@@ -0,0 +1,224 @@
+use crate::config::{RuleExample, RuleExamples};
+use crate::pattern::{Matcher, RuleMatcher, RuleMatcherInput};
+use crate::template;
+use anyhow::{bail, Context, Result};
+use mastodon_async::entities::admin;
+use mastodon_async::entities::status::Status;
+use serde_json::{json, Value};
+
+/// How much of an example to quote in error messages.
+const EXAMPLE_DESCRIPTION_MAX_CHARS: usize = 80;
+
+/// Check that a rule's matchers match all of its `should_match` examples and none of its `should_not_match` examples.
+/// Reports every failing example at once, not just the first.
+pub fn check_examples(
+    rule_name: &str,
+    examples: &RuleExamples,
+    matchers: &[RuleMatcher],
+) -> Result<()> {
+    let mut failures = vec![];
+    for (expected, kind, examples) in [
+        (true, "should_match", &examples.should_match),
+        (false, "should_not_match", &examples.should_not_match),
+    ] {
+        for (index, example) in examples.iter().enumerate() {
+            let input = example_input(example).with_context(|| {
+                format!(
+                    "Rule {rule_name}: {kind} example {n} isn't a valid status or account",
+                    n = index + 1
+                )
+            })?;
+            let is_match = matchers.iter().any(|matcher| matcher.is_match(&input));
+            if is_match != expected {
+                failures.push(format!(
+                    "{kind} example {n} {outcome}: {description}",
+                    n = index + 1,
+                    outcome = if is_match { "matched" } else { "didn't match" },
+                    description = describe(example),
+                ));
+            }
+        }
+    }
+    if !failures.is_empty() {
+        bail!(
+            "Rule {rule_name} failed its examples:\n- {failures}",
+            failures = failures.join("\n- ")
+        );
+    }
+    Ok(())
+}
+
+/// Build matcher input for an example, filling in anything it leaves out with placeholders.
+pub fn example_input(example: &RuleExample) -> Result<RuleMatcherInput> {
+    Ok(match example {
+        RuleExample::Text(text) => {
+            let mut status = placeholder_status();
+            merge(&mut status, json!({ "content": text }));
+            RuleMatcherInput::from(&serde_json::from_value::<Status>(status)?)
+        }
+        RuleExample::Status { status: partial } => {
+            let mut status = placeholder_status();
+            merge(&mut status, partial.clone());
+            RuleMatcherInput::from(&serde_json::from_value::<Status>(status)?)
+        }
+        RuleExample::Account { account: partial } => {
+            let mut account = placeholder_admin_account();
+            merge(&mut account, partial.clone());
+            RuleMatcherInput::from(&serde_json::from_value::<admin::Account>(account)?)
+        }
+    })
+}
+
+/// Short description of an example for error messages.
+fn describe(example: &RuleExample) -> String {
+    let description = match example {
+        RuleExample::Text(text) => text.clone(),
+        RuleExample::Status { status } => format!("status {status}"),
+        RuleExample::Account { account } => format!("account {account}"),
+    };
+    template::truncate(&description, EXAMPLE_DESCRIPTION_MAX_CHARS)
+}
+
+/// Recursively copy fields from `patch` into `base`, replacing anything that isn't an object.
+fn merge(base: &mut Value, patch: Value) {
+    match (base, patch) {
+        (Value::Object(base), Value::Object(patch)) => {
+            for (key, value) in patch {
+                merge(base.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (base, patch) => *base = patch,
+    }
+}
+
+fn placeholder_account() -> Value {
+    json!({
+        "id": "1",
+        "username": "example",
+        "acct": "example",
+        "display_name": "",
+        "locked": false,
+        "bot": false,
+        "discoverable": null,
+        "group": false,
+        "created_at": "2023-01-01T00:00:00.000Z",
+        "note": "",
+        "url": "https://example.test/@example",
+        "avatar": "https://example.test/avatars/original/missing.png",
+        "avatar_static": "https://example.test/avatars/original/missing.png",
+        "header": "https://example.test/headers/original/missing.png",
+        "header_static": "https://example.test/headers/original/missing.png",
+        "followers_count": 0,
+        "following_count": 0,
+        "statuses_count": 0,
+        "last_status_at": null,
+        "limited": false,
+        "suspended": false,
+        "emojis": [],
+        "fields": [],
+    })
+}
+
+pub fn placeholder_status() -> Value {
+    json!({
+        "id": "1",
+        "created_at": "2023-01-01T00:00:00.000Z",
+        "in_reply_to_id": null,
+        "in_reply_to_account_id": null,
+        "sensitive": false,
+        "spoiler_text": "",
+        "visibility": "public",
+        "language": null,
+        "uri": "https://example.test/users/example/statuses/1",
+        "url": "https://example.test/@example/1",
+        "replies_count": 0,
+        "reblogs_count": 0,
+        "favourites_count": 0,
+        "edited_at": null,
+        "content": "",
+        "reblog": null,
+        "application": null,
+        "account": placeholder_account(),
+        "media_attachments": [],
+        "mentions": [],
+        "tags": [],
+        "emojis": [],
+        "card": null,
+        "poll": null,
+        "filtered": [],
+    })
+}
+
+pub fn placeholder_admin_account() -> Value {
+    json!({
+        "id": "1",
+        "username": "example",
+        "domain": null,
+        "created_at": "2023-01-01T00:00:00.000Z",
+        "email": "example@example.test",
+        "ip": null,
+        "ips": [],
+        "locale": "en",
+        "invite_request": null,
+        "role": null,
+        "confirmed": true,
+        "approved": true,
+        "disabled": false,
+        "silenced": false,
+        "suspended": false,
+        "account": placeholder_account(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RulePattern;
+    use crate::pattern::CompileMatcher;
+
+    #[test]
+    fn test_merge() {
+        let mut base = json!({ "a": 1, "b": { "c": 2, "d": 3 } });
+        merge(&mut base, json!({ "b": { "c": 4 }, "e": 5 }));
+        assert_eq!(json!({ "a": 1, "b": { "c": 4, "d": 3 }, "e": 5 }), base);
+    }
+
+    #[test]
+    fn test_check_examples() {
+        let pattern: RulePattern = serde_yaml::from_str(
+            r#"
+            post:
+              text:
+                word: casino
+            "#,
+        )
+        .unwrap();
+        let matchers = vec![pattern.compile().unwrap()];
+        let examples: RuleExamples = serde_yaml::from_str(
+            r#"
+            should_match:
+            - "<p>Best casino bonuses!</p>"
+            - status:
+                content: "<p>casino</p>"
+                account:
+                  acct: spammer@spam.test
+            should_not_match:
+            - "<p>Casinos are a bad investment.</p>"
+            "#,
+        )
+        .unwrap();
+        check_examples("casino", &examples, &matchers).unwrap();
+
+        let examples: RuleExamples = serde_yaml::from_str(
+            r#"
+            should_not_match:
+            - "<p>casino</p>"
+            "#,
+        )
+        .unwrap();
+        let error = check_examples("casino", &examples, &matchers)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("should_not_match example 1 matched"));
+    }
+}
@@ -0,0 +1,310 @@
+use crate::config::Exempt;
+use crate::interop::api::ApiClient;
+use crate::pattern::{CompileMatcher, Matcher, UserMatcher, UserMatcherInput};
+use anyhow::Result;
+use mastodon_async::entities::account::Account;
+use mastodon_async::entities::AccountId;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// How long to trust our list of staff accounts before fetching it again.
+const STAFF_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// How long to trust a follow relationship before checking it again.
+const FOLLOWING_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Compiled form of the global and per-user [`Exempt`] sections,
+/// plus caches for the parts that need API lookups.
+#[derive(Clone, Debug)]
+pub struct Exemptions {
+    accounts: HashSet<String>,
+    users: Vec<UserMatcher>,
+    staff: bool,
+    following: bool,
+    staff_cache: Arc<Mutex<Option<(Instant, HashSet<AccountId>)>>>,
+    following_cache: Arc<Mutex<HashMap<AccountId, (Instant, bool)>>>,
+}
+
+/// Why an account was exempted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exemption {
+    Account,
+    User,
+    Staff,
+    Following,
+}
+
+impl Display for Exemption {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Account => "listed account",
+            Self::User => "user pattern",
+            Self::Staff => "staff account",
+            Self::Following => "followed by bot",
+        })
+    }
+}
+
+impl Exemptions {
+    /// Merge the global and per-user sections for a given domain.
+    pub fn compile(domain: &str, global: Option<&Exempt>, user: Option<&Exempt>) -> Result<Self> {
+        let mut exemptions = Self {
+            accounts: Default::default(),
+            users: vec![],
+            staff: false,
+            following: false,
+            staff_cache: Default::default(),
+            following_cache: Default::default(),
+        };
+        for exempt in global.into_iter().chain(user) {
+            exemptions.accounts.extend(
+                exempt
+                    .accounts
+                    .iter()
+                    .map(|acct| normalize_acct(domain, acct)),
+            );
+            for pattern in exempt.users.iter() {
+                exemptions.users.push(pattern.compile()?);
+            }
+            exemptions.staff |= exempt.staff;
+            exemptions.following |= exempt.following;
+        }
+        Ok(exemptions)
+    }
+
+    /// Return the reason an account is exempt from rule actions, if it is.
+    /// Cheap checks go first so we only call the API when we have to.
+    pub async fn check(&self, api: &ApiClient, account: &Account) -> Result<Option<Exemption>> {
+        if self.accounts.contains(&account.acct.to_lowercase()) {
+            return Ok(Some(Exemption::Account));
+        }
+
+        let user_input = UserMatcherInput::from(account);
+        if self
+            .users
+            .iter()
+            .any(|matcher| matcher.is_match(&user_input))
+        {
+            return Ok(Some(Exemption::User));
+        }
+
+        if self.staff && self.is_staff(api, &account.id).await? {
+            return Ok(Some(Exemption::Staff));
+        }
+
+        if self.following && self.is_followed(api, &account.id).await? {
+            return Ok(Some(Exemption::Following));
+        }
+
+        Ok(None)
+    }
+
+    async fn is_staff(&self, api: &ApiClient, account_id: &AccountId) -> Result<bool> {
+        let mut staff_cache = self.staff_cache.lock().await;
+        match staff_cache.as_ref() {
+            Some((fetched_at, staff)) if fetched_at.elapsed() < STAFF_CACHE_TTL => {
+                Ok(staff.contains(account_id))
+            }
+            _ => {
+                let staff = api
+                    .staff_accounts()
+                    .await?
+                    .into_iter()
+                    .map(|account| account.id)
+                    .collect::<HashSet<_>>();
+                let is_staff = staff.contains(account_id);
+                *staff_cache = Some((Instant::now(), staff));
+                Ok(is_staff)
+            }
+        }
+    }
+
+    async fn is_followed(&self, api: &ApiClient, account_id: &AccountId) -> Result<bool> {
+        let mut following_cache = self.following_cache.lock().await;
+        if let Some((fetched_at, following)) = following_cache.get(account_id) {
+            if fetched_at.elapsed() < FOLLOWING_CACHE_TTL {
+                return Ok(*following);
+            }
+        }
+        let following = api.relationship(account_id).await?.following;
+        following_cache.retain(|_, (fetched_at, _)| fetched_at.elapsed() < FOLLOWING_CACHE_TTL);
+        following_cache.insert(account_id.clone(), (Instant::now(), following));
+        Ok(following)
+    }
+}
+
+/// Convert a configured account handle into the form used by [`Account::acct`]:
+/// no leading `@`, and no domain for local accounts.
+fn normalize_acct(domain: &str, acct: &str) -> String {
+    let acct = acct.trim_start_matches('@').to_lowercase();
+    match acct.split_once('@') {
+        Some((username, acct_domain)) if acct_domain.eq_ignore_ascii_case(domain) => {
+            username.to_string()
+        }
+        _ => acct,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{block_on, serve_stub};
+    use axum::extract::Query;
+    use axum::routing::get;
+    use axum::{Json, Router};
+    use mastodon_async::Mastodon;
+    use reqwest::Client;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn account(id: &str, acct: &str) -> Account {
+        serde_json::from_value(json!({
+            "id": id,
+            "username": acct.split('@').next().unwrap(),
+            "acct": acct,
+            "display_name": "",
+            "locked": false,
+            "bot": false,
+            "discoverable": null,
+            "group": false,
+            "created_at": "2023-01-01T00:00:00.000Z",
+            "note": "",
+            "url": format!("https://example.test/@{acct}"),
+            "avatar": "https://example.test/avatars/original/missing.png",
+            "avatar_static": "https://example.test/avatars/original/missing.png",
+            "header": "https://example.test/headers/original/missing.png",
+            "header_static": "https://example.test/headers/original/missing.png",
+            "followers_count": 0,
+            "following_count": 0,
+            "statuses_count": 0,
+            "last_status_at": null,
+            "limited": false,
+            "suspended": false,
+            "emojis": [],
+            "fields": [],
+        }))
+        .unwrap()
+    }
+
+    /// Serve a staff list with account 10 on it, and a bot user that follows account 20.
+    /// Returns the base URL and how many times each was requested.
+    async fn serve_lookups() -> (String, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        let staff_requests = Arc::new(AtomicUsize::new(0));
+        let relationship_requests = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route(
+                "/api/v1/admin/accounts",
+                get({
+                    let staff_requests = staff_requests.clone();
+                    move || async move {
+                        staff_requests.fetch_add(1, Ordering::SeqCst);
+                        Json(json!([{"id": "10", "username": "admin"}]))
+                    }
+                }),
+            )
+            .route(
+                "/api/v1/accounts/relationships",
+                get({
+                    let relationship_requests = relationship_requests.clone();
+                    move |Query(query): Query<HashMap<String, String>>| async move {
+                        relationship_requests.fetch_add(1, Ordering::SeqCst);
+                        let id = &query["id[]"];
+                        Json(json!([{"id": id, "following": id == "20"}]))
+                    }
+                }),
+            );
+        (serve_stub(app).await, staff_requests, relationship_requests)
+    }
+
+    #[test]
+    fn test_check() {
+        block_on(async {
+            let (base, staff_requests, relationship_requests) = serve_lookups().await;
+            let mastodon = Mastodon::from(mastodon_async::data::Data {
+                base: base.into(),
+                client_id: "".into(),
+                client_secret: "".into(),
+                redirect: "".into(),
+                token: "token".into(),
+            });
+            let api = ApiClient::new(&Client::new(), &mastodon);
+
+            // Listed accounts and user patterns don't need the API.
+            let global: Exempt =
+                serde_yaml::from_str("accounts: ['@Friend@partner.test']").unwrap();
+            let user: Exempt = serde_yaml::from_str("users: [{local: true}]").unwrap();
+            let exemptions =
+                Exemptions::compile("example.test", Some(&global), Some(&user)).unwrap();
+            assert_eq!(
+                Some(Exemption::Account),
+                exemptions
+                    .check(&api, &account("1", "friend@partner.test"))
+                    .await
+                    .unwrap()
+            );
+            assert_eq!(
+                Some(Exemption::User),
+                exemptions
+                    .check(&api, &account("2", "alice"))
+                    .await
+                    .unwrap()
+            );
+            assert_eq!(
+                None,
+                exemptions
+                    .check(&api, &account("3", "spammer@spam.test"))
+                    .await
+                    .unwrap()
+            );
+            assert_eq!(0, staff_requests.load(Ordering::SeqCst));
+            assert_eq!(0, relationship_requests.load(Ordering::SeqCst));
+
+            let staff_and_following: Exempt =
+                serde_yaml::from_str("{staff: true, following: true}").unwrap();
+            let exemptions =
+                Exemptions::compile("example.test", Some(&staff_and_following), None).unwrap();
+            for _ in 0..2 {
+                assert_eq!(
+                    Some(Exemption::Staff),
+                    exemptions
+                        .check(&api, &account("10", "admin"))
+                        .await
+                        .unwrap()
+                );
+                assert_eq!(
+                    Some(Exemption::Following),
+                    exemptions
+                        .check(&api, &account("20", "friend@partner.test"))
+                        .await
+                        .unwrap()
+                );
+                assert_eq!(
+                    None,
+                    exemptions
+                        .check(&api, &account("30", "spammer@spam.test"))
+                        .await
+                        .unwrap()
+                );
+            }
+            // The second round came from the caches.
+            assert_eq!(1, staff_requests.load(Ordering::SeqCst));
+            assert_eq!(2, relationship_requests.load(Ordering::SeqCst));
+        });
+    }
+
+    #[test]
+    fn test_normalize_acct() {
+        assert_eq!("automod", normalize_acct("example.test", "automod"));
+        assert_eq!(
+            "automod",
+            normalize_acct("example.test", "@automod@Example.test")
+        );
+        assert_eq!(
+            "admin@partner.test",
+            normalize_acct("example.test", "@Admin@partner.test")
+        );
+    }
+}
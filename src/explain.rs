@@ -0,0 +1,530 @@
+use crate::config::{
+    AccountPattern, Config, EmailPattern, InstancePattern, IpPattern, LinkPattern, PostPattern,
+    RspamdPattern, Rule, RulePattern, ScoreRange, StoredPerDomainUser, StringPattern,
+    SymbolPattern, TextPattern, UserPattern,
+};
+use crate::event::action::{describe_actions, Verdict};
+use crate::health::Health;
+use crate::init_user_handler;
+use crate::pattern::{CompileMatcher, Evidence, Matcher, RuleMatcherInput};
+use crate::setup::{ensure_registered, ensure_settings};
+use anyhow::Result;
+use mastodon_async::entities::account::Account;
+use mastodon_async::entities::StatusId;
+use reqwest::Client;
+use std::fmt;
+use std::path::Path;
+
+/// What `m1000 explain` looks at.
+pub enum ExplainTarget<'a> {
+    Status { id: &'a str, rspamd: bool },
+    Account { acct: &'a str },
+}
+
+/// Fetch a status or account and print, for every rule, which parts of its patterns matched and why,
+/// then the actions that m1000 would take. Doesn't take any actions.
+pub async fn explain(
+    config_dir: &Path,
+    client: &Client,
+    domain: &str,
+    username: &str,
+    target: ExplainTarget<'_>,
+) -> Result<()> {
+    let settings = ensure_settings(config_dir)?;
+    let registered = ensure_registered(config_dir, client, domain, None, None).await?;
+    let handler = init_user_handler(
+        config_dir,
+        client,
+        &settings,
+        registered,
+        domain,
+        username,
+        &Health::default(),
+    )
+    .await?;
+    // The compiled config doesn't keep the patterns as written, which is what we want to show.
+    let config = Config::load(config_dir, domain, username)?;
+
+    let (description, input, account, signup): (String, RuleMatcherInput, Account, bool) =
+        match target {
+            ExplainTarget::Status { id, rspamd } => {
+                let status = handler.mastodon.get_status(&StatusId::new(id)).await?;
+                let mut input = RuleMatcherInput::from(&status);
+                let mut description = format!(
+                    "status {id} by {acct}",
+                    id = status.id,
+                    acct = status.account.acct
+                );
+                if rspamd {
+                    if let Some(rspamd) = handler.rspamd.as_ref() {
+                        let result = rspamd.scan(domain, &status).await?;
+                        description.push_str(&format!(
+                            " (rspamd: {action}, score {score})",
+                            action = result.action,
+                            score = result.score
+                        ));
+                        input.rspamd(result);
+                    }
+                }
+                (description, input, status.account, false)
+            }
+            ExplainTarget::Account { acct } => {
+                let account = handler.api.lookup_account(acct).await?;
+                let admin_account = handler.api.admin_account(&account.id).await?;
+                (
+                    format!(
+                        "account {acct} ({id})",
+                        acct = admin_account.account.acct,
+                        id = admin_account.id
+                    ),
+                    RuleMatcherInput::from(&admin_account),
+                    admin_account.account.clone(),
+                    !admin_account.approved,
+                )
+            }
+        };
+
+    println!("{description}");
+    println!();
+    for rule in config.rules.iter() {
+        print!("{}", explain_rule(rule, &input));
+    }
+    println!();
+
+    let verdict = Verdict::evaluate(&handler.config, &input);
+    if verdict.is_empty() {
+        println!("No rules matched, so m1000 would do nothing.");
+    } else if let Some(exemption) = handler.exemptions.check(&handler.api, &account).await? {
+        println!(
+            "Rules matched: {rule_names}. The account is exempt ({exemption}), so m1000 would do nothing.",
+            rule_names = verdict.rule_names.join(", ")
+        );
+    } else {
+        println!(
+            "Rules matched: {rule_names}. m1000 would do: {actions}.",
+            rule_names = verdict.rule_names.join(", "),
+            actions = describe_actions(&verdict.planned_actions(signup))
+        );
+    }
+    Ok(())
+}
+
+/// Whether part of a pattern matched.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Matched,
+    Unmatched,
+    /// Empty `any` or `all` lists, which are optimized away when compiling.
+    Ignored,
+}
+
+/// One node of a rule's pattern tree, as written in the config, with how it did against an input.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExplainNode {
+    pub label: String,
+    pub outcome: Outcome,
+    /// Only collected for leaves, since every matching ancestor has the same evidence.
+    pub evidence: Vec<String>,
+    pub children: Vec<ExplainNode>,
+}
+
+impl ExplainNode {
+    fn write(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        let mark = match self.outcome {
+            Outcome::Matched => "[x]",
+            Outcome::Unmatched => "[ ]",
+            Outcome::Ignored => "[-]",
+        };
+        write!(
+            f,
+            "{indent}{mark} {label}",
+            indent = "    ".repeat(depth),
+            label = self.label
+        )?;
+        if self.outcome == Outcome::Ignored {
+            write!(f, " (empty, ignored)")?;
+        }
+        if !self.evidence.is_empty() {
+            write!(f, " (evidence: {})", self.evidence.join(", "))?;
+        }
+        writeln!(f)?;
+        for child in self.children.iter() {
+            child.write(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ExplainNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+/// Explain one rule. The rule matches if any of its top-level patterns match.
+pub fn explain_rule(rule: &Rule, input: &RuleMatcherInput) -> ExplainNode {
+    let children = rule
+        .patterns
+        .iter()
+        .map(|pattern| pattern.explain(&|pattern| pattern, input))
+        .collect::<Vec<_>>();
+    ExplainNode {
+        label: format!("rule: {name}", name = rule.name),
+        outcome: if children
+            .iter()
+            .any(|child| child.outcome == Outcome::Matched)
+        {
+            Outcome::Matched
+        } else {
+            Outcome::Unmatched
+        },
+        evidence: vec![],
+        children,
+    }
+}
+
+/// Any part of a rule pattern.
+/// `wrap` turns the part back into a whole rule pattern by wrapping it in the parts above it,
+/// so that we can compile and match it the same way as the whole rule.
+trait Explain: Clone {
+    fn explain(&self, wrap: &dyn Fn(Self) -> RulePattern, input: &RuleMatcherInput) -> ExplainNode;
+}
+
+/// Match a part of a rule pattern against an input on its own.
+fn node(
+    label: impl Into<String>,
+    pattern: RulePattern,
+    input: &RuleMatcherInput,
+    children: Vec<ExplainNode>,
+) -> ExplainNode {
+    let (outcome, evidence) = match pattern.compile() {
+        Ok(matcher) if matcher.is_match(input) => {
+            let mut evidence = vec![];
+            if children.is_empty() {
+                matcher.evidence(input, &mut evidence);
+                evidence.sort();
+                evidence.dedup();
+            }
+            (Outcome::Matched, evidence)
+        }
+        Ok(_) => (Outcome::Unmatched, vec![]),
+        // Config loading already checked everything else, so this is an empty pattern.
+        Err(_) => (Outcome::Ignored, vec![]),
+    };
+    ExplainNode {
+        label: label.into(),
+        outcome,
+        evidence,
+        children,
+    }
+}
+
+fn leaf<P: Explain>(
+    label: String,
+    pattern: &P,
+    wrap: &dyn Fn(P) -> RulePattern,
+    input: &RuleMatcherInput,
+) -> ExplainNode {
+    node(label, wrap(pattern.clone()), input, vec![])
+}
+
+fn combinator<P: Explain>(
+    label: &str,
+    pattern: &P,
+    children: &[P],
+    wrap: &dyn Fn(P) -> RulePattern,
+    input: &RuleMatcherInput,
+) -> ExplainNode {
+    node(
+        label,
+        wrap(pattern.clone()),
+        input,
+        children
+            .iter()
+            .map(|child| child.explain(wrap, input))
+            .collect(),
+    )
+}
+
+/// A pattern that applies another kind of pattern to part of the input, like `text` or `mention`.
+/// These match exactly when what they contain matches, so they share a line with it
+/// unless it has children of its own.
+fn nested(label: &str, child: ExplainNode) -> ExplainNode {
+    ExplainNode {
+        label: format!("{label} > {child_label}", child_label = child.label),
+        ..child
+    }
+}
+
+fn rspamd_label(pattern: &RspamdPattern) -> String {
+    let mut parts = vec![];
+    if let Some(action) = &pattern.action {
+        parts.push(action.clone());
+    }
+    if let Some(score) = &pattern.score {
+        parts.push(score_label("score", score));
+    }
+    match &pattern.symbol {
+        Some(SymbolPattern::Name(name)) => parts.push(format!("symbol {name}")),
+        Some(SymbolPattern::Scored { name, score }) => parts.push(format!(
+            "symbol {name}, {range}",
+            range = score_label("score", score)
+        )),
+        None => {}
+    }
+    parts.join(", ")
+}
+
+/// Describe a score range like `8 <= score < 15`.
+fn score_label(label: &str, range: &ScoreRange) -> String {
+    match (range.at_least, range.below) {
+        (Some(at_least), Some(below)) => format!("{at_least} <= {label} < {below}"),
+        (Some(at_least), None) => format!("{label} >= {at_least}"),
+        (None, Some(below)) => format!("{label} < {below}"),
+        (None, None) => format!("any {label}"),
+    }
+}
+
+impl Explain for RulePattern {
+    fn explain(&self, wrap: &dyn Fn(Self) -> RulePattern, input: &RuleMatcherInput) -> ExplainNode {
+        match self {
+            Self::Account { account } => nested(
+                "account",
+                account.explain(&|account| wrap(Self::Account { account }), input),
+            ),
+            Self::Post { post } => nested(
+                "post",
+                post.explain(&|post| wrap(Self::Post { post }), input),
+            ),
+            Self::RspamdAction { action } => leaf(format!("rspamd: {action}"), self, wrap, input),
+            Self::Rspamd { rspamd } => leaf(
+                format!("rspamd: {label}", label = rspamd_label(rspamd)),
+                self,
+                wrap,
+                input,
+            ),
+            Self::Any { any: children } => combinator("any", self, children, wrap, input),
+            Self::All { all: children } => combinator("all", self, children, wrap, input),
+            Self::Not { not } => {
+                combinator("not", self, std::slice::from_ref(not.as_ref()), wrap, input)
+            }
+        }
+    }
+}
+
+impl Explain for AccountPattern {
+    fn explain(&self, wrap: &dyn Fn(Self) -> RulePattern, input: &RuleMatcherInput) -> ExplainNode {
+        match self {
+            Self::User { user } => nested(
+                "user",
+                user.explain(&|user| wrap(Self::User { user }), input),
+            ),
+            Self::Text { text } => nested(
+                "text",
+                text.explain(&|text| wrap(Self::Text { text }), input),
+            ),
+            Self::Reason { reason } => nested(
+                "reason",
+                reason.explain(&|reason| wrap(Self::Reason { reason }), input),
+            ),
+            Self::Email { email } => nested(
+                "email",
+                email.explain(&|email| wrap(Self::Email { email }), input),
+            ),
+            Self::Ip { ip } => nested("ip", ip.explain(&|ip| wrap(Self::Ip { ip }), input)),
+            Self::Any { any: children } => combinator("any", self, children, wrap, input),
+            Self::All { all: children } => combinator("all", self, children, wrap, input),
+            Self::Not { not } => {
+                combinator("not", self, std::slice::from_ref(not.as_ref()), wrap, input)
+            }
+        }
+    }
+}
+
+impl Explain for PostPattern {
+    fn explain(&self, wrap: &dyn Fn(Self) -> RulePattern, input: &RuleMatcherInput) -> ExplainNode {
+        match self {
+            Self::Text { text } => nested(
+                "text",
+                text.explain(&|text| wrap(Self::Text { text }), input),
+            ),
+            Self::Any { any: children } => combinator("any", self, children, wrap, input),
+            Self::All { all: children } => combinator("all", self, children, wrap, input),
+            Self::Not { not } => {
+                combinator("not", self, std::slice::from_ref(not.as_ref()), wrap, input)
+            }
+        }
+    }
+}
+
+impl Explain for TextPattern {
+    fn explain(&self, wrap: &dyn Fn(Self) -> RulePattern, input: &RuleMatcherInput) -> ExplainNode {
+        match self {
+            Self::Word { word } => leaf(format!("word: {word}"), self, wrap, input),
+            Self::Regex { regex } => leaf(format!("regex: {regex}"), self, wrap, input),
+            Self::Link { link } => nested(
+                "link",
+                link.explain(&|link| wrap(Self::Link { link }), input),
+            ),
+            Self::Mention { mention } => nested(
+                "mention",
+                mention.explain(&|mention| wrap(Self::Mention { mention }), input),
+            ),
+            Self::Hashtag { hashtag } => nested(
+                "hashtag",
+                hashtag.explain(&|hashtag| wrap(Self::Hashtag { hashtag }), input),
+            ),
+            Self::Any { any: children } => combinator("any", self, children, wrap, input),
+            Self::All { all: children } => combinator("all", self, children, wrap, input),
+            Self::Not { not } => {
+                combinator("not", self, std::slice::from_ref(not.as_ref()), wrap, input)
+            }
+        }
+    }
+}
+
+impl Explain for UserPattern {
+    fn explain(&self, wrap: &dyn Fn(Self) -> RulePattern, input: &RuleMatcherInput) -> ExplainNode {
+        match self {
+            Self::Username { username } => nested(
+                "username",
+                username.explain(&|username| wrap(Self::Username { username }), input),
+            ),
+            Self::Instance { instance } => nested(
+                "instance",
+                instance.explain(&|instance| wrap(Self::Instance { instance }), input),
+            ),
+            Self::Local { local } => leaf(format!("local: {local}"), self, wrap, input),
+            Self::Any { any: children } => combinator("any", self, children, wrap, input),
+            Self::All { all: children } => combinator("all", self, children, wrap, input),
+            Self::Not { not } => {
+                combinator("not", self, std::slice::from_ref(not.as_ref()), wrap, input)
+            }
+        }
+    }
+}
+
+impl Explain for EmailPattern {
+    fn explain(&self, wrap: &dyn Fn(Self) -> RulePattern, input: &RuleMatcherInput) -> ExplainNode {
+        match self {
+            Self::Local { local } => nested(
+                "local",
+                local.explain(&|local| wrap(Self::Local { local }), input),
+            ),
+            Self::Domain { domain } => nested(
+                "domain",
+                domain.explain(&|domain| wrap(Self::Domain { domain }), input),
+            ),
+            Self::Disposable { disposable } => {
+                leaf(format!("disposable: {disposable}"), self, wrap, input)
+            }
+            Self::Any { any: children } => combinator("any", self, children, wrap, input),
+            Self::All { all: children } => combinator("all", self, children, wrap, input),
+            Self::Not { not } => {
+                combinator("not", self, std::slice::from_ref(not.as_ref()), wrap, input)
+            }
+        }
+    }
+}
+
+impl Explain for IpPattern {
+    fn explain(&self, wrap: &dyn Fn(Self) -> RulePattern, input: &RuleMatcherInput) -> ExplainNode {
+        match self {
+            Self::Cidr { cidr } => leaf(format!("cidr: {cidr}"), self, wrap, input),
+            Self::List { list } => leaf(
+                format!("list: {list}", list = list.to_string_lossy()),
+                self,
+                wrap,
+                input,
+            ),
+            Self::Any { any: children } => combinator("any", self, children, wrap, input),
+            Self::All { all: children } => combinator("all", self, children, wrap, input),
+            Self::Not { not } => {
+                combinator("not", self, std::slice::from_ref(not.as_ref()), wrap, input)
+            }
+        }
+    }
+}
+
+impl Explain for StringPattern {
+    fn explain(&self, wrap: &dyn Fn(Self) -> RulePattern, input: &RuleMatcherInput) -> ExplainNode {
+        match self {
+            Self::Word { word } => leaf(format!("word: {word}"), self, wrap, input),
+            Self::Regex { regex } => leaf(format!("regex: {regex}"), self, wrap, input),
+            Self::Any { any: children } => combinator("any", self, children, wrap, input),
+            Self::All { all: children } => combinator("all", self, children, wrap, input),
+            Self::Not { not } => {
+                combinator("not", self, std::slice::from_ref(not.as_ref()), wrap, input)
+            }
+        }
+    }
+}
+
+impl Explain for LinkPattern {
+    fn explain(&self, wrap: &dyn Fn(Self) -> RulePattern, input: &RuleMatcherInput) -> ExplainNode {
+        match self {
+            Self::Word { word } => leaf(format!("word: {word}"), self, wrap, input),
+            Self::Regex { regex } => leaf(format!("regex: {regex}"), self, wrap, input),
+            Self::Domain { domain } => leaf(format!("domain: {domain}"), self, wrap, input),
+            Self::Any { any: children } => combinator("any", self, children, wrap, input),
+            Self::All { all: children } => combinator("all", self, children, wrap, input),
+            Self::Not { not } => {
+                combinator("not", self, std::slice::from_ref(not.as_ref()), wrap, input)
+            }
+        }
+    }
+}
+
+impl Explain for InstancePattern {
+    fn explain(&self, wrap: &dyn Fn(Self) -> RulePattern, input: &RuleMatcherInput) -> ExplainNode {
+        match self {
+            Self::Word { word } => leaf(format!("word: {word}"), self, wrap, input),
+            Self::Regex { regex } => leaf(format!("regex: {regex}"), self, wrap, input),
+            Self::Domain { domain } => leaf(format!("domain: {domain}"), self, wrap, input),
+            Self::Any { any: children } => combinator("any", self, children, wrap, input),
+            Self::All { all: children } => combinator("all", self, children, wrap, input),
+            Self::Not { not } => {
+                combinator("not", self, std::slice::from_ref(not.as_ref()), wrap, input)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RuleExample;
+    use crate::example::example_input;
+
+    #[test]
+    fn test_explain_rule() {
+        let rule: Rule = serde_yaml::from_str(
+            r#"
+            name: casino spam
+            patterns:
+            - post:
+                text:
+                  any:
+                  - word: casino
+                  - word: poker
+            - all: []
+            "#,
+        )
+        .unwrap();
+        let input = example_input(&RuleExample::Text(
+            "<p>Best casino bonuses!</p>".to_string(),
+        ))
+        .unwrap();
+
+        assert_eq!(
+            "\
+[x] rule: casino spam
+    [x] post > text > any
+        [x] word: casino (evidence: casino)
+        [ ] word: poker
+    [-] all (empty, ignored)
+",
+            explain_rule(&rule, &input).to_string()
+        );
+    }
+}
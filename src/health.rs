@@ -0,0 +1,313 @@
+use crate::audit::AuditEntry;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use time::{Duration, OffsetDateTime};
+
+/// Shared health state for the whole server, updated by each domain user's event handler.
+#[derive(Clone, Debug, Default)]
+pub struct Health(Arc<Mutex<HealthState>>);
+
+#[derive(Clone, Debug, Default, Serialize)]
+struct HealthState {
+    /// Keyed by `username@domain`.
+    handlers: BTreeMap<String, HandlerHealth>,
+    /// Not present if rspamd isn't configured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rspamd: Option<ComponentHealth>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct HandlerHealth {
+    /// False if the handler task has exited or panicked.
+    running: bool,
+    #[serde(with = "time::serde::rfc3339")]
+    started_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    last_event_at: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    last_event_success_at: Option<OffsetDateTime>,
+    /// Mastodon API calls made by `m1000` itself.
+    api: ComponentHealth,
+    /// Streaming API connection. Not present if this user doesn't use streaming.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream: Option<ComponentHealth>,
+    /// Not present if this user doesn't use polling.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    polling: Option<ComponentHealth>,
+}
+
+/// How far back to look when deciding whether something is working.
+const HEALTH_WINDOW: Duration = Duration::minutes(5);
+
+/// A component needs at least this many attempts in the window before its failure rate counts,
+/// so that one failure in a quiet period doesn't make it unhealthy.
+const MIN_ATTEMPTS: usize = 3;
+
+/// Something that can fail now and then, such as rspamd or a streaming API connection.
+/// Unhealthy if most of its recent attempts failed, or if it's been failing for the whole window.
+#[derive(Clone, Debug, Default, Serialize)]
+struct ComponentHealth {
+    #[serde(with = "time::serde::rfc3339::option")]
+    last_success_at: Option<OffsetDateTime>,
+    /// When the current run of failures started, if the most recent attempt failed.
+    #[serde(with = "time::serde::rfc3339::option")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failing_since: Option<OffsetDateTime>,
+    /// When each attempt in the window happened, and whether it succeeded, oldest first.
+    #[serde(skip)]
+    recent: VecDeque<(OffsetDateTime, bool)>,
+    /// Attempts in the window.
+    recent_attempts: usize,
+    /// Failed attempts in the window.
+    recent_failures: usize,
+}
+
+impl ComponentHealth {
+    fn update<T>(&mut self, result: &anyhow::Result<T>) {
+        self.update_at(OffsetDateTime::now_utc(), result);
+    }
+
+    fn update_at<T>(&mut self, now: OffsetDateTime, result: &anyhow::Result<T>) {
+        match result {
+            Ok(_) => {
+                self.last_success_at = Some(now);
+                self.failing_since = None;
+            }
+            Err(_) => {
+                self.failing_since.get_or_insert(now);
+            }
+        }
+        self.recent.push_back((now, result.is_ok()));
+        self.forget_before(now - HEALTH_WINDOW);
+    }
+
+    fn forget_before(&mut self, oldest: OffsetDateTime) {
+        while self.recent.front().is_some_and(|(at, _)| *at < oldest) {
+            self.recent.pop_front();
+        }
+        self.recent_attempts = self.recent.len();
+        self.recent_failures = self.recent.iter().filter(|(_, ok)| !ok).count();
+    }
+
+    fn is_ok_at(&mut self, now: OffsetDateTime) -> bool {
+        self.forget_before(now - HEALTH_WINDOW);
+        let mostly_failing =
+            self.recent_attempts >= MIN_ATTEMPTS && 2 * self.recent_failures > self.recent_attempts;
+        let failing_for_window = self
+            .failing_since
+            .is_some_and(|failing_since| now - failing_since >= HEALTH_WINDOW);
+        !mostly_failing && !failing_for_window
+    }
+
+    fn ok(component: Option<&mut Self>, now: OffsetDateTime) -> bool {
+        component.is_none_or(|component| component.is_ok_at(now))
+    }
+}
+
+/// Response body for health check endpoints.
+#[derive(Clone, Debug, Serialize)]
+pub struct HealthReport {
+    /// All handler tasks are running.
+    live: bool,
+    /// Live, and no critical component is mostly failing, or has been failing for a while.
+    ready: bool,
+    #[serde(flatten)]
+    state: HealthState,
+}
+
+impl Health {
+    /// Start tracking rspamd. Until this is called, rspamd is assumed to be unused.
+    pub fn rspamd_configured(&self) {
+        self.0
+            .lock()
+            .unwrap()
+            .rspamd
+            .get_or_insert_with(Default::default);
+    }
+
+    pub fn rspamd_result<T>(&self, result: &anyhow::Result<T>) {
+        let mut state = self.0.lock().unwrap();
+        state
+            .rspamd
+            .get_or_insert_with(Default::default)
+            .update(result);
+    }
+
+    /// A handler task has started.
+    /// `streaming` and `polling` are whether it gets events from those as well as webhooks.
+    pub fn handler_started(&self, key: &str, streaming: bool, polling: bool) {
+        let now = OffsetDateTime::now_utc();
+        self.0.lock().unwrap().handlers.insert(
+            key.to_string(),
+            HandlerHealth {
+                running: true,
+                started_at: now,
+                last_event_at: None,
+                last_event_success_at: None,
+                api: Default::default(),
+                stream: streaming.then(Default::default),
+                polling: polling.then(Default::default),
+            },
+        );
+    }
+
+    /// A Mastodon API call made for a handler has finished, successfully or not, after any retries.
+    pub fn api_result<T>(&self, key: &str, result: &anyhow::Result<T>) {
+        if let Some(handler) = self.0.lock().unwrap().handlers.get_mut(key) {
+            handler.api.update(result);
+        }
+    }
+
+    /// A handler's streaming API connection has connected, or failed or closed.
+    pub fn stream_result<T>(&self, key: &str, result: &anyhow::Result<T>) {
+        if let Some(handler) = self.0.lock().unwrap().handlers.get_mut(key) {
+            handler
+                .stream
+                .get_or_insert_with(Default::default)
+                .update(result);
+        }
+    }
+
+    /// A handler's poll of all of its sources has finished, successfully or not.
+    pub fn poll_result<T>(&self, key: &str, result: &anyhow::Result<T>) {
+        if let Some(handler) = self.0.lock().unwrap().handlers.get_mut(key) {
+            handler
+                .polling
+                .get_or_insert_with(Default::default)
+                .update(result);
+        }
+    }
+
+    /// A handler task has exited or panicked. The reason goes in the log, not the health report.
+    pub fn handler_stopped(&self, key: &str) {
+        if let Some(handler) = self.0.lock().unwrap().handlers.get_mut(key) {
+            handler.running = false;
+        }
+    }
+
+    /// Update a handler's health from the audit entry for an event it just handled.
+    pub fn event_handled(&self, key: &str, audit_entry: &AuditEntry) {
+        let now = OffsetDateTime::now_utc();
+        let mut state = self.0.lock().unwrap();
+        let Some(handler) = state.handlers.get_mut(key) else {
+            return;
+        };
+        handler.last_event_at = Some(now);
+        if audit_entry.error.is_none() {
+            handler.last_event_success_at = Some(now);
+        }
+    }
+
+    pub fn report(&self) -> HealthReport {
+        let mut state = self.0.lock().unwrap().clone();
+        let now = OffsetDateTime::now_utc();
+        let live = state.handlers.values().all(|handler| handler.running);
+        // Check everything, so that every component's recent counts are up to date in the report.
+        let mut ready = live;
+        for handler in state.handlers.values_mut() {
+            ready &= ComponentHealth::ok(Some(&mut handler.api), now);
+            ready &= ComponentHealth::ok(handler.stream.as_mut(), now);
+            ready &= ComponentHealth::ok(handler.polling.as_mut(), now);
+        }
+        ready &= ComponentHealth::ok(state.rspamd.as_mut(), now);
+        HealthReport { live, ready, state }
+    }
+}
+
+/// Full health check, used by `m1000 healthcheck`: fails if anything critical is down.
+pub async fn serve_healthcheck(
+    Extension(health): Extension<Health>,
+) -> (StatusCode, Json<HealthReport>) {
+    let report = health.report();
+    (status_code(report.ready), Json(report))
+}
+
+/// Readiness check: same as the full health check.
+pub async fn serve_readyz(
+    Extension(health): Extension<Health>,
+) -> (StatusCode, Json<HealthReport>) {
+    serve_healthcheck(Extension(health)).await
+}
+
+/// Liveness check: only fails if a handler task has died, which requires a restart to fix.
+pub async fn serve_livez(Extension(health): Extension<Health>) -> (StatusCode, Json<HealthReport>) {
+    let report = health.report();
+    (status_code(report.live), Json(report))
+}
+
+fn status_code(ok: bool) -> StatusCode {
+    if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_report() {
+        let health = Health::default();
+        health.handler_started("automod@example.test", true, false);
+        health.rspamd_configured();
+        let report = health.report();
+        assert!(report.live && report.ready);
+
+        // One failure isn't enough to be unready, but mostly failing is.
+        health.rspamd_result::<()>(&Err(anyhow!("rspamc exited with code 1")));
+        assert!(health.report().ready);
+        health.rspamd_result(&Ok(()));
+        health.rspamd_result::<()>(&Err(anyhow!("rspamc exited with code 1")));
+        health.rspamd_result::<()>(&Err(anyhow!("rspamc exited with code 1")));
+        let report = health.report();
+        assert!(report.live && !report.ready);
+
+        health.rspamd_result(&Ok(()));
+        health.rspamd_result(&Ok(()));
+        health.api_result::<()>("automod@example.test", &Err(anyhow!("502 Bad Gateway")));
+        let report = health.report();
+        assert!(report.live && report.ready);
+
+        health.handler_stopped("automod@example.test");
+        let report = health.report();
+        assert!(!report.live && !report.ready);
+    }
+
+    #[test]
+    fn test_component_window() {
+        let start = OffsetDateTime::now_utc();
+        let mut component = ComponentHealth::default();
+        component.update_at::<()>(start, &Err(anyhow!("Connection closed")));
+        assert!(component.is_ok_at(start));
+
+        // A component that keeps failing, but not often enough to have a failure rate, still becomes unready.
+        let later = start + Duration::minutes(4);
+        component.update_at::<()>(later, &Err(anyhow!("Connection refused")));
+        assert!(component.is_ok_at(later));
+        assert!(!component.is_ok_at(start + HEALTH_WINDOW));
+
+        // Recovering makes it ready again straight away.
+        let recovered = start + HEALTH_WINDOW + Duration::seconds(1);
+        component.update_at(recovered, &Ok(()));
+        assert!(component.is_ok_at(recovered));
+
+        // Old failures age out of the window.
+        for seconds in 0..3 {
+            component.update_at::<()>(
+                recovered + Duration::seconds(seconds),
+                &Err(anyhow!("Connection refused")),
+            );
+        }
+        assert!(!component.is_ok_at(recovered + Duration::seconds(3)));
+        component.update_at(recovered + Duration::seconds(4), &Ok(()));
+        assert!(component.is_ok_at(recovered + HEALTH_WINDOW + Duration::seconds(3)));
+        assert_eq!(1, component.recent_attempts);
+        assert_eq!(0, component.recent_failures);
+    }
+}
@@ -0,0 +1,681 @@
+use crate::dead_letter::{DeadLetter, DeadLetterQueue};
+use crate::health::Health;
+use crate::metrics::{api_path_label, MASTODON_API_ERRORS};
+use anyhow::{anyhow, bail, Result};
+use mastodon_async::entities::account::Account;
+use mastodon_async::entities::admin;
+use mastodon_async::entities::status::Status;
+use mastodon_async::entities::{AccountId, ReportId, RuleId, StatusId};
+use mastodon_async::Mastodon;
+use rand::Rng;
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+use reqwest::{Client, Method, Response, StatusCode};
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+use tracing::warn;
+use url::Url;
+
+/// Thin client for Mastodon API methods that `mastodon-async` doesn't cover,
+/// and for moderation actions that need retries.
+/// Shares the access token of the domain user's [`Mastodon`] client.
+#[derive(Clone, Debug)]
+pub struct ApiClient {
+    client: Client,
+    base: String,
+    token: String,
+    /// Instance domain, for metrics.
+    domain: String,
+    /// When our rate limit resets, if we've used it up. Shared between clones.
+    rate_limit_reset: Arc<Mutex<Option<OffsetDateTime>>>,
+    /// Where POSTs go if they still fail after retrying.
+    dead_letters: Option<DeadLetterQueue>,
+    /// Where to report whether the instance is reachable, and the `username@domain` key to report it under.
+    health: Option<(Health, String)>,
+}
+
+/// For API methods that don't take any parameters.
+const NO_PARAMS: &[(&str, &str)] = &[];
+
+/// Maximum page size for admin account lists.
+const ADMIN_ACCOUNTS_PAGE_SIZE: usize = 200;
+
+/// Total tries for one API call, including the first.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Upper bound of the backoff before the first retry. Doubles with each retry.
+const BASE_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Longest we'll wait before any one retry, including waiting for a rate limit to reset.
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// Why an API call failed, and whether trying again might help.
+pub enum ApiError {
+    /// Couldn't connect, or rate limited, so the server didn't act on the request.
+    Unsent(anyhow::Error),
+    /// Other network error, or server error. The server may or may not have acted on the request.
+    Transient(anyhow::Error),
+    /// Anything else, such as a bad request or missing permissions.
+    Permanent(anyhow::Error),
+}
+
+impl From<ApiError> for anyhow::Error {
+    fn from(e: ApiError) -> Self {
+        match e {
+            ApiError::Unsent(e) | ApiError::Transient(e) | ApiError::Permanent(e) => e,
+        }
+    }
+}
+
+impl ApiError {
+    /// Whether to try again now. Only GETs are retried after errors that might have come after the server
+    /// acted on the request: retrying a POST such as a report could do it twice.
+    fn retryable(&self, method: &Method) -> bool {
+        match self {
+            Self::Unsent(_) => true,
+            Self::Transient(_) => method == Method::GET,
+            Self::Permanent(_) => false,
+        }
+    }
+}
+
+impl ApiClient {
+    pub fn new(client: &Client, mastodon: &Mastodon) -> Self {
+        let base = mastodon.data.base.trim_end_matches('/').to_string();
+        let domain = Url::parse(&base)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_else(|| base.clone());
+        Self {
+            client: client.clone(),
+            base,
+            token: mastodon.data.token.to_string(),
+            domain,
+            rate_limit_reset: Default::default(),
+            dead_letters: None,
+            health: None,
+        }
+    }
+
+    /// Save POSTs that still fail after retrying so that `m1000 retry-failed` can replay them.
+    pub fn with_dead_letter_queue(mut self, dead_letters: DeadLetterQueue) -> Self {
+        self.dead_letters = Some(dead_letters);
+        self
+    }
+
+    /// Report whether calls reach the instance to a handler's health.
+    pub fn with_health(mut self, health: &Health, key: &str) -> Self {
+        self.health = Some((health.clone(), key.to_string()));
+        self
+    }
+
+    async fn get<Q, T>(&self, path: &str, query: &Q) -> Result<T>
+    where
+        Q: Serialize + ?Sized,
+        T: DeserializeOwned,
+    {
+        let result = async {
+            let query = serde_urlencoded::to_string(query)?;
+            let response = self.send(Method::GET, path, &query).await?;
+            Ok(response.json().await?)
+        }
+        .await;
+        self.count_error(Method::GET, path, &result);
+        result
+    }
+
+    async fn post<F, T>(&self, path: &str, form: &F) -> Result<T>
+    where
+        F: Serialize + ?Sized,
+        T: DeserializeOwned,
+    {
+        let result = async {
+            let form = serde_urlencoded::to_string(form)?;
+            match self.send(Method::POST, path, &form).await {
+                Ok(response) => Ok(response.json().await?),
+                // Only queue POSTs that never reached the server,
+                // since replaying one that it may have acted on could do it twice.
+                Err(ApiError::Unsent(e)) => {
+                    if let Some(dead_letters) = self.dead_letters.as_ref() {
+                        dead_letters.push(&DeadLetter::new(path, &form, &e)).await;
+                    }
+                    Err(e)
+                }
+                Err(ApiError::Transient(e) | ApiError::Permanent(e)) => Err(e),
+            }
+        }
+        .await;
+        self.count_error(Method::POST, path, &result);
+        result
+    }
+
+    /// Replay a POST from the dead-letter queue. Doesn't queue it again if it fails,
+    /// but tells the caller whether it's safe to.
+    pub async fn replay(&self, dead_letter: &DeadLetter) -> Result<(), ApiError> {
+        let result = async {
+            let response = self
+                .send(Method::POST, &dead_letter.path, &dead_letter.form)
+                .await?;
+            let _: IgnoredAny = response
+                .json()
+                .await
+                .map_err(|e| ApiError::Transient(e.into()))?;
+            Ok(())
+        }
+        .await;
+        self.count_error(Method::POST, &dead_letter.path, &result);
+        result
+    }
+
+    /// Send a request, retrying transient failures with exponential backoff and full jitter,
+    /// and waiting for the rate limit to reset if we've run out.
+    /// `params` is already URL-encoded: a query string for GETs, or a form body for POSTs.
+    /// Reports whether the request reached the instance in the end to the handler's health.
+    async fn send(&self, method: Method, path: &str, params: &str) -> Result<Response, ApiError> {
+        let result = self.send_with_retries(method, path, params).await;
+        if let Some((health, key)) = self.health.as_ref() {
+            // Requests that Mastodon refused still reached it.
+            let reached = match result.as_ref() {
+                Err(ApiError::Unsent(e) | ApiError::Transient(e)) => Err(anyhow!("{e}")),
+                _ => Ok(()),
+            };
+            health.api_result(key, &reached);
+        }
+        result
+    }
+
+    async fn send_with_retries(
+        &self,
+        method: Method,
+        path: &str,
+        params: &str,
+    ) -> Result<Response, ApiError> {
+        let mut attempt = 0;
+        loop {
+            self.wait_for_rate_limit().await;
+            let error = match self.send_once(method.clone(), path, params).await {
+                Ok(response) => return Ok(response),
+                Err(e) => e,
+            };
+            attempt += 1;
+            if attempt >= MAX_ATTEMPTS || !error.retryable(&method) {
+                return Err(error);
+            }
+            let error = anyhow::Error::from(error);
+            let delay = backoff(attempt, rand::thread_rng().gen());
+            warn!(
+                "{domain}: {method} {path} failed (attempt {attempt} of {MAX_ATTEMPTS}), \
+                retrying in {delay:?}: {error}",
+                domain = self.domain,
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    async fn send_once(
+        &self,
+        method: Method,
+        path: &str,
+        params: &str,
+    ) -> Result<Response, ApiError> {
+        let mut url = format!("{base}{path}", base = self.base);
+        let mut body = None;
+        if method == Method::GET {
+            if !params.is_empty() {
+                url = format!("{url}?{params}");
+            }
+        } else {
+            body = Some(params.to_string());
+        }
+        let mut request = self.client.request(method.clone(), url);
+        if let Some(body) = body {
+            request = request
+                .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(body);
+        }
+
+        let response = request.bearer_auth(&self.token).send().await.map_err(|e| {
+            if e.is_connect() {
+                ApiError::Unsent(e.into())
+            } else {
+                ApiError::Transient(e.into())
+            }
+        })?;
+        self.update_rate_limit(response.headers()).await;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let error = anyhow!(
+            "{method} {path} failed: {status}: {body}",
+            body = response.text().await.unwrap_or_default()
+        );
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            Err(ApiError::Unsent(error))
+        } else if status.is_server_error() {
+            Err(ApiError::Transient(error))
+        } else {
+            Err(ApiError::Permanent(error))
+        }
+    }
+
+    /// Remember when the rate limit resets if this response used it up.
+    async fn update_rate_limit(&self, headers: &HeaderMap) {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        if header("X-RateLimit-Remaining") != Some("0") {
+            return;
+        }
+        if let Some(reset) = header("X-RateLimit-Reset")
+            .and_then(|reset| OffsetDateTime::parse(reset, &Rfc3339).ok())
+        {
+            *self.rate_limit_reset.lock().await = Some(reset);
+        }
+    }
+
+    async fn wait_for_rate_limit(&self) {
+        // Copy it, so that every caller waiting on the same rate limit sees it.
+        let Some(reset) = *self.rate_limit_reset.lock().await else {
+            return;
+        };
+        let Ok(delay) = Duration::try_from(reset - OffsetDateTime::now_utc()) else {
+            // Already reset.
+            return;
+        };
+        let delay = delay.min(MAX_BACKOFF);
+        warn!(
+            "{domain}: Rate limited until {reset}, waiting {delay:?}",
+            domain = self.domain
+        );
+        tokio::time::sleep(delay).await;
+    }
+
+    fn count_error<T, E>(&self, method: Method, path: &str, result: &Result<T, E>) {
+        if result.is_err() {
+            MASTODON_API_ERRORS
+                .with_label_values(&[&self.domain, method.as_str(), &api_path_label(path)])
+                .inc();
+        }
+    }
+
+    /// List all local accounts with a staff role: owners, admins, and moderators.
+    pub async fn staff_accounts(&self) -> Result<Vec<AccountSummary>> {
+        let mut accounts = vec![];
+        let mut max_id: Option<AccountId> = None;
+        loop {
+            let mut query = vec![
+                ("staff", "true".to_string()),
+                ("limit", ADMIN_ACCOUNTS_PAGE_SIZE.to_string()),
+            ];
+            if let Some(max_id) = max_id.as_ref() {
+                query.push(("max_id", max_id.to_string()));
+            }
+            let page: Vec<AccountSummary> = self.get("/api/v1/admin/accounts", &query).await?;
+            let last_page = page.len() < ADMIN_ACCOUNTS_PAGE_SIZE;
+            max_id = page.last().map(|account| account.id.clone());
+            accounts.extend(page);
+            if last_page || max_id.is_none() {
+                break;
+            }
+        }
+        Ok(accounts)
+    }
+
+    /// One page of statuses from a timeline or an account's statuses, newest first.
+    pub async fn statuses(&self, path: &str, query: &[(&str, String)]) -> Result<Vec<Status>> {
+        self.get(path, query).await
+    }
+
+    /// One page of local accounts from the admin account list, newest first.
+    pub async fn local_accounts(&self, max_id: Option<&str>) -> Result<Vec<admin::Account>> {
+        let mut query = vec![
+            ("local", "true".to_string()),
+            ("limit", ADMIN_ACCOUNTS_PAGE_SIZE.to_string()),
+        ];
+        if let Some(max_id) = max_id {
+            query.push(("max_id", max_id.to_string()));
+        }
+        self.get("/api/v1/admin/accounts", &query).await
+    }
+
+    /// One page of the admin account list, newest first.
+    pub async fn admin_accounts(&self, query: &[(&str, String)]) -> Result<Vec<admin::Account>> {
+        self.get("/api/v1/admin/accounts", query).await
+    }
+
+    /// One page of the admin report list, newest first. Only unresolved reports by default.
+    pub async fn admin_reports(&self, query: &[(&str, String)]) -> Result<Vec<admin::Report>> {
+        self.get("/api/v1/admin/reports", query).await
+    }
+
+    /// Look up an account by its handle: `username` for local accounts, or `username@domain` for remote ones.
+    pub async fn lookup_account(&self, acct: &str) -> Result<Account> {
+        self.get("/api/v1/accounts/lookup", &[("acct", acct)]).await
+    }
+
+    /// Get the admin view of an account, with its email and IP addresses if it's local.
+    pub async fn admin_account(&self, account_id: &AccountId) -> Result<admin::Account> {
+        self.get(&format!("/api/v1/admin/accounts/{account_id}"), NO_PARAMS)
+            .await
+    }
+
+    /// Get the admin view of a report.
+    pub async fn admin_report(&self, report_id: &ReportId) -> Result<admin::Report> {
+        self.get(&format!("/api/v1/admin/reports/{report_id}"), NO_PARAMS)
+            .await
+    }
+
+    /// Base URL of the instance's streaming API, as advertised by the instance.
+    pub async fn streaming_url(&self) -> Result<String> {
+        let instance: InstanceSummary = self.get("/api/v1/instance", NO_PARAMS).await?;
+        Ok(instance.urls.streaming_api)
+    }
+
+    /// Get the bot user's relationship with another account.
+    pub async fn relationship(&self, account_id: &AccountId) -> Result<Relationship> {
+        let relationships: Vec<Relationship> = self
+            .get(
+                "/api/v1/accounts/relationships",
+                &[("id[]", account_id.to_string())],
+            )
+            .await?;
+        let Some(relationship) = relationships.into_iter().next() else {
+            bail!("No relationship returned for account {account_id}");
+        };
+        Ok(relationship)
+    }
+
+    /// Approve a pending registration.
+    pub async fn approve_account(&self, account_id: &AccountId) -> Result<AccountSummary> {
+        self.post(
+            &format!("/api/v1/admin/accounts/{account_id}/approve"),
+            NO_PARAMS,
+        )
+        .await
+    }
+
+    /// Reject a pending registration, which deletes the account.
+    pub async fn reject_account(&self, account_id: &AccountId) -> Result<AccountSummary> {
+        self.post(
+            &format!("/api/v1/admin/accounts/{account_id}/reject"),
+            NO_PARAMS,
+        )
+        .await
+    }
+
+    /// List the instance's rules, so we can quote them.
+    pub async fn instance_rules(&self) -> Result<Vec<InstanceRule>> {
+        self.get("/api/v1/instance/rules", NO_PARAMS).await
+    }
+
+    /// Block signups from an email domain.
+    pub async fn create_email_domain_block(&self, domain: &str) -> Result<EmailDomainBlock> {
+        self.post("/api/v1/admin/email_domain_blocks", &[("domain", domain)])
+            .await
+    }
+
+    /// Block or limit an IP range.
+    pub async fn create_ip_block(&self, request: &IpBlockRequest) -> Result<IpBlock> {
+        self.post("/api/v1/admin/ip_blocks", request).await
+    }
+
+    /// Block a remote domain.
+    pub async fn create_domain_block(&self, request: &DomainBlockRequest) -> Result<DomainBlock> {
+        self.post("/api/v1/admin/domain_blocks", request).await
+    }
+
+    /// File a report.
+    pub async fn create_report(&self, request: &ReportRequest) -> Result<ReportSummary> {
+        self.post("/api/v1/reports", &request.form()).await
+    }
+
+    /// Perform a moderation action on an account, optionally with a warning.
+    pub async fn perform_account_action(
+        &self,
+        account_id: &AccountId,
+        request: &AccountActionRequest,
+    ) -> Result<()> {
+        let _: IgnoredAny = self
+            .post(
+                &format!("/api/v1/admin/accounts/{account_id}/action"),
+                &request.form(),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+/// Exponential backoff with full jitter: a random delay up to `BASE_BACKOFF * 2^(attempt - 1)`,
+/// capped at `MAX_BACKOFF`. `jitter` is in `[0, 1)`.
+pub fn backoff(attempt: u32, jitter: f64) -> Duration {
+    let ceiling = BASE_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(MAX_BACKOFF);
+    ceiling.mul_f64(jitter)
+}
+
+/// Parameters for filing a report.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReportRequest {
+    pub account_id: AccountId,
+    pub status_ids: Vec<StatusId>,
+    pub comment: String,
+    /// One of `spam`, `violation`, or `other`.
+    pub category: String,
+    /// Only used with the `violation` category.
+    pub rule_ids: Vec<RuleId>,
+    pub forward: bool,
+}
+
+impl ReportRequest {
+    /// Form encoding with Rails-style array parameters, which `serde_urlencoded` can't do on its own.
+    fn form(&self) -> Vec<(&'static str, String)> {
+        let mut form = vec![
+            ("account_id", self.account_id.to_string()),
+            ("comment", self.comment.clone()),
+            ("category", self.category.clone()),
+            ("forward", self.forward.to_string()),
+        ];
+        form.extend(
+            self.status_ids
+                .iter()
+                .map(|status_id| ("status_ids[]", status_id.to_string())),
+        );
+        form.extend(
+            self.rule_ids
+                .iter()
+                .map(|rule_id| ("rule_ids[]", rule_id.to_string())),
+        );
+        form
+    }
+}
+
+/// The parts of a v1 instance entity that we need.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+struct InstanceSummary {
+    urls: InstanceUrls,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+struct InstanceUrls {
+    streaming_api: String,
+}
+
+/// The parts of a report entity that we need.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct ReportSummary {
+    pub id: ReportId,
+}
+
+/// Parameters for an admin account action.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccountActionRequest {
+    /// One of `none`, `sensitive`, `disable`, `silence`, or `suspend`.
+    pub action_type: String,
+    pub report_id: Option<ReportId>,
+    /// Warning shown to the account's owner.
+    pub text: Option<String>,
+    /// Mastodon emails the account's owner unless this is `false`.
+    pub send_email_notification: Option<bool>,
+}
+
+impl AccountActionRequest {
+    fn form(&self) -> Vec<(&'static str, String)> {
+        let mut form = vec![("type", self.action_type.clone())];
+        if let Some(send_email_notification) = self.send_email_notification {
+            form.push((
+                "send_email_notification",
+                send_email_notification.to_string(),
+            ));
+        }
+        if let Some(report_id) = self.report_id.as_ref() {
+            form.push(("report_id", report_id.to_string()));
+        }
+        if let Some(text) = self.text.as_ref() {
+            form.push(("text", text.clone()));
+        }
+        form
+    }
+}
+
+/// Parameters for creating an admin domain block.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DomainBlockRequest {
+    pub domain: String,
+    /// One of `silence`, `suspend`, or `noop`.
+    pub severity: String,
+    pub reject_media: bool,
+    pub private_comment: String,
+}
+
+/// The parts of an admin domain block entity that we need.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct DomainBlock {
+    pub id: String,
+    pub domain: String,
+}
+
+/// The parts of an admin email domain block entity that we need.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct EmailDomainBlock {
+    pub id: String,
+    pub domain: String,
+}
+
+/// Parameters for creating an admin IP block.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IpBlockRequest {
+    /// CIDR range.
+    pub ip: String,
+    /// One of `sign_up_requires_approval`, `sign_up_block`, or `no_access`.
+    pub severity: String,
+    pub comment: String,
+    /// Seconds until the block expires. Blocks without this never expire.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_in: Option<u64>,
+}
+
+/// The parts of an admin IP block entity that we need.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct IpBlock {
+    pub id: String,
+    pub ip: String,
+}
+
+/// The parts of an admin account entity that we need for staff lookups and registrations.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct AccountSummary {
+    pub id: AccountId,
+    pub username: String,
+    #[serde(default)]
+    pub domain: Option<String>,
+}
+
+/// An instance rule entity.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct InstanceRule {
+    pub id: String,
+    pub text: String,
+}
+
+/// The parts of a relationship entity that we need for exemptions.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Relationship {
+    pub id: AccountId,
+    pub following: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(Duration::ZERO, backoff(1, 0.0));
+        assert_eq!(Duration::from_millis(250), backoff(1, 0.5));
+        assert_eq!(Duration::from_millis(1000), backoff(3, 0.5));
+        assert_eq!(MAX_BACKOFF / 2, backoff(30, 0.5));
+    }
+
+    #[test]
+    fn test_retryable() {
+        let unsent = ApiError::Unsent(anyhow!("429 Too Many Requests"));
+        assert!(unsent.retryable(&Method::GET));
+        assert!(unsent.retryable(&Method::POST));
+
+        let transient = ApiError::Transient(anyhow!("502 Bad Gateway"));
+        assert!(transient.retryable(&Method::GET));
+        assert!(!transient.retryable(&Method::POST));
+
+        let permanent = ApiError::Permanent(anyhow!("403 Forbidden"));
+        assert!(!permanent.retryable(&Method::GET));
+    }
+
+    #[test]
+    fn test_account_action_form() {
+        let request = AccountActionRequest {
+            action_type: "suspend".to_string(),
+            report_id: None,
+            text: None,
+            send_email_notification: None,
+        };
+        // Without a warning, leave emailing the account's owner up to Mastodon.
+        assert_eq!(
+            "type=suspend",
+            serde_urlencoded::to_string(request.form()).unwrap()
+        );
+
+        let request = AccountActionRequest {
+            action_type: "none".to_string(),
+            report_id: Some(ReportId::new("5")),
+            text: Some("Stop that".to_string()),
+            send_email_notification: Some(false),
+        };
+        assert_eq!(
+            "type=none&send_email_notification=false&report_id=5&text=Stop+that",
+            serde_urlencoded::to_string(request.form()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_report_form() {
+        let request = ReportRequest {
+            account_id: AccountId::new("1"),
+            status_ids: vec![StatusId::new("2"), StatusId::new("3")],
+            comment: "Automod rules broken".to_string(),
+            category: "violation".to_string(),
+            rule_ids: vec![RuleId::new("4")],
+            forward: false,
+        };
+        assert_eq!(
+            "account_id=1&comment=Automod+rules+broken&category=violation&forward=false\
+            &status_ids%5B%5D=2&status_ids%5B%5D=3&rule_ids%5B%5D=4",
+            serde_urlencoded::to_string(request.form()).unwrap()
+        );
+    }
+}
@@ -20,8 +20,8 @@ pub async fn dump_as_mime(
     username: &str,
     id: &str,
 ) -> anyhow::Result<()> {
-    let registered = ensure_registered(config_dir, client, domain).await?;
-    let mastodon = ensure_mastodon(config_dir, registered, domain, username, false).await?;
+    let registered = ensure_registered(config_dir, client, domain, None, None).await?;
+    let mastodon = ensure_mastodon(config_dir, registered, domain, username, None, false).await?;
     let status = mastodon.get_status(&StatusId::new(id)).await?;
 
     let message_builder = status_to_mime(domain, &status);
@@ -1,42 +1,164 @@
-use crate::config::Rspamd;
+use crate::config::{Rspamd, Secret};
 use crate::interop::mime;
-use anyhow::{bail, Result};
+use crate::metrics::{RSPAMD_SCAN_FAILURES, RSPAMD_SCAN_SECONDS};
+use anyhow::{anyhow, bail, Result};
 use mastodon_async::prelude::Status;
+use reqwest::Client;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::process::Stdio;
+use std::time::Duration;
 use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
 
-/// Run a MIME message version of a post through rspamd, returning the action it recommends.
-pub async fn rspamd_scan(rspamd: &Rspamd, domain: &str, status: &Status) -> Result<String> {
-    let rspamc_output: RspamcSymbolsOutput =
-        rspamc_command(rspamd, "symbols", domain, status).await?;
-    Ok(rspamc_output.action)
+/// Talks to rspamd over its HTTP interface if it has a URL, or by running `rspamc` if not.
+/// Shares the program's HTTP client, so connections to rspamd are pooled.
+#[derive(Clone, Debug)]
+pub struct RspamdClient {
+    client: Client,
+    rspamd: Rspamd,
+    /// Resolved controller password.
+    password: Option<String>,
 }
 
-/// Tell rspamd to learn a MIME message version of a post as ham.
-pub async fn rspamd_learn_ham(rspamd: &Rspamd, domain: &str, status: &Status) -> Result<()> {
-    let _rspamc_output: RspamcLearnOutput =
-        rspamc_command(rspamd, "learn_ham", domain, status).await?;
-    Ok(())
+/// What the rspamd HTTP interface returns instead of a result.
+#[derive(Deserialize)]
+struct RspamdError {
+    error: String,
 }
 
-/// Tell rspamd to learn a MIME message version of a post as spam.
-pub async fn rspamd_learn_spam(rspamd: &Rspamd, domain: &str, status: &Status) -> Result<()> {
-    let _rspamc_output: RspamcLearnOutput =
-        rspamc_command(rspamd, "learn_spam", domain, status).await?;
-    Ok(())
+impl RspamdClient {
+    pub fn new(client: &Client, rspamd: &Rspamd) -> Result<Self> {
+        Ok(Self {
+            client: client.clone(),
+            rspamd: rspamd.clone(),
+            password: rspamd.password.as_ref().map(Secret::resolve).transpose()?,
+        })
+    }
+
+    pub fn settings(&self) -> &Rspamd {
+        &self.rspamd
+    }
+
+    /// Run a MIME message version of a post through rspamd, returning its score, symbols, and recommended action.
+    pub async fn scan(&self, domain: &str, status: &Status) -> Result<RspamdResult> {
+        let mut headers = vec![];
+        let mut args = vec![];
+        if let Some(settings_id) = self.rspamd.settings_id.as_ref() {
+            headers.push(("Settings-ID", settings_id.clone()));
+            args.push(format!("--settings-id={settings_id}"));
+        }
+        let timer = RSPAMD_SCAN_SECONDS.start_timer();
+        let result: Result<RspamdResult> = self
+            .run("checkv2", "symbols", &headers, &args, domain, status)
+            .await;
+        timer.observe_duration();
+        if result.is_err() {
+            RSPAMD_SCAN_FAILURES.inc();
+        }
+        result
+    }
+
+    /// Tell rspamd to learn a MIME message version of a post as ham.
+    pub async fn learn_ham(&self, domain: &str, status: &Status) -> Result<()> {
+        let _output: RspamcLearnOutput = self
+            .run("learnham", "learn_ham", &[], &[], domain, status)
+            .await?;
+        Ok(())
+    }
+
+    /// Tell rspamd to learn a MIME message version of a post as spam.
+    pub async fn learn_spam(&self, domain: &str, status: &Status) -> Result<()> {
+        let _output: RspamcLearnOutput = self
+            .run("learnspam", "learn_spam", &[], &[], domain, status)
+            .await?;
+        Ok(())
+    }
+
+    /// Add hashes of a MIME message version of a post to rspamd's fuzzy storage with the given flag.
+    pub async fn fuzzy_add(&self, domain: &str, status: &Status, flag: u32) -> Result<()> {
+        let _output: RspamcLearnOutput = self
+            .run(
+                "fuzzyadd",
+                "fuzzy_add",
+                &[("Flag", flag.to_string())],
+                &[format!("--flag={flag}")],
+                domain,
+                status,
+            )
+            .await?;
+        Ok(())
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.rspamd.timeout_secs)
+    }
+
+    /// Use the HTTP endpoint if rspamd has a URL, and the `rspamc` command otherwise.
+    async fn run<T: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        command_name: &str,
+        headers: &[(&str, String)],
+        args: &[String],
+        domain: &str,
+        status: &Status,
+    ) -> Result<T> {
+        match self.rspamd.url.as_ref() {
+            Some(url) => self.post(url, endpoint, headers, domain, status).await,
+            None => tokio::time::timeout(
+                self.timeout(),
+                rspamc_command(&self.rspamd, command_name, args, domain, status),
+            )
+            .await
+            .map_err(|_| anyhow!("rspamc {command_name} timed out"))?,
+        }
+    }
+
+    async fn post<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        endpoint: &str,
+        headers: &[(&str, String)],
+        domain: &str,
+        status: &Status,
+    ) -> Result<T> {
+        let message_bytes = mime::status_to_mime(domain, status).write_to_vec()?;
+        let mut request = self
+            .client
+            .post(format!("{url}/{endpoint}", url = url.trim_end_matches('/')))
+            .timeout(self.timeout())
+            .body(message_bytes);
+        if let Some(password) = self.password.as_ref() {
+            request = request.header("Password", password);
+        }
+        for (name, value) in headers {
+            request = request.header(*name, value);
+        }
+
+        let response = request.send().await?;
+        let status_code = response.status();
+        let body = response.bytes().await?;
+        if !status_code.is_success() {
+            let message = serde_json::from_slice::<RspamdError>(&body)
+                .map(|e| e.error)
+                .unwrap_or_else(|_| String::from_utf8_lossy(&body).to_string());
+            bail!("rspamd /{endpoint} failed with {status_code}: {message}");
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
 }
 
-async fn rspamc_command<'de, T: DeserializeOwned>(
+async fn rspamc_command<T: DeserializeOwned>(
     rspamd: &Rspamd,
     command_name: &str,
+    extra_args: &[String],
     domain: &str,
     status: &Status,
 ) -> Result<T> {
     let Some(rspamc_command) = rspamd.rspamc_command.as_ref() else {
-        bail!("rspamc_command is missing from global settings.");
+        bail!("Either url or rspamc_command must be set in the rspamd section of global settings.");
     };
     let Some((cmd, args)) = rspamc_command.split_first() else {
         bail!("rspamc_command in global settings is an empty list. It should be a non-empty list, or not present at all.");
@@ -48,17 +170,22 @@ async fn rspamc_command<'de, T: DeserializeOwned>(
     }
 
     command.arg("--json");
+    for arg in extra_args {
+        command.arg(arg);
+    }
     command.arg(command_name);
     command.stdin(Stdio::piped());
     command.stdout(Stdio::piped());
+    // Don't leave it running if we time out.
+    command.kill_on_drop(true);
     let mut process = command.spawn()?;
 
     let Some(mut stdin) = process.stdin.take() else {
         bail!("Couldn't get rspamc stdin");
     };
-    let message_builder = mime::status_to_mime(domain, &status);
+    let message_builder = mime::status_to_mime(domain, status);
     let message_bytes = message_builder.write_to_vec()?;
-    stdin.write(message_bytes.as_slice()).await?;
+    stdin.write_all(message_bytes.as_slice()).await?;
     drop(stdin);
 
     let output = process.wait_with_output().await?;
@@ -79,15 +206,123 @@ async fn rspamc_command<'de, T: DeserializeOwned>(
     Ok(deserialized_output)
 }
 
-/// JSON output of `rspamc` or synonym `rspamc symbols`.
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-pub struct RspamcSymbolsOutput {
+/// JSON output of `rspamc` or synonym `rspamc symbols`, or of the `/checkv2` endpoint.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RspamdResult {
     /// Action recommended. The most common are `no action` and `reject`, but there are others:
     /// https://rspamd.com/doc/faq.html#what-are-rspamd-actions
     pub action: String,
+    /// Total score of all symbols.
+    #[serde(default)]
+    pub score: f64,
+    /// Symbols that fired, by name.
+    #[serde(default)]
+    pub symbols: BTreeMap<String, RspamdSymbol>,
+}
+
+/// One symbol that fired for a message.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RspamdSymbol {
+    /// This symbol's contribution to the total score. May be negative.
+    #[serde(default)]
+    pub score: f64,
+    /// Details such as the URL or domain that triggered it.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
 }
 
-/// JSON output of `rspamc learn_ham` or synonym `rspamc learn_spam`.
+/// JSON output of `rspamc learn_ham`, `rspamc learn_spam`, or `rspamc fuzzy_add`, or their endpoints.
 /// No significant fields yet.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct RspamcLearnOutput {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::example::placeholder_status;
+    use crate::test_util::{block_on, serve_stub};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use serde_json::json;
+
+    /// Stand-in for rspamd's controller worker. Checks the password and other headers,
+    /// and refuses to learn anything as ham.
+    async fn serve_rspamd() -> String {
+        let check_password = |headers: &HeaderMap| {
+            headers.get("Password").and_then(|v| v.to_str().ok()) == Some("q1")
+        };
+        let app = Router::new()
+            .route(
+                "/checkv2",
+                post(move |headers: HeaderMap, body: String| async move {
+                    assert!(check_password(&headers));
+                    assert_eq!("m1000", headers["Settings-ID"]);
+                    assert!(body.contains("Message-ID"));
+                    Json(json!({
+                        "action": "add header",
+                        "score": 7.5,
+                        "required_score": 15.0,
+                        "symbols": {
+                            "URIBL_BLACK": {
+                                "name": "URIBL_BLACK",
+                                "score": 7.5,
+                                "options": ["casino.example"],
+                            },
+                        },
+                    }))
+                }),
+            )
+            .route(
+                "/learnham",
+                post(|| async {
+                    (
+                        StatusCode::NOT_FOUND,
+                        Json(json!({"error": "No statfiles found"})),
+                    )
+                }),
+            )
+            .route(
+                "/fuzzyadd",
+                post(move |headers: HeaderMap| async move {
+                    assert!(check_password(&headers));
+                    assert_eq!("3", headers["Flag"]);
+                    Json(json!({"success": true}))
+                }),
+            );
+        format!("{base}/", base = serve_stub(app).await)
+    }
+
+    #[test]
+    fn test_http_client() {
+        block_on(async {
+            let rspamd = Rspamd {
+                rspamc_command: None,
+                url: Some(serve_rspamd().await),
+                password: Some("q1".to_string().into()),
+                settings_id: Some("m1000".to_string()),
+                timeout_secs: Rspamd::default_timeout_secs(),
+                learn_from_reports: true,
+                fuzzy_flag: Some(3),
+            };
+            let rspamd = RspamdClient::new(&Client::new(), &rspamd).unwrap();
+            let status: Status = serde_json::from_value(placeholder_status()).unwrap();
+
+            let result = rspamd.scan("example.test", &status).await.unwrap();
+            assert_eq!("add header", result.action);
+            assert_eq!(7.5, result.score);
+            assert_eq!(
+                vec!["casino.example".to_string()],
+                result.symbols["URIBL_BLACK"].options
+            );
+            rspamd.fuzzy_add("example.test", &status, 3).await.unwrap();
+            let error = rspamd
+                .learn_ham("example.test", &status)
+                .await
+                .unwrap_err()
+                .to_string();
+            assert!(error.contains("No statfiles found"), "{error}");
+        });
+    }
+}
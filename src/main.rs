@@ -1,35 +1,80 @@
+mod audit;
+mod backfill;
+mod check;
+mod checkpoint;
 mod config;
+mod dead_letter;
+mod dispatch;
+mod domain_policy;
 mod event;
+mod example;
+mod exempt;
+mod explain;
+mod health;
 mod interop;
+mod metrics;
 mod pattern;
+mod polling;
+mod recorder;
+mod replay_guard;
 mod setup;
+mod streaming;
+mod template;
+mod test_rules;
+#[cfg(test)]
+mod test_util;
 mod webhook;
 mod websub;
 
-use crate::config::{Config, Report, Restrict, Rule, Settings, USER_AGENT};
+use crate::audit::{audit, parse_query_date, AuditEntry, AuditLog, AuditQuery};
+use crate::backfill::{backfill, BackfillOptions, BackfillSource};
+use crate::check::{check_before_loading, check_config, ConfigFile};
+use crate::config::{
+    Block, Config, Polling, Registration, Report, ReportTemplate, Restrict, Rule, Settings,
+    Streaming, Warn, Webhook, USER_AGENT,
+};
+use crate::dead_letter::{retry_failed, DeadLetterQueue};
+use crate::dispatch::dispatch_events;
+use crate::domain_policy::DomainPolicyTracker;
+use crate::event::account::handle_account;
 use crate::event::report::handle_report;
 use crate::event::status::handle_status;
+use crate::example::check_examples;
+use crate::exempt::Exemptions;
+use crate::explain::{explain, ExplainTarget};
+use crate::health::{serve_healthcheck, serve_livez, serve_readyz, Health};
+use crate::interop::api::ApiClient;
 use crate::interop::mime::dump_as_mime;
+use crate::interop::rspamd::RspamdClient;
+use crate::metrics::{serve_metrics, EVENTS, EVENTS_DROPPED, EVENTS_REFUSED, WEBHOOK_REQUESTS};
 use crate::pattern::{CompileMatcher, RuleMatcher};
+use crate::polling::{poll_events, PollTarget};
+use crate::recorder::{replay_recordings, Recorder, ReplayOptions, ReplayTarget};
+use crate::replay_guard::{Admission, ReplayGuard};
 use crate::setup::{
     ensure_config, ensure_mastodon, ensure_registered, ensure_settings, ensure_webhook, setup,
+    SetupArgs,
 };
+use crate::streaming::{stream_events, StreamSource};
+use crate::test_rules::test_rules;
 use crate::websub::{XHubSignature, XHubSignatureAlgorithm};
-use anyhow::{anyhow, bail, Error, Result};
+use anyhow::{bail, Error, Result};
 use axum::body::Bytes;
 use axum::extract::{Query, TypedHeader};
-use axum::http::StatusCode;
+use axum::http::{HeaderMap, StatusCode};
 use axum::routing::{get, post};
 use axum::{Extension, Router};
 use clap::{Parser, Subcommand};
 use futures::stream::{FuturesUnordered, StreamExt};
+use mastodon_async::registration::Registered;
 use mastodon_async::Mastodon;
 use reqwest::Client;
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::{broadcast, Mutex};
 use tracing::{error, info};
@@ -47,7 +92,8 @@ async fn main() -> Result<()> {
         Command::Setup {
             ref domain,
             ref username,
-        } => setup(config_dir, client, domain, username).await,
+            ref secrets,
+        } => setup(config_dir, client, domain, username, secrets).await,
         Command::Serve => serve(config_dir, client).await,
         Command::Healthcheck => healthcheck(config_dir, client).await,
         Command::DumpAsMime {
@@ -55,19 +101,134 @@ async fn main() -> Result<()> {
             ref username,
             ref id,
         } => dump_as_mime(config_dir, client, domain, username, id).await,
+        Command::Audit {
+            ref domain,
+            ref username,
+            ref account,
+            ref rule,
+            ref since,
+            ref until,
+        } => audit(
+            config_dir,
+            domain,
+            username,
+            &AuditQuery {
+                account: account.clone(),
+                rule: rule.clone(),
+                since: since.as_deref().map(parse_query_date).transpose()?,
+                until: until.as_deref().map(parse_query_date).transpose()?,
+            },
+        ),
+        Command::RetryFailed {
+            ref domain,
+            ref username,
+        } => retry_failed(config_dir, client, domain, username).await,
+        Command::TestRules {
+            ref config,
+            ref corpus,
+        } => test_rules(config, corpus),
+        Command::CheckConfig { ref config } => check_config(config_dir, config.as_deref()),
+        Command::Schema { ref out_dir } => config::write_schemas(out_dir),
+        Command::Explain {
+            ref domain,
+            ref username,
+            ref status,
+            ref account,
+            rspamd,
+        } => {
+            let target = match (status, account) {
+                (Some(id), _) => ExplainTarget::Status { id, rspamd },
+                (None, Some(acct)) => ExplainTarget::Account {
+                    acct: acct.trim_start_matches('@'),
+                },
+                (None, None) => bail!("Either --status or --account is required"),
+            };
+            explain(config_dir, client, domain, username, target).await
+        }
+        Command::Backfill {
+            ref domain,
+            ref username,
+            shadow,
+            ref since,
+            ref until,
+            limit,
+            pause_ms,
+            restart,
+            ref source,
+        } => {
+            backfill(
+                config_dir,
+                client,
+                domain,
+                username,
+                source,
+                &BackfillOptions {
+                    shadow,
+                    since: since.as_deref().map(parse_query_date).transpose()?,
+                    until: until.as_deref().map(parse_query_date).transpose()?,
+                    limit,
+                    pause: Duration::from_millis(pause_ms),
+                    restart,
+                },
+            )
+            .await
+        }
+        Command::Replay {
+            ref dir,
+            ref event,
+            ref domain,
+            speed,
+            ref url,
+            ref username,
+            shadow,
+        } => {
+            let target = match username {
+                Some(username) => ReplayTarget::InProcess {
+                    username: username.clone(),
+                    shadow,
+                },
+                None => ReplayTarget::Server { url: url.clone() },
+            };
+            replay_recordings(
+                config_dir,
+                client,
+                dir,
+                &ReplayOptions {
+                    events: event.clone(),
+                    domain: domain.clone(),
+                    speed,
+                    target,
+                },
+            )
+            .await
+        }
     };
 }
 
 async fn serve(config_dir: &PathBuf, client: &Client) -> Result<()> {
-    let domain_handler_map = init_domain_handlers(config_dir, &client).await?;
+    check_before_loading(ConfigFile::Settings, &Settings::file_path(config_dir))?;
+    let settings = ensure_settings(config_dir)?;
+    let health = Health::default();
+    if settings.rspamd.is_some() {
+        health.rspamd_configured();
+    }
+
+    let domain_handler_map = init_domain_handlers(config_dir, &client, &health).await?;
+    let recorder = settings
+        .record
+        .as_ref()
+        .map(|record| Recorder::new(&config_dir.join(&record.dir)));
 
     let make_service = Router::new()
         .route("/healthcheck", get(serve_healthcheck))
+        .route("/livez", get(serve_livez))
+        .route("/readyz", get(serve_readyz))
         .route("/webhook", post(receive_webhook))
         .layer(Extension(Arc::new(Mutex::new(domain_handler_map))))
+        .layer(Extension(health))
+        .layer(Extension(recorder))
         .into_make_service();
 
-    let settings = ensure_settings(config_dir)?;
     let server_futures = FuturesUnordered::new();
     for addr_str in settings.listen {
         let addr = SocketAddr::from_str(&addr_str)?;
@@ -75,6 +236,15 @@ async fn serve(config_dir: &PathBuf, client: &Client) -> Result<()> {
         let server_future = axum::Server::bind(&addr).serve(make_service.clone());
         server_futures.push(server_future);
     }
+    let make_metrics_service = Router::new()
+        .route("/metrics", get(serve_metrics))
+        .into_make_service();
+    for addr_str in settings.metrics_listen {
+        let addr = SocketAddr::from_str(&addr_str)?;
+        info!("Serving metrics on {}", addr);
+        let server_future = axum::Server::bind(&addr).serve(make_metrics_service.clone());
+        server_futures.push(server_future);
+    }
     for server_result in server_futures.collect::<Vec<_>>().await {
         server_result?;
     }
@@ -85,11 +255,9 @@ async fn serve(config_dir: &PathBuf, client: &Client) -> Result<()> {
 /// Healthcheck command suitable for Docker. Calls our healthcheck endpoint on the first listen address.
 async fn healthcheck(config_dir: &PathBuf, client: &Client) -> Result<()> {
     let settings = ensure_settings(config_dir)?;
-    let addr_str = settings.listen.first().ok_or(anyhow!(
-        "Couldn't find any listen addresses in global settings"
-    ))?;
+    let addr = settings.local_addr()?;
     let status = client
-        .get(format!("http://{addr_str}/healthcheck"))
+        .get(format!("http://{addr}/healthcheck"))
         .send()
         .await?
         .status();
@@ -111,7 +279,7 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Command {
-    /// Run interactive setup.
+    /// Set up a domain user. Prompts for anything missing unless it can get it from flags or environment variables.
     Setup {
         /// Domain name of the instance to which you're connecting.
         #[arg(short, long)]
@@ -119,6 +287,8 @@ enum Command {
         /// Username of the bot account you're using, without the leading @ or domain.
         #[arg(short, long)]
         username: String,
+        #[command(flatten)]
+        secrets: SetupArgs,
     },
     /// Run the server.
     Serve,
@@ -136,84 +306,357 @@ enum Command {
         #[arg(short, long)]
         id: String,
     },
-}
-
-/// Process a healthcheck request.
-/// Currently, we have no real state to check, so we always return a 2xx response.
-async fn serve_healthcheck() -> StatusCode {
-    StatusCode::NO_CONTENT
+    /// Print audit log entries for a domain user as JSONL, oldest first.
+    Audit {
+        /// Domain name of the instance to which you're connecting.
+        #[arg(short, long)]
+        domain: String,
+        /// Username of the bot account you're using, without the leading @ or domain.
+        #[arg(short, long)]
+        username: String,
+        /// Only show entries for this account ID or handle.
+        #[arg(short, long)]
+        account: Option<String>,
+        /// Only show entries where this rule matched.
+        #[arg(short, long)]
+        rule: Option<String>,
+        /// Only show entries at or after this date (YYYY-MM-DD) or RFC 3339 timestamp.
+        #[arg(long)]
+        since: Option<String>,
+        /// Only show entries before this date (YYYY-MM-DD) or RFC 3339 timestamp.
+        #[arg(long)]
+        until: Option<String>,
+    },
+    /// Replay moderation actions that failed after retrying, such as during an instance outage.
+    RetryFailed {
+        /// Domain name of the instance to which you're connecting.
+        #[arg(short, long)]
+        domain: String,
+        /// Username of the bot account you're using, without the leading @ or domain.
+        #[arg(short, long)]
+        username: String,
+    },
+    /// Run a config's rules against a corpus of saved statuses, accounts, or webhook payloads,
+    /// without any network access or credentials.
+    TestRules {
+        /// Path to the `config.yaml` to test.
+        #[arg(long)]
+        config: PathBuf,
+        /// Path to a JSONL corpus file. Each line can have an `expected_rules` list of rule names
+        /// for precision and recall, and an `rspamd_action` to stand in for an rspamd scan.
+        #[arg(long)]
+        corpus: PathBuf,
+    },
+    /// Check config files for mistakes, with the line and column of each.
+    /// Checks global settings and every domain user's config unless given a single config file.
+    CheckConfig {
+        /// Path to a single `config.yaml` to check.
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+    /// Write JSON Schemas for each kind of config file, for editor autocompletion and validation.
+    Schema {
+        /// Directory to write `{name}.schema.json` files to.
+        #[arg(long)]
+        out_dir: PathBuf,
+    },
+    /// Show why a domain user's rules match or don't match a status or account,
+    /// and what m1000 would do about it. Doesn't take any actions.
+    Explain {
+        /// Domain name of the instance to which you're connecting.
+        #[arg(short, long)]
+        domain: String,
+        /// Username of the bot account you're using, without the leading @ or domain.
+        #[arg(short, long)]
+        username: String,
+        /// ID of the status to explain.
+        #[arg(long, conflicts_with = "account", required_unless_present = "account")]
+        status: Option<String>,
+        /// Handle of the account to explain: `username` for local accounts, or `username@domain`.
+        #[arg(long)]
+        account: Option<String>,
+        /// Scan the status with rspamd, if it's configured, for `rspamd` patterns.
+        #[arg(long)]
+        rspamd: bool,
+    },
+    /// Run a domain user's rules over statuses or accounts from before the rules existed.
+    /// Resumes where the last run for the same source left off.
+    Backfill {
+        /// Domain name of the instance to which you're connecting.
+        #[arg(short, long)]
+        domain: String,
+        /// Username of the bot account you're using, without the leading @ or domain.
+        #[arg(short, long)]
+        username: String,
+        /// Log and audit what rules would have done, without doing it.
+        #[arg(long)]
+        shadow: bool,
+        /// Stop at items created before this date (YYYY-MM-DD) or RFC 3339 timestamp.
+        #[arg(long)]
+        since: Option<String>,
+        /// Skip items created at or after this date (YYYY-MM-DD) or RFC 3339 timestamp.
+        #[arg(long)]
+        until: Option<String>,
+        /// Stop after this many items. Run again to continue.
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Milliseconds to wait between pages, to leave API capacity for the running server.
+        #[arg(long, default_value_t = 1000)]
+        pause_ms: u64,
+        /// Start over from the newest item instead of resuming.
+        #[arg(long)]
+        restart: bool,
+        #[command(subcommand)]
+        source: BackfillSource,
+    },
+    /// Replay recorded webhook requests, either to a running server or in this process.
+    Replay {
+        /// Directory of recordings, as set by `record.dir` in global settings.
+        #[arg(long)]
+        dir: PathBuf,
+        /// Only replay events of this type, such as `status.created`. Can be repeated.
+        #[arg(long)]
+        event: Vec<String>,
+        /// Only replay events for this domain. Required when replaying in this process.
+        #[arg(short, long)]
+        domain: Option<String>,
+        /// Playback speed relative to the recording. 0 replays everything without pauses.
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+        /// Webhook URL of a running server. Defaults to the first listen address in global settings.
+        #[arg(long, conflicts_with = "username")]
+        url: Option<String>,
+        /// Handle events in this process as this domain user, instead of posting them to a server.
+        #[arg(short, long, requires = "domain")]
+        username: Option<String>,
+        /// Log and audit what rules would have done, without doing it. Only when replaying in this process.
+        #[arg(long, requires = "username")]
+        shadow: bool,
+    },
 }
 
 /// Arbitrary.
 const EVENT_CHANNEL_SIZE: usize = 256;
 
+/// Events handled at once per domain user, if not configured.
+/// Most of the time spent handling an event is waiting for rspamd or the Mastodon API.
+const DEFAULT_CONCURRENCY: usize = 4;
+
 /// Initialize domain handlers:
 /// - create per-domain webhook event channels
 /// - ensure that this app is registered with each domain
 /// - ensure that this app's user credentials are valid for each domain user
 /// - compile rule patterns to matchers
-/// - spawn a task to handle each domain user's webhook events
+/// - spawn a task to handle each domain user's events, and another to notice if it stops
+/// - spawn tasks to read from the streaming API or poll it for domain users who use them
 async fn init_domain_handlers(
     config_dir: &PathBuf,
     client: &Client,
+    health: &Health,
 ) -> Result<HashMap<String, DomainHandler>> {
     let settings = ensure_settings(config_dir)?;
     let mut domain_handler_map = HashMap::<String, DomainHandler>::new();
     let domains_and_usernames = config::configured_domains_and_usernames(config_dir)?;
     for (domain, usernames) in domains_and_usernames {
-        let webhook = ensure_webhook(config_dir, &domain, false)?;
-        let webhook_secret = webhook.secret.bytes().collect();
-        let (event_sender, _) = broadcast::channel::<webhook::Event>(EVENT_CHANNEL_SIZE);
-        info!(
-            "Webhook ready for {webhook_domain}",
-            webhook_domain = webhook.domain
-        );
-
-        let registered = ensure_registered(config_dir, client, &domain).await?;
-
+        let registered = ensure_registered(config_dir, client, &domain, None, None).await?;
+        let mut handlers = vec![];
         for username in usernames {
-            let mastodon =
-                ensure_mastodon(config_dir, registered.clone(), &domain, &username, false).await?;
-
-            let account = mastodon.verify_credentials().await?;
+            handlers.push(
+                init_user_handler(
+                    config_dir,
+                    client,
+                    &settings,
+                    registered.clone(),
+                    &domain,
+                    &username,
+                    health,
+                )
+                .await?,
+            );
+        }
 
+        // A domain doesn't need a webhook if all of its users get events from the streaming API or polling.
+        let webhook_optional = handlers
+            .iter()
+            .all(|handler| handler.config.streaming.is_some() || handler.config.polling.is_some());
+        let webhook = if webhook_optional && !Webhook::file_path(config_dir, &domain).exists() {
+            info!("No webhook for {domain}: using only the streaming API or polling");
+            None
+        } else {
+            let webhook = ensure_webhook(config_dir, &domain, None, false)?;
             info!(
-                "Authenticated with {username}@{domain}",
-                username = account.username,
-                domain = domain,
+                "Webhook ready for {webhook_domain}",
+                webhook_domain = webhook.domain
             );
+            Some(webhook)
+        };
+        let (event_sender, _) = broadcast::channel::<webhook::Event>(EVENT_CHANNEL_SIZE);
 
-            let config =
-                CompiledConfig::try_from(&ensure_config(config_dir, &domain, &username).await?)?;
-
-            tokio::spawn(handle_events(
-                event_sender.subscribe(),
-                settings.clone(),
-                config,
-                mastodon,
-            ));
+        for handler in handlers {
+            let username = handler.config.username.clone();
+            let streaming = handler.config.streaming.clone();
+            let polling = handler.config.polling.clone();
+            let health_key = format!("{username}@{domain}");
+            // Before starting streaming or polling, which report their own health.
+            health.handler_started(&health_key, streaming.is_some(), polling.is_some());
+            let event_receiver =
+                if streaming.is_none() && polling.is_none() && handler.config.webhooks {
+                    event_sender.subscribe()
+                } else {
+                    // This user gets their own channel, fed by the streaming API or polling, and maybe webhooks too.
+                    let (user_event_sender, user_event_receiver) =
+                        broadcast::channel::<webhook::Event>(EVENT_CHANNEL_SIZE);
+                    if webhook.is_some() && handler.config.webhooks {
+                        tokio::spawn(forward_events(
+                            event_sender.subscribe(),
+                            user_event_sender.clone(),
+                        ));
+                    }
+                    if let Some(streaming) = streaming {
+                        tokio::spawn(stream_events(
+                            StreamSource {
+                                api: handler.api.clone(),
+                                token: handler.mastodon.data.token.to_string(),
+                                domain: domain.clone(),
+                                username: username.clone(),
+                                streaming,
+                                health: health.clone(),
+                            },
+                            user_event_sender.clone(),
+                        ));
+                    }
+                    if let Some(polling) = polling {
+                        let target = PollTarget {
+                            api: handler.api.clone(),
+                            config_dir: config_dir.clone(),
+                            domain: domain.clone(),
+                            username: username.clone(),
+                            polling,
+                            health: health.clone(),
+                        };
+                        let health_key = health_key.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = poll_events(target, user_event_sender).await {
+                                error!("{health_key}: Polling stopped: {e}");
+                            }
+                        });
+                    }
+                    user_event_receiver
+                };
+
+            let join_handle = tokio::spawn(handle_events(event_receiver, handler));
+            let health = health.clone();
+            tokio::spawn(async move {
+                let reason = match join_handle.await {
+                    Ok(Ok(())) => "exited".to_string(),
+                    Ok(Err(e)) => format!("failed: {e}"),
+                    Err(e) => format!("panicked: {e}"),
+                };
+                error!("{health_key}: Event handler stopped: {reason}");
+                health.handler_stopped(&health_key);
+            });
         }
 
-        domain_handler_map.insert(
-            domain.clone(),
-            DomainHandler {
-                domain,
-                webhook_secret,
-                event_sender,
-            },
-        );
+        if let Some(webhook) = webhook {
+            let webhook_secret = webhook.secret.resolve()?.into_bytes();
+            let replay_guard =
+                ReplayGuard::load(config_dir, &domain, settings.replay_protection.as_ref())?;
+            domain_handler_map.insert(
+                domain.clone(),
+                DomainHandler {
+                    domain,
+                    webhook_secret,
+                    event_sender,
+                    replay_guard,
+                },
+            );
+        }
     }
 
     Ok(domain_handler_map)
 }
 
-/// Holds the webhook secret and event channel sender for one domain.
+/// Set up everything needed to handle events for one domain user:
+/// check their credentials, and load and compile their config.
+pub async fn init_user_handler(
+    config_dir: &Path,
+    client: &Client,
+    settings: &Settings,
+    registered: Registered,
+    domain: &str,
+    username: &str,
+    health: &Health,
+) -> Result<UserHandler> {
+    let mastodon = ensure_mastodon(config_dir, registered, domain, username, None, false).await?;
+
+    let account = mastodon.verify_credentials().await?;
+
+    info!(
+        "Authenticated with {username}@{domain}",
+        username = account.username,
+        domain = domain,
+    );
+
+    check_before_loading(
+        ConfigFile::Config,
+        &Config::file_path(config_dir, domain, username),
+    )?;
+    let config = ensure_config(config_dir, domain, username).await?;
+    let exemptions = Exemptions::compile(domain, settings.exempt.as_ref(), config.exempt.as_ref())?;
+    let domain_policy = config
+        .domain_policy
+        .as_ref()
+        .map(DomainPolicyTracker::compile)
+        .transpose()?;
+    let config = CompiledConfig::try_from(&config)?;
+    let api = ApiClient::new(client, &mastodon)
+        .with_dead_letter_queue(DeadLetterQueue::new(config_dir, domain, username))
+        .with_health(health, &format!("{username}@{domain}"));
+    let audit = AuditLog::new(config_dir, domain, username, settings.audit.as_ref());
+    let rspamd = settings
+        .rspamd
+        .as_ref()
+        .map(|rspamd| RspamdClient::new(client, rspamd))
+        .transpose()?;
+
+    Ok(UserHandler {
+        settings: settings.clone(),
+        config,
+        mastodon,
+        api,
+        rspamd,
+        exemptions,
+        domain_policy,
+        audit,
+        health: health.clone(),
+        shadow: false,
+    })
+}
+
+/// Holds the webhook secret, replay guard, and event channel sender for one domain.
 /// The sender may fan out to multiple users under that domain.
 #[derive(Clone, Debug)]
 struct DomainHandler {
     domain: String,
     webhook_secret: Vec<u8>,
     event_sender: broadcast::Sender<webhook::Event>,
+    replay_guard: ReplayGuard,
+}
+
+/// Holds everything needed to handle events for one user under a domain.
+#[derive(Clone, Debug)]
+pub struct UserHandler {
+    pub settings: Settings,
+    pub config: CompiledConfig,
+    pub mastodon: Mastodon,
+    pub api: ApiClient,
+    pub rspamd: Option<RspamdClient>,
+    pub exemptions: Exemptions,
+    pub domain_policy: Option<DomainPolicyTracker>,
+    pub audit: AuditLog,
+    pub health: Health,
+    /// Log and audit what rules would have done, without doing it.
+    pub shadow: bool,
 }
 
 /// Same as [`Config`] but with compiled rules.
@@ -221,6 +664,11 @@ struct DomainHandler {
 pub struct CompiledConfig {
     pub domain: String,
     pub username: String,
+    pub report_template: Option<ReportTemplate>,
+    pub concurrency: usize,
+    pub webhooks: bool,
+    pub streaming: Option<Streaming>,
+    pub polling: Option<Polling>,
     pub rules: Vec<CompiledRule>,
 }
 
@@ -232,9 +680,21 @@ impl TryFrom<&Config> for CompiledConfig {
         for rule in config.rules.iter() {
             rules.push(CompiledRule::try_from(rule)?);
         }
+        if let Some(report_template) = config.report_template.as_ref() {
+            report_template.validate("reports")?;
+        }
+        let concurrency = config.concurrency.unwrap_or(DEFAULT_CONCURRENCY);
+        if concurrency == 0 {
+            bail!("concurrency must be at least 1");
+        }
         Ok(Self {
             domain: config.domain.clone(),
             username: config.username.clone(),
+            report_template: config.report_template.clone(),
+            concurrency,
+            webhooks: config.webhooks,
+            streaming: config.streaming.clone(),
+            polling: config.polling.clone(),
             rules,
         })
     }
@@ -246,6 +706,9 @@ pub struct CompiledRule {
     pub name: String,
     pub report: Option<Report>,
     pub restrict: Option<Restrict>,
+    pub registration: Option<Registration>,
+    pub block: Option<Block>,
+    pub warn: Option<Warn>,
     pub matchers: Vec<RuleMatcher>,
 }
 
@@ -257,10 +720,22 @@ impl TryFrom<&Rule> for CompiledRule {
         for pattern in rule.patterns.iter() {
             matchers.push(pattern.compile()?);
         }
+        if let Some(warn) = rule.warn.as_ref() {
+            template::validate(&warn.text, &format!("warning in rule {}", rule.name))?;
+        }
+        if let Some(report_template) = rule.report.as_ref().and_then(|r| r.template.as_ref()) {
+            report_template.validate(&format!("report in rule {}", rule.name))?;
+        }
+        if let Some(examples) = rule.examples.as_ref() {
+            check_examples(&rule.name, examples, &matchers)?;
+        }
         Ok(Self {
             name: rule.name.clone(),
             report: rule.report.clone(),
             restrict: rule.restrict.clone(),
+            registration: rule.registration,
+            block: rule.block.clone(),
+            warn: rule.warn.clone(),
             matchers,
         })
     }
@@ -269,20 +744,47 @@ impl TryFrom<&Rule> for CompiledRule {
 /// Receive a webhook event, figure out which domain it's for, and route it to the right domain handler.
 async fn receive_webhook(
     Extension(domain_handler_map): Extension<Arc<Mutex<HashMap<String, DomainHandler>>>>,
+    Extension(recorder): Extension<Option<Recorder>>,
     TypedHeader(x_hub_signature): TypedHeader<XHubSignature>,
     Query(params): Query<webhook::Params>,
+    headers: HeaderMap,
     body: Bytes,
 ) -> StatusCode {
+    let (status, signature) = route_webhook(
+        domain_handler_map,
+        recorder,
+        x_hub_signature,
+        params,
+        headers,
+        body,
+    )
+    .await;
+    WEBHOOK_REQUESTS
+        .with_label_values(&[status.as_str(), signature])
+        .inc();
+    status
+}
+
+/// Does the work of [`receive_webhook`].
+/// Also returns the outcome of checking the signature, for metrics.
+async fn route_webhook(
+    domain_handler_map: Arc<Mutex<HashMap<String, DomainHandler>>>,
+    recorder: Option<Recorder>,
+    x_hub_signature: XHubSignature,
+    params: webhook::Params,
+    headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, &'static str) {
     if x_hub_signature.algorithm != XHubSignatureAlgorithm::Sha256 {
         // Mastodon supports exactly one signature algorithm.
         error!(
             "Unsupported webhook signature algorithm: {algorithm}",
             algorithm = x_hub_signature.algorithm
         );
-        return StatusCode::UNAUTHORIZED;
+        return (StatusCode::UNAUTHORIZED, "unsupported_algorithm");
     }
 
-    let (domain, event_sender) = {
+    let (domain, event_sender, replay_guard) = {
         let domain_handler_map = domain_handler_map.lock().await;
         let domain_handlers: Vec<&DomainHandler>;
         if let Some(domain) = params.domain {
@@ -299,71 +801,178 @@ async fn receive_webhook(
             .collect::<Vec<_>>();
         if matching_domain_handlers.len() > 1 {
             error!("Multiple domains could have signed an incoming webhook event");
-            return StatusCode::UNAUTHORIZED;
+            return (StatusCode::UNAUTHORIZED, "ambiguous");
         }
         let Some(domain_handler) = matching_domain_handlers.first() else {
             error!("Could not find a domain that could have signed an incoming webhook event");
-            return StatusCode::UNAUTHORIZED;
+            return (StatusCode::UNAUTHORIZED, "invalid");
         };
         (
             domain_handler.domain.clone(),
             domain_handler.event_sender.clone(),
+            domain_handler.replay_guard.clone(),
         )
     };
 
+    // Only record requests that are really from one of our domains.
+    if let Some(recorder) = recorder {
+        recorder.record(&domain, &headers, &body).await;
+    }
+
     match serde_json::from_slice::<webhook::Event>(&body) {
         Err(e) => {
             error!(
                 "{domain}: Decoding error {e}: {body}",
                 body = String::from_utf8_lossy(&body)
             );
-            StatusCode::UNPROCESSABLE_ENTITY
+            (StatusCode::UNPROCESSABLE_ENTITY, "valid")
         }
         Ok(event) => {
-            if let Err(e) = event_sender.send(event) {
-                error!("{domain}: Channel error: {e}");
-                StatusCode::INTERNAL_SERVER_ERROR
-            } else {
-                StatusCode::ACCEPTED
+            EVENTS.with_label_values(&[event.name(), &domain]).inc();
+            let admission = replay_guard.admit(&event).await;
+            if admission != Admission::Fresh {
+                EVENTS_REFUSED
+                    .with_label_values(&[admission.name(), &domain])
+                    .inc();
+            }
+            match admission {
+                Admission::Fresh => {}
+                Admission::Stale => {
+                    // Either a replay of a captured request, or a badly wrong clock.
+                    error!(
+                        "{domain}: Refusing stale {name} event for {object_id} created at {created_at}",
+                        name = event.name(),
+                        object_id = event.object_id().unwrap_or_default(),
+                        created_at = event.created_at().map(|t| t.to_string()).unwrap_or_default(),
+                    );
+                    return (StatusCode::FORBIDDEN, "valid");
+                }
+                Admission::Duplicate => {
+                    // Acknowledge it so that Mastodon stops retrying.
+                    info!(
+                        "{domain}: Ignoring duplicate {name} event for {object_id}",
+                        name = event.name(),
+                        object_id = event.object_id().unwrap_or_default(),
+                    );
+                    return (StatusCode::OK, "valid");
+                }
+            }
+            // Only remember the event once it's on its way to being handled, so that Mastodon's retry of it isn't ignored.
+            match event_sender.send(event.clone()) {
+                Ok(_) => {
+                    replay_guard.accepted(&event).await;
+                    (StatusCode::ACCEPTED, "valid")
+                }
+                Err(e) => {
+                    error!("{domain}: Channel error: {e}");
+                    EVENTS_DROPPED.with_label_values(&[&domain]).inc();
+                    replay_guard.forget(&event).await;
+                    (StatusCode::INTERNAL_SERVER_ERROR, "valid")
+                }
             }
         }
     }
 }
 
-/// Handle webhook events for a given domain user.
-async fn handle_events(
+/// Pass a domain's webhook events on to a user who also gets events from the streaming API or polling.
+async fn forward_events(
     mut event_receiver: broadcast::Receiver<webhook::Event>,
-    settings: Settings,
-    config: CompiledConfig,
-    mastodon: Mastodon,
-) -> Result<()> {
-    let domain = &config.domain;
-    let username = &config.username;
+    event_sender: broadcast::Sender<webhook::Event>,
+) {
     loop {
         match event_receiver.recv().await {
-            Ok(event) => match event {
-                webhook::Event::StatusCreated { status, .. }
-                | webhook::Event::StatusUpdated { status, .. } => {
-                    if let Err(e) = handle_status(&settings, &config, &mastodon, &status).await {
-                        error!("{username}@{domain}: Error handling status: {e}");
-                    }
-                }
-                webhook::Event::ReportCreated { report, .. }
-                | webhook::Event::ReportUpdated { report, .. } => {
-                    if let Err(e) = handle_report(&settings, &domain, &report).await {
-                        error!("{username}@{domain}: Error handling status: {e}");
-                    }
-                }
-                _ => {
-                    info!("{username}@{domain}: Unimplemented event type: {event:#?}");
-                }
-            },
+            Ok(event) => {
+                // The only error is that the user's handler has stopped, which it reports itself.
+                let _ = event_sender.send(event);
+            }
             Err(RecvError::Lagged(skipped)) => {
-                error!("{username}@{domain}: Channel error: fell behind event stream. Skipping {skipped} events to catch up.");
+                error!("Channel error: fell behind webhook events. Skipping {skipped} events to catch up.");
             }
-            Err(RecvError::Closed) => {
-                return Ok(());
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Handle webhook events for one domain user, up to the configured number at once.
+/// Events for the same account are handled one at a time, in the order received.
+async fn handle_events(
+    event_receiver: broadcast::Receiver<webhook::Event>,
+    handler: UserHandler,
+) -> Result<()> {
+    let domain = handler.config.domain.clone();
+    let username = handler.config.username.clone();
+    let concurrency = handler.config.concurrency;
+    let handler = Arc::new(handler);
+    dispatch_events(
+        event_receiver,
+        &domain,
+        &username,
+        concurrency,
+        move |event| {
+            let handler = handler.clone();
+            async move { handle_event(&handler, event).await }
+        },
+    )
+    .await
+}
+
+/// Handle a single webhook event, and record what happened in the audit log and health state.
+async fn handle_event(handler: &UserHandler, event: webhook::Event) {
+    let domain = &handler.config.domain;
+    let username = &handler.config.username;
+    let health_key = format!("{username}@{domain}");
+    match event {
+        webhook::Event::StatusCreated { ref status, .. }
+        | webhook::Event::StatusUpdated { ref status, .. } => {
+            let mut audit_entry = AuditEntry::new(
+                event.name(),
+                &status.account.id.to_string(),
+                &status.account.acct,
+                Some(&status.id.to_string()),
+            );
+            let result = handle_status(handler, status, &mut audit_entry).await;
+            if let Err(e) = result.as_ref() {
+                error!("{username}@{domain}: Error handling status: {e}");
+            }
+            audit_entry.finish(&result);
+            handler.audit.record(&audit_entry).await;
+            handler.health.event_handled(&health_key, &audit_entry);
+        }
+        webhook::Event::AccountCreated { ref account, .. }
+        | webhook::Event::AccountApproved { ref account, .. } => {
+            let mut audit_entry = AuditEntry::new(
+                event.name(),
+                &account.id.to_string(),
+                &account.account.acct,
+                None,
+            );
+            let result = handle_account(handler, account, true, &mut audit_entry).await;
+            if let Err(e) = result.as_ref() {
+                error!("{username}@{domain}: Error handling account: {e}");
             }
+            audit_entry.finish(&result);
+            handler.audit.record(&audit_entry).await;
+            handler.health.event_handled(&health_key, &audit_entry);
+        }
+        webhook::Event::ReportCreated { ref report, .. }
+        | webhook::Event::ReportUpdated { ref report, .. } => {
+            let mut audit_entry = AuditEntry::new(
+                event.name(),
+                report.target_account.id.as_ref(),
+                &report.target_account.account.acct,
+                None,
+            );
+            let result =
+                handle_report(handler.rspamd.as_ref(), domain, report, &mut audit_entry).await;
+            if let Err(e) = result.as_ref() {
+                error!("{username}@{domain}: Error handling report: {e}");
+            }
+            audit_entry.finish(&result);
+            handler.audit.record(&audit_entry).await;
+            handler.health.event_handled(&health_key, &audit_entry);
+        }
+        _ => {
+            info!("{username}@{domain}: Unimplemented event type: {event:#?}");
         }
     }
 }
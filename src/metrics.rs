@@ -0,0 +1,137 @@
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_int_counter, register_int_counter_vec, Encoder, Histogram,
+    IntCounter, IntCounterVec, TextEncoder,
+};
+use tracing::error;
+
+lazy_static! {
+    pub static ref WEBHOOK_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "m1000_webhook_requests_total",
+        "Webhook requests received, by response status code and signature check outcome.",
+        &["status", "signature"]
+    )
+    .unwrap();
+    pub static ref EVENTS: IntCounterVec = register_int_counter_vec!(
+        "m1000_events_total",
+        "Events received from webhooks, the streaming API, or polling, by event type and domain.",
+        &["type", "domain"]
+    )
+    .unwrap();
+    pub static ref EVENTS_REFUSED: IntCounterVec = register_int_counter_vec!(
+        "m1000_events_refused_total",
+        "Webhook events not handled because they were stale or duplicates, by reason and domain.",
+        &["reason", "domain"]
+    )
+    .unwrap();
+    pub static ref EVENTS_DROPPED: IntCounterVec = register_int_counter_vec!(
+        "m1000_events_dropped_total",
+        "Webhook events that couldn't be sent to any domain user's handler, by domain.",
+        &["domain"]
+    )
+    .unwrap();
+    pub static ref EVENTS_LAGGED: IntCounterVec = register_int_counter_vec!(
+        "m1000_events_lagged_total",
+        "Webhook events skipped because a domain user's handler fell behind.",
+        &["domain", "username"]
+    )
+    .unwrap();
+    pub static ref STREAM_RECONNECTS: IntCounterVec = register_int_counter_vec!(
+        "m1000_stream_reconnects_total",
+        "Times a domain user's streaming API connection dropped or failed, by domain and username.",
+        &["domain", "username"]
+    )
+    .unwrap();
+    pub static ref POLL_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "m1000_poll_failures_total",
+        "Failed polls of the Mastodon API, by domain, username, and source.",
+        &["domain", "username", "source"]
+    )
+    .unwrap();
+    pub static ref RULE_HITS: IntCounterVec = register_int_counter_vec!(
+        "m1000_rule_hits_total",
+        "Posts and accounts that matched each rule, including exempt accounts.",
+        &["domain", "username", "rule"]
+    )
+    .unwrap();
+    pub static ref REPORTS_FILED: IntCounterVec = register_int_counter_vec!(
+        "m1000_reports_filed_total",
+        "Reports filed successfully.",
+        &["domain", "username"]
+    )
+    .unwrap();
+    pub static ref RESTRICTIONS: IntCounterVec = register_int_counter_vec!(
+        "m1000_restrictions_total",
+        "Accounts restricted successfully, by restriction level.",
+        &["domain", "username", "restrict"]
+    )
+    .unwrap();
+    pub static ref RSPAMD_SCAN_SECONDS: Histogram = register_histogram!(
+        "m1000_rspamd_scan_seconds",
+        "Time taken to scan a post with rspamd, including failed scans."
+    )
+    .unwrap();
+    pub static ref RSPAMD_SCAN_FAILURES: IntCounter = register_int_counter!(
+        "m1000_rspamd_scan_failures_total",
+        "Posts that rspamd couldn't scan."
+    )
+    .unwrap();
+    pub static ref MASTODON_API_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "m1000_mastodon_api_errors_total",
+        "Failed Mastodon API calls, by domain, HTTP method, and API path.",
+        &["domain", "method", "path"]
+    )
+    .unwrap();
+}
+
+/// Serve all registered metrics in Prometheus text format.
+pub async fn serve_metrics() -> impl IntoResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        error!("Couldn't encode metrics: {e}");
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            [(header::CONTENT_TYPE, "text/plain".to_string())],
+            vec![],
+        );
+    }
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+        buffer,
+    )
+}
+
+/// Replace IDs in an API path so that it can be used as a metric label without unbounded cardinality.
+pub fn api_path_label(path: &str) -> String {
+    path.split('/')
+        .map(|segment| {
+            if !segment.is_empty() && segment.chars().all(|c| c.is_ascii_digit()) {
+                ":id"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_path_label() {
+        assert_eq!(
+            "/api/v1/admin/accounts/:id/approve",
+            api_path_label("/api/v1/admin/accounts/109348/approve")
+        );
+        assert_eq!(
+            "/api/v1/admin/domain_blocks",
+            api_path_label("/api/v1/admin/domain_blocks")
+        );
+    }
+}
@@ -1,10 +1,13 @@
-use crate::config::{AccountPattern, TextPattern, UserPattern};
+use crate::config::{AccountPattern, EmailPattern, IpPattern, TextPattern, UserPattern};
 use crate::pattern::compiler::{optimize, PatternNode};
+use crate::pattern::email::{EmailMatcher, EmailMatcherInput};
+use crate::pattern::ip::{parse_cidr, IpMatcher, IpMatcherInput};
 use crate::pattern::text::{TextMatcher, TextMatcherInput};
 use crate::pattern::user::{UserMatcher, UserMatcherInput};
-use crate::pattern::{CompileMatcher, Matcher};
+use crate::pattern::{CompileMatcher, Evidence, Matcher};
 use anyhow::Result;
-use mastodon_async::entities::account::Account;
+use mastodon_async::entities::{account::Account, admin};
+use std::net::IpAddr;
 use std::rc::Rc;
 use std::sync::Arc;
 
@@ -12,6 +15,9 @@ use std::sync::Arc;
 enum AccountPatternLeaf {
     User(UserPattern),
     Text(TextPattern),
+    Reason(TextPattern),
+    Email(EmailPattern),
+    Ip(IpPattern),
 }
 
 impl From<&AccountPattern> for Rc<PatternNode<AccountPatternLeaf>> {
@@ -23,6 +29,15 @@ impl From<&AccountPattern> for Rc<PatternNode<AccountPatternLeaf>> {
             AccountPattern::Text { text } => PatternNode::Leaf {
                 leaf: AccountPatternLeaf::Text(text.clone()),
             },
+            AccountPattern::Reason { reason } => PatternNode::Leaf {
+                leaf: AccountPatternLeaf::Reason(reason.clone()),
+            },
+            AccountPattern::Email { email } => PatternNode::Leaf {
+                leaf: AccountPatternLeaf::Email(email.clone()),
+            },
+            AccountPattern::Ip { ip } => PatternNode::Leaf {
+                leaf: AccountPatternLeaf::Ip(ip.clone()),
+            },
             AccountPattern::Any { any } => PatternNode::Any {
                 children: any.into_iter().map(|x| Self::from(x)).collect(),
             },
@@ -43,6 +58,9 @@ pub struct AccountMatcher(Arc<AccountMatcherInner>);
 enum AccountMatcherInner {
     User(UserMatcher),
     Text(TextMatcher),
+    Reason(TextMatcher),
+    Email(EmailMatcher),
+    Ip(IpMatcher),
     Any(Vec<Self>),
     All(Vec<Self>),
     Not(Box<Self>),
@@ -57,6 +75,15 @@ impl AccountMatcherInner {
             PatternNode::Leaf {
                 leaf: AccountPatternLeaf::Text(pattern),
             } => Self::Text(pattern.compile()?),
+            PatternNode::Leaf {
+                leaf: AccountPatternLeaf::Reason(pattern),
+            } => Self::Reason(pattern.compile()?),
+            PatternNode::Leaf {
+                leaf: AccountPatternLeaf::Email(pattern),
+            } => Self::Email(pattern.compile()?),
+            PatternNode::Leaf {
+                leaf: AccountPatternLeaf::Ip(pattern),
+            } => Self::Ip(pattern.compile()?),
             PatternNode::Any { children } => {
                 let mut matchers = vec![];
                 for child in children {
@@ -80,6 +107,12 @@ impl AccountMatcherInner {
 pub struct AccountMatcherInput {
     user: UserMatcherInput,
     text: TextMatcherInput,
+    /// Only available for new local accounts on instances that require approval.
+    reason: Option<TextMatcherInput>,
+    /// Only available for local accounts.
+    email: Option<EmailMatcherInput>,
+    /// Only available for local accounts.
+    ips: IpMatcherInput,
 }
 
 impl From<&Account> for AccountMatcherInput {
@@ -87,15 +120,57 @@ impl From<&Account> for AccountMatcherInput {
         Self {
             user: UserMatcherInput::from(account),
             text: TextMatcherInput::from(account),
+            reason: None,
+            email: None,
+            ips: Default::default(),
         }
     }
 }
 
+impl From<&admin::Account> for AccountMatcherInput {
+    fn from(admin_account: &admin::Account) -> Self {
+        let mut input = Self::from(&admin_account.account);
+        input.reason = admin_account
+            .invite_request
+            .as_deref()
+            .map(TextMatcherInput::from);
+        input.email = Some(EmailMatcherInput::from(admin_account.email.as_str()));
+        input.ips = IpMatcherInput::from(admin_account_ips(admin_account));
+        input
+    }
+}
+
+/// The signup IP and any IPs the account has logged in from.
+pub fn admin_account_ips(admin_account: &admin::Account) -> Vec<IpAddr> {
+    let mut ips = admin_account
+        .ip
+        .iter()
+        .map(|ip| ip.to_string())
+        .chain(admin_account.ips.iter().map(|ip| ip.ip.to_string()))
+        .filter_map(|ip| parse_cidr(&ip).ok())
+        .map(|network| network.addr())
+        .collect::<Vec<_>>();
+    ips.sort();
+    ips.dedup();
+    ips
+}
+
 impl Matcher<&AccountMatcherInput> for AccountMatcherInner {
     fn is_match(&self, input: &AccountMatcherInput) -> bool {
         match self {
             Self::User(matcher) => matcher.is_match(&input.user),
             Self::Text(matcher) => matcher.is_match(&input.text),
+            Self::Reason(matcher) => input
+                .reason
+                .as_ref()
+                .map(|reason| matcher.is_match(reason))
+                .unwrap_or(false),
+            Self::Email(matcher) => input
+                .email
+                .as_ref()
+                .map(|email| matcher.is_match(email))
+                .unwrap_or(false),
+            Self::Ip(matcher) => matcher.is_match(&input.ips),
             Self::Any(children) => children.iter().any(|child| child.is_match(input)),
             Self::All(children) => children.iter().all(|child| child.is_match(input)),
             Self::Not(child) => !child.is_match(input),
@@ -109,6 +184,34 @@ impl Matcher<&AccountMatcherInput> for AccountMatcher {
     }
 }
 
+impl Evidence<&AccountMatcherInput> for AccountMatcherInner {
+    fn evidence(&self, input: &AccountMatcherInput, evidence: &mut Vec<String>) {
+        match self {
+            Self::User(_) => evidence.push(input.user.to_string()),
+            Self::Text(matcher) => matcher.evidence(&input.text, evidence),
+            Self::Reason(matcher) => {
+                if let Some(reason) = input.reason.as_ref() {
+                    matcher.evidence(reason, evidence);
+                }
+            }
+            Self::Email(_) => evidence.extend(input.email.iter().map(|email| email.to_string())),
+            Self::Ip(_) => evidence.extend(input.ips.addresses().iter().map(|ip| ip.to_string())),
+            Self::Any(children) | Self::All(children) => {
+                for child in children.iter().filter(|child| child.is_match(input)) {
+                    child.evidence(input, evidence);
+                }
+            }
+            Self::Not(_) => {}
+        }
+    }
+}
+
+impl Evidence<&AccountMatcherInput> for AccountMatcher {
+    fn evidence(&self, input: &AccountMatcherInput, evidence: &mut Vec<String>) {
+        self.0.evidence(input, evidence)
+    }
+}
+
 impl CompileMatcher<AccountMatcher> for AccountPattern {
     fn compile(&self) -> Result<AccountMatcher> {
         Ok(AccountMatcher(Arc::new(AccountMatcherInner::from(
@@ -116,3 +219,74 @@ impl CompileMatcher<AccountMatcher> for AccountPattern {
         )?)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::example::placeholder_admin_account;
+    use serde_json::json;
+
+    fn is_match(yaml: &str, input: &AccountMatcherInput) -> bool {
+        serde_yaml::from_str::<AccountPattern>(yaml)
+            .unwrap()
+            .compile()
+            .unwrap()
+            .is_match(input)
+    }
+
+    #[test]
+    fn test_reason() {
+        let mut admin_account = placeholder_admin_account();
+        admin_account["invite_request"] = "I want to post about my casino".into();
+        let admin_account: admin::Account = serde_json::from_value(admin_account).unwrap();
+        let input = AccountMatcherInput::from(&admin_account);
+        assert!(is_match("reason: {word: casino}", &input));
+        assert!(!is_match("reason: {word: poker}", &input));
+
+        let mut evidence = vec![];
+        serde_yaml::from_str::<AccountPattern>("reason: {word: casino}")
+            .unwrap()
+            .compile()
+            .unwrap()
+            .evidence(&input, &mut evidence);
+        assert_eq!(vec!["casino".to_string()], evidence);
+
+        // No reason given.
+        let admin_account: admin::Account =
+            serde_json::from_value(placeholder_admin_account()).unwrap();
+        let input = AccountMatcherInput::from(&admin_account);
+        assert!(!is_match("reason: {word: casino}", &input));
+        assert!(is_match("not: {reason: {word: casino}}", &input));
+    }
+
+    #[test]
+    fn test_from() {
+        let mut admin_account = placeholder_admin_account();
+        admin_account["email"] = "someone@mailinator.com".into();
+        admin_account["ip"] = "192.0.2.1".into();
+        admin_account["ips"] =
+            json!([{"ip": "198.51.100.1", "used_at": "2023-01-01T00:00:00.000Z"}]);
+        admin_account["invite_request"] = "casino".into();
+        let admin_account: admin::Account = serde_json::from_value(admin_account).unwrap();
+
+        // Admin accounts have signup details.
+        let input = AccountMatcherInput::from(&admin_account);
+        assert!(is_match("email: {disposable: true}", &input));
+        assert!(is_match("ip: {cidr: 192.0.2.0/24}", &input));
+        assert!(is_match("ip: {cidr: 198.51.100.0/24}", &input));
+        assert!(is_match("reason: {word: casino}", &input));
+
+        // Plain accounts, like the ones on posts, don't.
+        let input = AccountMatcherInput::from(&admin_account.account);
+        assert!(!is_match("email: {disposable: true}", &input));
+        assert!(!is_match("ip: {cidr: 192.0.2.0/24}", &input));
+        assert!(!is_match("reason: {word: casino}", &input));
+        assert!(is_match(
+            &format!(
+                "user: {{username: {{word: {username}}}}}",
+                username = admin_account.account.username
+            ),
+            &input
+        ));
+    }
+}
@@ -1,6 +1,23 @@
-use anyhow::{bail, Result};
+use anyhow::Result;
+use std::fmt;
 use std::rc::Rc;
 
+/// Returned by [`optimize`] when a pattern is made entirely of empty `any` and `all` lists,
+/// so callers can tell this apart from other compilation errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmptyPattern;
+
+impl fmt::Display for EmptyPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Pattern reduced to nothing: it only contains empty `any` or `all` lists"
+        )
+    }
+}
+
+impl std::error::Error for EmptyPattern {}
+
 /// Intermediate representation of an expression made up of leaf matchers and boolean operators.
 /// Leaf matchers might be regex patterns for strings, or other things for Mastodon API structures.
 #[derive(Debug, Clone)]
@@ -82,7 +99,7 @@ pub fn optimize<L: Clone>(root: Rc<PatternNode<L>>) -> Result<Rc<PatternNode<L>>
                     break;
                 }
             } else {
-                bail!("Reduced to nothingness");
+                return Err(EmptyPattern.into());
             }
         }
     }
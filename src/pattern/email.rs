@@ -0,0 +1,226 @@
+use crate::config::{EmailPattern, InstancePattern, StringPattern};
+use crate::pattern::compiler::{optimize, PatternNode};
+use crate::pattern::instance::InstanceMatcher;
+use crate::pattern::string::StringMatcher;
+use crate::pattern::{CompileMatcher, Matcher};
+use anyhow::Result;
+use lazy_static::lazy_static;
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+use std::sync::Arc;
+
+lazy_static! {
+    static ref DISPOSABLE_EMAIL_DOMAINS: HashSet<&'static str> =
+        domain_list(include_str!("disposable_email_domains.txt"));
+    static ref WEBMAIL_EMAIL_DOMAINS: HashSet<&'static str> =
+        domain_list(include_str!("webmail_email_domains.txt"));
+}
+
+fn domain_list(contents: &'static str) -> HashSet<&'static str> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect()
+}
+
+/// Is this domain, or any domain it's a subdomain of, a disposable email service?
+fn is_disposable(domain: &str) -> bool {
+    in_domain_list(&DISPOSABLE_EMAIL_DOMAINS, domain)
+}
+
+/// Is this domain, or any domain it's a subdomain of, a big email provider
+/// that too many other people use to block?
+pub fn is_webmail(domain: &str) -> bool {
+    in_domain_list(&WEBMAIL_EMAIL_DOMAINS, &domain.to_lowercase())
+}
+
+fn in_domain_list(list: &HashSet<&'static str>, domain: &str) -> bool {
+    let mut domain = domain;
+    loop {
+        if list.contains(domain) {
+            return true;
+        }
+        match domain.split_once('.') {
+            Some((_, parent)) => domain = parent,
+            None => return false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum EmailPatternLeaf {
+    Local(StringPattern),
+    Domain(InstancePattern),
+    Disposable(bool),
+}
+
+impl From<&EmailPattern> for Rc<PatternNode<EmailPatternLeaf>> {
+    fn from(p: &EmailPattern) -> Rc<PatternNode<EmailPatternLeaf>> {
+        Rc::new(match p {
+            EmailPattern::Local { local } => PatternNode::Leaf {
+                leaf: EmailPatternLeaf::Local(local.clone()),
+            },
+            EmailPattern::Domain { domain } => PatternNode::Leaf {
+                leaf: EmailPatternLeaf::Domain(domain.clone()),
+            },
+            EmailPattern::Disposable { disposable } => PatternNode::Leaf {
+                leaf: EmailPatternLeaf::Disposable(*disposable),
+            },
+            EmailPattern::Any { any } => PatternNode::Any {
+                children: any.into_iter().map(|x| Self::from(x)).collect(),
+            },
+            EmailPattern::All { all } => PatternNode::All {
+                children: all.into_iter().map(|x| Self::from(x)).collect(),
+            },
+            EmailPattern::Not { not } => PatternNode::Not {
+                child: Self::from(not.as_ref()),
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EmailMatcher(Arc<EmailMatcherInner>);
+
+#[derive(Debug, Clone)]
+enum EmailMatcherInner {
+    Local(StringMatcher),
+    Domain(InstanceMatcher),
+    Disposable(bool),
+    Any(Vec<Self>),
+    All(Vec<Self>),
+    Not(Box<Self>),
+}
+
+impl EmailMatcherInner {
+    pub fn from(node: Rc<PatternNode<EmailPatternLeaf>>) -> Result<Self> {
+        Ok(match node.as_ref() {
+            PatternNode::Leaf {
+                leaf: EmailPatternLeaf::Local(pattern),
+            } => Self::Local(pattern.compile()?),
+            PatternNode::Leaf {
+                leaf: EmailPatternLeaf::Domain(pattern),
+            } => Self::Domain(pattern.compile()?),
+            PatternNode::Leaf {
+                leaf: EmailPatternLeaf::Disposable(disposable),
+            } => Self::Disposable(*disposable),
+            PatternNode::Any { children } => {
+                let mut matchers = vec![];
+                for child in children {
+                    matchers.push(Self::from(child.clone())?);
+                }
+                Self::Any(matchers)
+            }
+            PatternNode::All { children } => {
+                let mut matchers = vec![];
+                for child in children {
+                    matchers.push(Self::from(child.clone())?);
+                }
+                Self::All(matchers)
+            }
+            PatternNode::Not { child } => Self::Not(Box::new(Self::from(child.clone())?)),
+        })
+    }
+}
+
+/// An email address split at the last `@`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmailMatcherInput {
+    local: String,
+    domain: String,
+}
+
+impl From<&str> for EmailMatcherInput {
+    fn from(email: &str) -> Self {
+        match email.rsplit_once('@') {
+            Some((local, domain)) => Self {
+                local: local.to_string(),
+                domain: domain.to_lowercase(),
+            },
+            None => Self {
+                local: email.to_string(),
+                domain: String::new(),
+            },
+        }
+    }
+}
+
+impl EmailMatcherInput {
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+}
+
+impl Display for EmailMatcherInput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{local}@{domain}",
+            local = self.local,
+            domain = self.domain
+        )
+    }
+}
+
+impl Matcher<&EmailMatcherInput> for EmailMatcherInner {
+    fn is_match(&self, input: &EmailMatcherInput) -> bool {
+        match self {
+            Self::Local(matcher) => matcher.is_match(&input.local),
+            Self::Domain(matcher) => matcher.is_match(&input.domain),
+            Self::Disposable(disposable) => *disposable == is_disposable(&input.domain),
+            Self::Any(children) => children.iter().any(|child| child.is_match(input)),
+            Self::All(children) => children.iter().all(|child| child.is_match(input)),
+            Self::Not(child) => !child.is_match(input),
+        }
+    }
+}
+
+impl Matcher<&EmailMatcherInput> for EmailMatcher {
+    fn is_match(&self, input: &EmailMatcherInput) -> bool {
+        self.0.is_match(input)
+    }
+}
+
+impl CompileMatcher<EmailMatcher> for EmailPattern {
+    fn compile(&self) -> Result<EmailMatcher> {
+        Ok(EmailMatcher(Arc::new(EmailMatcherInner::from(optimize(
+            Rc::<PatternNode<EmailPatternLeaf>>::from(self),
+        )?)?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disposable_and_domain() {
+        let pattern = EmailPattern::Any {
+            any: vec![
+                EmailPattern::Disposable { disposable: true },
+                EmailPattern::Domain {
+                    domain: InstancePattern::Domain {
+                        domain: "spam.test".to_string(),
+                    },
+                },
+            ],
+        };
+
+        let matcher = pattern.compile().expect("Couldn't compile");
+        assert!(matcher.is_match(&EmailMatcherInput::from("someone@mailinator.com")));
+        assert!(matcher.is_match(&EmailMatcherInput::from("someone@eu.Mailinator.com")));
+        assert!(matcher.is_match(&EmailMatcherInput::from("someone@mail.spam.test")));
+        assert!(!matcher.is_match(&EmailMatcherInput::from("someone@example.test")));
+    }
+
+    #[test]
+    fn test_webmail() {
+        assert!(is_webmail("gmail.com"));
+        assert!(is_webmail("GMail.com"));
+        assert!(is_webmail("eu.outlook.com"));
+        assert!(!is_webmail("mailinator.com"));
+        assert!(!is_webmail("notgmail.com"));
+    }
+}
@@ -0,0 +1,247 @@
+use crate::config::IpPattern;
+use crate::pattern::compiler::{optimize, PatternNode};
+use crate::pattern::{CompileMatcher, Matcher};
+use anyhow::{anyhow, Result};
+use ipnet::IpNet;
+use std::fs::read_to_string;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+enum IpPatternLeaf {
+    Cidr(String),
+    List(PathBuf),
+}
+
+impl From<&IpPattern> for Rc<PatternNode<IpPatternLeaf>> {
+    fn from(p: &IpPattern) -> Rc<PatternNode<IpPatternLeaf>> {
+        Rc::new(match p {
+            IpPattern::Cidr { cidr } => PatternNode::Leaf {
+                leaf: IpPatternLeaf::Cidr(cidr.clone()),
+            },
+            IpPattern::List { list } => PatternNode::Leaf {
+                leaf: IpPatternLeaf::List(list.clone()),
+            },
+            IpPattern::Any { any } => PatternNode::Any {
+                children: any.into_iter().map(|x| Self::from(x)).collect(),
+            },
+            IpPattern::All { all } => PatternNode::All {
+                children: all.into_iter().map(|x| Self::from(x)).collect(),
+            },
+            IpPattern::Not { not } => PatternNode::Not {
+                child: Self::from(not.as_ref()),
+            },
+        })
+    }
+}
+
+/// Parse a CIDR range, or a single address, which is treated as a range containing only itself.
+pub fn parse_cidr(s: &str) -> Result<IpNet> {
+    let s = s.trim();
+    if let Ok(net) = s.parse::<IpNet>() {
+        return Ok(net);
+    }
+    s.parse::<IpAddr>()
+        .map(IpNet::from)
+        .map_err(|_| anyhow!("Not an IP address or CIDR range: {s}"))
+}
+
+impl IpPatternLeaf {
+    fn networks(&self) -> Result<Vec<IpNet>> {
+        match self {
+            Self::Cidr(cidr) => Ok(vec![parse_cidr(cidr)?]),
+            Self::List(path) => {
+                let contents = read_to_string(path).map_err(|e| {
+                    anyhow!(
+                        "Couldn't read IP list {path}: {e}",
+                        path = path.to_string_lossy()
+                    )
+                })?;
+                let mut networks = vec![];
+                for line in contents.lines() {
+                    let line = line.trim();
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                    networks.push(parse_cidr(line)?);
+                }
+                Ok(networks)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IpMatcher(Arc<IpMatcherInner>);
+
+#[derive(Debug, Clone)]
+enum IpMatcherInner {
+    /// Matches if any address is in any of these ranges.
+    AnyNetworks(Vec<IpNet>),
+    Any(Vec<Self>),
+    All(Vec<Self>),
+    Not(Box<Self>),
+}
+
+impl IpMatcherInner {
+    pub fn from(node: Rc<PatternNode<IpPatternLeaf>>) -> Result<Self> {
+        Ok(match node.as_ref() {
+            PatternNode::Leaf { leaf } => Self::AnyNetworks(IpNet::aggregate(&leaf.networks()?)),
+            PatternNode::Any { children } => {
+                let leaves = children
+                    .iter()
+                    .flat_map(|child| match child.as_ref() {
+                        PatternNode::Leaf { leaf } => Some(leaf),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                if leaves.len() == children.len() {
+                    let mut networks = vec![];
+                    for leaf in leaves {
+                        networks.extend(leaf.networks()?);
+                    }
+                    Self::AnyNetworks(IpNet::aggregate(&networks))
+                } else {
+                    let mut matchers = vec![];
+                    for child in children {
+                        matchers.push(Self::from(child.clone())?);
+                    }
+                    Self::Any(matchers)
+                }
+            }
+            PatternNode::All { children } => {
+                let mut matchers = vec![];
+                for child in children {
+                    matchers.push(Self::from(child.clone())?);
+                }
+                Self::All(matchers)
+            }
+            PatternNode::Not { child } => Self::Not(Box::new(Self::from(child.clone())?)),
+        })
+    }
+}
+
+/// All of the IP addresses an account is known to have used.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpMatcherInput {
+    addresses: Vec<IpAddr>,
+}
+
+impl From<Vec<IpAddr>> for IpMatcherInput {
+    fn from(addresses: Vec<IpAddr>) -> Self {
+        Self { addresses }
+    }
+}
+
+impl IpMatcherInput {
+    pub fn addresses(&self) -> &[IpAddr] {
+        &self.addresses
+    }
+}
+
+impl Matcher<&IpMatcherInput> for IpMatcherInner {
+    fn is_match(&self, input: &IpMatcherInput) -> bool {
+        match self {
+            Self::AnyNetworks(networks) => input
+                .addresses
+                .iter()
+                .any(|address| networks.iter().any(|network| network.contains(address))),
+            Self::Any(children) => children.iter().any(|child| child.is_match(input)),
+            Self::All(children) => children.iter().all(|child| child.is_match(input)),
+            Self::Not(child) => !child.is_match(input),
+        }
+    }
+}
+
+impl Matcher<&IpMatcherInput> for IpMatcher {
+    fn is_match(&self, input: &IpMatcherInput) -> bool {
+        self.0.is_match(input)
+    }
+}
+
+impl CompileMatcher<IpMatcher> for IpPattern {
+    fn compile(&self) -> Result<IpMatcher> {
+        Ok(IpMatcher(Arc::new(IpMatcherInner::from(optimize(Rc::<
+            PatternNode<IpPatternLeaf>,
+        >::from(
+            self,
+        ))?)?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(addresses: &[&str]) -> IpMatcherInput {
+        IpMatcherInput::from(
+            addresses
+                .iter()
+                .map(|address| address.parse().unwrap())
+                .collect::<Vec<IpAddr>>(),
+        )
+    }
+
+    fn cidr(cidr: &str) -> IpPattern {
+        IpPattern::Cidr {
+            cidr: cidr.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_cidr() {
+        assert_eq!(
+            "192.0.2.0/24".parse::<IpNet>().unwrap(),
+            parse_cidr(" 192.0.2.0/24 ").unwrap()
+        );
+        assert_eq!(
+            "2001:db8::1/128".parse::<IpNet>().unwrap(),
+            parse_cidr("2001:db8::1").unwrap()
+        );
+        assert!(parse_cidr("spam.example").is_err());
+    }
+
+    #[test]
+    fn test_cidr() {
+        let matcher = cidr("192.0.2.0/24").compile().expect("Couldn't compile");
+        assert!(matcher.is_match(&input(&["192.0.2.7"])));
+        assert!(matcher.is_match(&input(&["2001:db8::1", "192.0.2.255"])));
+        assert!(!matcher.is_match(&input(&["198.51.100.1"])));
+        assert!(!matcher.is_match(&input(&[])));
+    }
+
+    #[test]
+    fn test_any_all_not() {
+        let any = IpPattern::Any {
+            any: vec![cidr("192.0.2.0/24"), cidr("2001:db8::/32")],
+        };
+        let matcher = any.compile().expect("Couldn't compile");
+        assert!(matcher.is_match(&input(&["192.0.2.7"])));
+        assert!(matcher.is_match(&input(&["2001:db8::1"])));
+        assert!(!matcher.is_match(&input(&["198.51.100.1"])));
+
+        let all = IpPattern::All {
+            all: vec![
+                cidr("192.0.2.0/24"),
+                IpPattern::Not {
+                    not: Box::new(cidr("192.0.2.128/25")),
+                },
+            ],
+        };
+        let matcher = all.compile().expect("Couldn't compile");
+        assert!(matcher.is_match(&input(&["192.0.2.7"])));
+        assert!(!matcher.is_match(&input(&["192.0.2.200"])));
+        assert!(!matcher.is_match(&input(&["198.51.100.1"])));
+    }
+
+    #[test]
+    fn test_bad_patterns() {
+        assert!(cidr("192.0.2.0/33").compile().is_err());
+        let missing_list = IpPattern::List {
+            list: PathBuf::from("/nonexistent/m1000/ip-list.txt"),
+        };
+        assert!(missing_list.compile().is_err());
+    }
+}
@@ -1,6 +1,8 @@
 mod account;
 mod compiler;
+mod email;
 mod instance;
+mod ip;
 mod link;
 mod post;
 mod regex;
@@ -15,8 +17,20 @@ pub trait Matcher<T> {
     fn is_match(&self, t: T) -> bool;
 }
 
+/// Collect human-readable descriptions of the parts of an input that made a matcher match,
+/// such as matched words or links, for use in warnings and reports.
+/// Only meaningful if the matcher matched: negated patterns contribute nothing.
+pub trait Evidence<T> {
+    fn evidence(&self, t: T, evidence: &mut Vec<String>);
+}
+
 pub trait CompileMatcher<M> {
     fn compile(&self) -> Result<M>;
 }
 
+pub use account::admin_account_ips;
+pub use compiler::EmptyPattern;
+pub use email::{is_webmail, EmailMatcherInput};
+pub use instance::InstanceMatcher;
 pub use rule::{RuleMatcher, RuleMatcherInput};
+pub use user::{UserMatcher, UserMatcherInput};
@@ -1,7 +1,7 @@
 use crate::config::{PostPattern, TextPattern};
 use crate::pattern::compiler::{optimize, PatternNode};
 use crate::pattern::text::{TextMatcher, TextMatcherInput};
-use crate::pattern::{CompileMatcher, Matcher};
+use crate::pattern::{CompileMatcher, Evidence, Matcher};
 use anyhow::Result;
 use mastodon_async::entities::status::Status;
 use std::rc::Rc;
@@ -97,6 +97,26 @@ impl Matcher<&PostMatcherInput> for PostMatcher {
     }
 }
 
+impl Evidence<&PostMatcherInput> for PostMatcherInner {
+    fn evidence(&self, input: &PostMatcherInput, evidence: &mut Vec<String>) {
+        match self {
+            Self::Text(matcher) => matcher.evidence(&input.text, evidence),
+            Self::Any(children) | Self::All(children) => {
+                for child in children.iter().filter(|child| child.is_match(input)) {
+                    child.evidence(input, evidence);
+                }
+            }
+            Self::Not(_) => {}
+        }
+    }
+}
+
+impl Evidence<&PostMatcherInput> for PostMatcher {
+    fn evidence(&self, input: &PostMatcherInput, evidence: &mut Vec<String>) {
+        self.0.evidence(input, evidence)
+    }
+}
+
 impl CompileMatcher<PostMatcher> for PostPattern {
     fn compile(&self) -> Result<PostMatcher> {
         Ok(PostMatcher(Arc::new(PostMatcherInner::from(optimize(
@@ -1,10 +1,13 @@
-use crate::config::{AccountPattern, PostPattern, RulePattern};
+use crate::config::{
+    AccountPattern, PostPattern, RspamdPattern, RulePattern, ScoreRange, SymbolPattern,
+};
+use crate::interop::rspamd::RspamdResult;
 use crate::pattern::account::{AccountMatcher, AccountMatcherInput};
 use crate::pattern::compiler::{optimize, PatternNode};
 use crate::pattern::post::{PostMatcher, PostMatcherInput};
-use crate::pattern::{CompileMatcher, Matcher};
-use anyhow::Result;
-use mastodon_async::entities::status::Status;
+use crate::pattern::{CompileMatcher, Evidence, Matcher};
+use anyhow::{bail, Result};
+use mastodon_async::entities::{admin, status::Status};
 use std::rc::Rc;
 use std::sync::Arc;
 
@@ -12,7 +15,7 @@ use std::sync::Arc;
 enum RulePatternLeaf {
     Account(AccountPattern),
     Post(PostPattern),
-    Rspamd(String),
+    Rspamd(RspamdPattern),
 }
 
 impl From<&RulePattern> for Rc<PatternNode<RulePatternLeaf>> {
@@ -24,8 +27,14 @@ impl From<&RulePattern> for Rc<PatternNode<RulePatternLeaf>> {
             RulePattern::Post { post } => PatternNode::Leaf {
                 leaf: RulePatternLeaf::Post(post.clone()),
             },
-            RulePattern::Rspamd { action } => PatternNode::Leaf {
-                leaf: RulePatternLeaf::Rspamd(action.clone()),
+            RulePattern::RspamdAction { action } => PatternNode::Leaf {
+                leaf: RulePatternLeaf::Rspamd(RspamdPattern {
+                    action: Some(action.clone()),
+                    ..Default::default()
+                }),
+            },
+            RulePattern::Rspamd { rspamd } => PatternNode::Leaf {
+                leaf: RulePatternLeaf::Rspamd(rspamd.clone()),
             },
             RulePattern::Any { any } => PatternNode::Any {
                 children: any.into_iter().map(|x| Self::from(x)).collect(),
@@ -47,7 +56,7 @@ pub struct RuleMatcher(Arc<RuleMatcherInner>);
 enum RuleMatcherInner {
     Account(AccountMatcher),
     Post(PostMatcher),
-    Rspamd(String),
+    Rspamd(RspamdPattern),
     Any(Vec<Self>),
     All(Vec<Self>),
     Not(Box<Self>),
@@ -63,8 +72,24 @@ impl RuleMatcherInner {
                 leaf: RulePatternLeaf::Post(pattern),
             } => Self::Post(pattern.compile()?),
             PatternNode::Leaf {
-                leaf: RulePatternLeaf::Rspamd(action),
-            } => Self::Rspamd(action.clone()),
+                leaf: RulePatternLeaf::Rspamd(pattern),
+            } => {
+                if pattern.action.is_none() && pattern.score.is_none() && pattern.symbol.is_none() {
+                    bail!("rspamd patterns need action, score, symbol, or a combination");
+                }
+                let symbol_range = match &pattern.symbol {
+                    Some(SymbolPattern::Scored { score, .. }) => Some(score),
+                    _ => None,
+                };
+                if [pattern.score.as_ref(), symbol_range]
+                    .into_iter()
+                    .flatten()
+                    .any(|range| range.at_least.is_none() && range.below.is_none())
+                {
+                    bail!("rspamd score ranges need at_least, below, or both");
+                }
+                Self::Rspamd(pattern.clone())
+            }
             PatternNode::Any { children } => {
                 let mut matchers = vec![];
                 for child in children {
@@ -87,37 +112,89 @@ impl RuleMatcherInner {
 #[derive(Debug, Clone)]
 pub struct RuleMatcherInput {
     account: AccountMatcherInput,
-    post: PostMatcherInput,
-    rspamd: Option<String>,
+    /// Not present for account events.
+    post: Option<PostMatcherInput>,
+    rspamd: Option<RspamdResult>,
 }
 
 impl From<&Status> for RuleMatcherInput {
     fn from(status: &Status) -> Self {
         Self {
             account: AccountMatcherInput::from(&status.account),
-            post: PostMatcherInput::from(status),
+            post: Some(PostMatcherInput::from(status)),
             // If rspamd is enabled, this can be added later.
             rspamd: None,
         }
     }
 }
 
+impl From<&admin::Account> for RuleMatcherInput {
+    fn from(admin_account: &admin::Account) -> Self {
+        Self {
+            account: AccountMatcherInput::from(admin_account),
+            post: None,
+            rspamd: None,
+        }
+    }
+}
+
 impl RuleMatcherInput {
-    pub fn rspamd(&mut self, action: String) -> &mut Self {
-        self.rspamd = Some(action);
+    pub fn rspamd(&mut self, result: RspamdResult) -> &mut Self {
+        self.rspamd = Some(result);
         self
     }
 }
 
+fn in_range(range: &ScoreRange, score: f64) -> bool {
+    range.at_least.is_none_or(|at_least| score >= at_least)
+        && range.below.is_none_or(|below| score < below)
+}
+
+fn rspamd_is_match(pattern: &RspamdPattern, result: &RspamdResult) -> bool {
+    pattern
+        .action
+        .as_ref()
+        .is_none_or(|action| action == &result.action)
+        && pattern
+            .score
+            .as_ref()
+            .is_none_or(|score| in_range(score, result.score))
+        && pattern.symbol.as_ref().is_none_or(|symbol| match symbol {
+            SymbolPattern::Name(name) => result.symbols.contains_key(name),
+            SymbolPattern::Scored { name, score } => result
+                .symbols
+                .get(name)
+                .is_some_and(|symbol| in_range(score, symbol.score)),
+        })
+}
+
+fn rspamd_evidence(pattern: &RspamdPattern, result: &RspamdResult, evidence: &mut Vec<String>) {
+    if let Some(action) = &pattern.action {
+        evidence.push(format!("rspamd: {action}"));
+    }
+    if pattern.score.is_some() {
+        evidence.push(format!("rspamd score: {score}", score = result.score));
+    }
+    if let Some(SymbolPattern::Name(name) | SymbolPattern::Scored { name, .. }) = &pattern.symbol {
+        if let Some(symbol) = result.symbols.get(name) {
+            evidence.push(format!("rspamd: {name} ({score})", score = symbol.score));
+        }
+    }
+}
+
 impl Matcher<&RuleMatcherInput> for RuleMatcherInner {
     fn is_match(&self, input: &RuleMatcherInput) -> bool {
         match self {
             Self::Account(matcher) => matcher.is_match(&input.account),
-            Self::Post(matcher) => matcher.is_match(&input.post),
-            Self::Rspamd(action) => input
+            Self::Post(matcher) => input
+                .post
+                .as_ref()
+                .map(|post| matcher.is_match(post))
+                .unwrap_or(false),
+            Self::Rspamd(pattern) => input
                 .rspamd
                 .as_ref()
-                .map(|input_action| action == input_action)
+                .map(|result| rspamd_is_match(pattern, result))
                 .unwrap_or(false),
             Self::Any(children) => children.iter().any(|child| child.is_match(input)),
             Self::All(children) => children.iter().all(|child| child.is_match(input)),
@@ -132,6 +209,36 @@ impl Matcher<&RuleMatcherInput> for RuleMatcher {
     }
 }
 
+impl Evidence<&RuleMatcherInput> for RuleMatcherInner {
+    fn evidence(&self, input: &RuleMatcherInput, evidence: &mut Vec<String>) {
+        match self {
+            Self::Account(matcher) => matcher.evidence(&input.account, evidence),
+            Self::Post(matcher) => {
+                if let Some(post) = input.post.as_ref() {
+                    matcher.evidence(post, evidence);
+                }
+            }
+            Self::Rspamd(pattern) => {
+                if let Some(result) = input.rspamd.as_ref() {
+                    rspamd_evidence(pattern, result, evidence);
+                }
+            }
+            Self::Any(children) | Self::All(children) => {
+                for child in children.iter().filter(|child| child.is_match(input)) {
+                    child.evidence(input, evidence);
+                }
+            }
+            Self::Not(_) => {}
+        }
+    }
+}
+
+impl Evidence<&RuleMatcherInput> for RuleMatcher {
+    fn evidence(&self, input: &RuleMatcherInput, evidence: &mut Vec<String>) {
+        self.0.evidence(input, evidence)
+    }
+}
+
 impl CompileMatcher<RuleMatcher> for RulePattern {
     fn compile(&self) -> Result<RuleMatcher> {
         Ok(RuleMatcher(Arc::new(RuleMatcherInner::from(optimize(
@@ -225,4 +332,85 @@ mod tests {
 
         assert!(matcher.is_match(&input));
     }
+
+    #[test]
+    fn test_rspamd_patterns() {
+        let status: Status = serde_json::from_value(crate::example::placeholder_status()).unwrap();
+        let mut input = RuleMatcherInput::from(&status);
+        let is_match = |yaml: &str, input: &RuleMatcherInput| {
+            serde_yaml::from_str::<RulePattern>(yaml)
+                .unwrap()
+                .compile()
+                .unwrap()
+                .is_match(input)
+        };
+        assert!(!is_match("rspamd: {score: {at_least: 8}}", &input));
+
+        input.rspamd(
+            serde_json::from_str(
+                r#"{
+                    "action": "reject",
+                    "score": 9.5,
+                    "required_score": 15,
+                    "symbols": {
+                        "URIBL_BLACK": {"name": "URIBL_BLACK", "score": 7.5},
+                        "MASTODON_NEW_ACCOUNT": {"name": "MASTODON_NEW_ACCOUNT", "score": 2.0}
+                    }
+                }"#,
+            )
+            .unwrap(),
+        );
+        assert!(is_match("action: reject", &input));
+        assert!(is_match("rspamd: {action: reject}", &input));
+        assert!(is_match("rspamd: {score: {at_least: 8}}", &input));
+        assert!(is_match(
+            "rspamd: {score: {at_least: 9.5, below: 15}}",
+            &input
+        ));
+        assert!(!is_match("rspamd: {score: {below: 8}}", &input));
+        assert!(is_match("rspamd: {symbol: URIBL_BLACK}", &input));
+        assert!(!is_match("rspamd: {symbol: RBL_SPAMHAUS}", &input));
+        assert!(is_match(
+            "rspamd: {symbol: {name: MASTODON_NEW_ACCOUNT, score: {at_least: 1}}}",
+            &input
+        ));
+        assert!(!is_match(
+            "rspamd: {symbol: {name: URIBL_BLACK, score: {below: 5}}}",
+            &input
+        ));
+
+        let pattern: RulePattern = serde_yaml::from_str("rspamd: {symbol: URIBL_BLACK}").unwrap();
+        let mut evidence = vec![];
+        pattern.compile().unwrap().evidence(&input, &mut evidence);
+        assert_eq!(vec!["rspamd: URIBL_BLACK (7.5)".to_string()], evidence);
+
+        // Every part of a combined pattern has to match.
+        assert!(is_match(
+            "rspamd: {score: {at_least: 8}, symbol: URIBL_BLACK}",
+            &input
+        ));
+        assert!(!is_match(
+            "rspamd: {score: {at_least: 8}, symbol: RBL_SPAMHAUS}",
+            &input
+        ));
+        assert!(!is_match(
+            "rspamd: {action: reject, score: {below: 8}}",
+            &input
+        ));
+        // Unknown keys are errors, not ignored.
+        assert!(serde_yaml::from_str::<RulePattern>(
+            "rspamd: {score: {at_least: 8}, sybmol: URIBL_BLACK}"
+        )
+        .is_err());
+        assert!(serde_yaml::from_str::<RulePattern>("rspamd: {score: {at_lest: 8}}").is_err());
+        assert!(serde_yaml::from_str::<RulePattern>("rspamd: {}")
+            .unwrap()
+            .compile()
+            .is_err());
+
+        assert!(serde_yaml::from_str::<RulePattern>("rspamd: {score: {}}")
+            .unwrap()
+            .compile()
+            .is_err());
+    }
 }
@@ -3,11 +3,11 @@ use crate::pattern::compiler::{optimize, PatternNode};
 use crate::pattern::link::LinkMatcher;
 use crate::pattern::string::StringMatcher;
 use crate::pattern::user::{UserMatcher, UserMatcherInput};
-use crate::pattern::{CompileMatcher, Matcher};
+use crate::pattern::{CompileMatcher, Evidence, Matcher};
 use anyhow::Result;
 use lazy_static::lazy_static;
 use mastodon_async::entities::{account::Account, status::Status};
-use regex::RegexSet;
+use regex::{Regex, RegexSet};
 use scraper::{Html, Selector};
 use std::collections::HashSet;
 use std::rc::Rc;
@@ -187,6 +187,32 @@ impl From<&Html> for TextMatcherInput {
     }
 }
 
+/// For plain text such as registration reasons, which have no HTML or API metadata.
+impl From<&str> for TextMatcherInput {
+    fn from(s: &str) -> Self {
+        let text = s.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        let links = text
+            .split(' ')
+            .filter_map(|word| Url::parse(word).ok())
+            .filter(|url| url.scheme() == "http" || url.scheme() == "https")
+            .collect();
+
+        let hashtags = Extractor::new()
+            .extract_hashtags(&text)
+            .iter()
+            .map(|tag| tag.value.to_string())
+            .collect();
+
+        Self {
+            text,
+            links,
+            mentions: Default::default(),
+            hashtags,
+        }
+    }
+}
+
 impl From<&Account> for TextMatcherInput {
     fn from(account: &Account) -> Self {
         let bio = Html::parse_fragment(&account.note);
@@ -274,6 +300,59 @@ impl Matcher<&TextMatcherInput> for TextMatcher {
     }
 }
 
+impl Evidence<&TextMatcherInput> for TextMatcherInner {
+    fn evidence(&self, input: &TextMatcherInput, evidence: &mut Vec<String>) {
+        match self {
+            Self::AllRegexes(regexes) | Self::AnyRegexes(regexes) => {
+                // Regex sets can tell us which patterns matched, but not where,
+                // so recompile just the ones that matched to find the matched text.
+                for index in regexes.matches(&input.text).into_iter() {
+                    if let Ok(regex) = Regex::new(&regexes.patterns()[index]) {
+                        evidence.extend(
+                            regex
+                                .find_iter(&input.text)
+                                .map(|found| found.as_str().to_string()),
+                        );
+                    }
+                }
+            }
+            Self::Link(matcher) => evidence.extend(
+                input
+                    .links
+                    .iter()
+                    .filter(|url| matcher.is_match(*url))
+                    .map(Url::to_string),
+            ),
+            Self::Mention(matcher) => evidence.extend(
+                input
+                    .mentions
+                    .iter()
+                    .filter(|mention| matcher.is_match(*mention))
+                    .map(|mention| format!("@{mention}")),
+            ),
+            Self::Hashtag(matcher) => evidence.extend(
+                input
+                    .hashtags
+                    .iter()
+                    .filter(|hashtag| matcher.is_match(*hashtag))
+                    .map(|hashtag| format!("#{hashtag}")),
+            ),
+            Self::Any(children) | Self::All(children) => {
+                for child in children.iter().filter(|child| child.is_match(input)) {
+                    child.evidence(input, evidence);
+                }
+            }
+            Self::Not(_) => {}
+        }
+    }
+}
+
+impl Evidence<&TextMatcherInput> for TextMatcher {
+    fn evidence(&self, input: &TextMatcherInput, evidence: &mut Vec<String>) {
+        self.0.evidence(input, evidence)
+    }
+}
+
 impl CompileMatcher<TextMatcher> for TextPattern {
     fn compile(&self) -> Result<TextMatcher> {
         Ok(TextMatcher(Arc::new(TextMatcherInner::from(optimize(
@@ -322,4 +401,32 @@ mod test {
         let input = TextMatcherInput::from(&account);
         assert_eq!(input.hashtags, HashSet::from(["mastodev".to_string()]));
     }
+
+    #[test]
+    fn test_evidence() {
+        let pattern = TextPattern::Any {
+            any: vec![
+                TextPattern::Word {
+                    word: "casino".to_string(),
+                },
+                TextPattern::Link {
+                    link: LinkPattern::Domain {
+                        domain: "spam.test".to_string(),
+                    },
+                },
+                TextPattern::Word {
+                    word: "poker".to_string(),
+                },
+            ],
+        };
+        let matcher = pattern.compile().expect("Couldn't compile");
+
+        let input = TextMatcherInput::from("Best Casino bonuses at https://spam.test/win");
+        assert!(matcher.is_match(&input));
+
+        let mut evidence = vec![];
+        matcher.evidence(&input, &mut evidence);
+        evidence.sort();
+        assert_eq!(evidence, vec!["Casino", "https://spam.test/win"]);
+    }
 }
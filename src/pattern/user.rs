@@ -5,6 +5,7 @@ use crate::pattern::string::StringMatcher;
 use crate::pattern::{CompileMatcher, Matcher};
 use anyhow::Result;
 use mastodon_async::entities::{account::Account, mention::Mention};
+use std::fmt::{Display, Formatter};
 use std::rc::Rc;
 use std::sync::Arc;
 
@@ -114,6 +115,16 @@ impl From<&Account> for UserMatcherInput {
     }
 }
 
+/// Formats as `username` for local users and `username@domain` for remote users.
+impl Display for UserMatcherInput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.domain.as_ref() {
+            Some(domain) => write!(f, "{username}@{domain}", username = self.username),
+            None => f.write_str(&self.username),
+        }
+    }
+}
+
 impl Matcher<&UserMatcherInput> for UserMatcherInner {
     fn is_match(&self, input: &UserMatcherInput) -> bool {
         match self {
@@ -0,0 +1,458 @@
+use crate::checkpoint::Checkpoint;
+use crate::config::{PollSource, Polling};
+use crate::health::Health;
+use crate::interop::api::ApiClient;
+use crate::metrics::{EVENTS, EVENTS_DROPPED, POLL_FAILURES};
+use crate::{webhook, EVENT_CHANNEL_SIZE};
+use anyhow::{bail, Result};
+use mastodon_async::entities::status::Status;
+use mastodon_async::entities::{admin, AccountId};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::sync::broadcast;
+use tokio::time::MissedTickBehavior;
+use tracing::{error, info};
+
+/// Page size for polled lists. Small enough that a page from every source fits in an event channel at once.
+const POLL_PAGE_SIZE: usize = 40;
+
+/// How long to wait for a handler that's behind to make room in its event channel.
+const CHANNEL_FULL_PAUSE: Duration = Duration::from_secs(1);
+
+/// Everything polling needs for one domain user.
+pub struct PollTarget {
+    pub api: ApiClient,
+    pub config_dir: PathBuf,
+    pub domain: String,
+    pub username: String,
+    pub polling: Polling,
+    pub health: Health,
+}
+
+/// What polling has already seen, so that it doesn't send the same events twice, even across restarts.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct PollCheckpoint {
+    /// ID of the newest item seen from each source, by source name.
+    #[serde(default)]
+    since_ids: BTreeMap<String, String>,
+    /// Local accounts that were waiting for approval at the last poll.
+    #[serde(default)]
+    pending_account_ids: BTreeSet<String>,
+}
+
+impl PollCheckpoint {
+    fn path(config_dir: &Path, domain: &str, username: &str) -> PathBuf {
+        config_dir.join(domain).join(username).join("polling.json")
+    }
+}
+
+impl Checkpoint for PollCheckpoint {}
+
+enum PolledItem {
+    Account(Box<admin::Account>),
+    Report(Box<admin::Report>),
+    Status(Box<Status>),
+}
+
+impl PolledItem {
+    fn id(&self) -> String {
+        match self {
+            Self::Account(account) => account.id.to_string(),
+            Self::Report(report) => report.id.to_string(),
+            Self::Status(status) => status.id.to_string(),
+        }
+    }
+
+    /// The webhook event that Mastodon would have sent when this item was created.
+    fn into_event(self, created_at: OffsetDateTime) -> webhook::Event {
+        match self {
+            Self::Account(account) => webhook::Event::AccountCreated {
+                created_at,
+                account: *account,
+            },
+            Self::Report(report) => webhook::Event::ReportCreated {
+                created_at,
+                report: *report,
+            },
+            Self::Status(status) => webhook::Event::StatusCreated {
+                created_at,
+                status: *status,
+            },
+        }
+    }
+}
+
+fn source_name(source: PollSource) -> &'static str {
+    match source {
+        PollSource::Accounts => "accounts",
+        PollSource::Reports => "reports",
+        PollSource::Public => "public",
+        PollSource::PublicLocal => "public:local",
+        PollSource::PublicRemote => "public:remote",
+    }
+}
+
+/// Poll a domain user's sources on an interval and send events for new items to `event_sender`.
+/// Saves a checkpoint after each source.
+/// Only returns if the checkpoint can't be loaded, or the user's event handler has stopped.
+pub async fn poll_events(
+    target: PollTarget,
+    event_sender: broadcast::Sender<webhook::Event>,
+) -> Result<()> {
+    let domain = &target.domain;
+    let username = &target.username;
+    let path = PollCheckpoint::path(&target.config_dir, domain, username);
+    let mut checkpoint = PollCheckpoint::load(&path)?;
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(target.polling.interval_secs.max(1)));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    info!(
+        "{username}@{domain}: Polling every {secs} seconds",
+        secs = target.polling.interval_secs
+    );
+    loop {
+        interval.tick().await;
+        let mut round = Ok(());
+        for source in target.polling.sources.iter().copied() {
+            if event_sender.receiver_count() == 0 {
+                bail!("Event handler has stopped");
+            }
+            let name = source_name(source);
+            if let Err(e) = poll(&target, source, &mut checkpoint, &event_sender).await {
+                error!("{username}@{domain}: Couldn't poll {name}: {e}");
+                POLL_FAILURES
+                    .with_label_values(&[domain, username, name])
+                    .inc();
+                round = Err(e);
+            }
+            if let Err(e) = checkpoint.save(&path) {
+                error!(
+                    "{username}@{domain}: Couldn't save polling checkpoint to {path}: {e}",
+                    path = path.to_string_lossy()
+                );
+            }
+        }
+        target
+            .health
+            .poll_result(&format!("{username}@{domain}"), &round);
+    }
+}
+
+/// Send events for everything from one source that's newer than the checkpoint.
+/// The first poll of a source only records where it is now. Use `m1000 backfill` for anything older.
+async fn poll(
+    target: &PollTarget,
+    source: PollSource,
+    checkpoint: &mut PollCheckpoint,
+    event_sender: &broadcast::Sender<webhook::Event>,
+) -> Result<()> {
+    let name = source_name(source);
+    if source == PollSource::Accounts {
+        poll_approvals(target, checkpoint, event_sender).await?;
+    }
+
+    let Some(mut since_id) = checkpoint.since_ids.get(name).cloned() else {
+        let newest = fetch(&target.api, source, vec![("limit", "1".to_string())]).await?;
+        // If there's nothing yet, then everything that shows up later is new.
+        let since_id = newest.first().map_or("0".to_string(), PolledItem::id);
+        checkpoint.since_ids.insert(name.to_string(), since_id);
+        return Ok(());
+    };
+
+    loop {
+        let query = vec![
+            ("min_id", since_id.clone()),
+            ("limit", POLL_PAGE_SIZE.to_string()),
+        ];
+        let page = fetch(&target.api, source, query).await?;
+        // Pages just after `min_id` are still newest first.
+        let Some(newest) = page.first() else {
+            break;
+        };
+        let newest_id = newest.id();
+        let last_page = page.len() < POLL_PAGE_SIZE;
+
+        wait_for_room(event_sender, page.len()).await?;
+        let created_at = OffsetDateTime::now_utc();
+        for item in page.into_iter().rev() {
+            send(target, event_sender, item.into_event(created_at));
+        }
+
+        checkpoint
+            .since_ids
+            .insert(name.to_string(), newest_id.clone());
+        since_id = newest_id;
+        if last_page {
+            break;
+        }
+    }
+    Ok(())
+}
+
+async fn fetch(
+    api: &ApiClient,
+    source: PollSource,
+    mut query: Vec<(&str, String)>,
+) -> Result<Vec<PolledItem>> {
+    let timeline = |local: bool, remote: bool| {
+        let mut query = query.clone();
+        query.push(("local", local.to_string()));
+        query.push(("remote", remote.to_string()));
+        query
+    };
+    Ok(match source {
+        PollSource::Accounts => {
+            query.push(("local", "true".to_string()));
+            api.admin_accounts(&query)
+                .await?
+                .into_iter()
+                .map(|account| PolledItem::Account(Box::new(account)))
+                .collect()
+        }
+        PollSource::Reports => api
+            .admin_reports(&query)
+            .await?
+            .into_iter()
+            .map(|report| PolledItem::Report(Box::new(report)))
+            .collect(),
+        PollSource::Public | PollSource::PublicLocal | PollSource::PublicRemote => {
+            let query = timeline(
+                source == PollSource::PublicLocal,
+                source == PollSource::PublicRemote,
+            );
+            api.statuses("/api/v1/timelines/public", &query)
+                .await?
+                .into_iter()
+                .map(|status| PolledItem::Status(Box::new(status)))
+                .collect()
+        }
+    })
+}
+
+/// Send `account.approved` events for accounts that were pending at the last poll and have since been approved.
+async fn poll_approvals(
+    target: &PollTarget,
+    checkpoint: &mut PollCheckpoint,
+    event_sender: &broadcast::Sender<webhook::Event>,
+) -> Result<()> {
+    let domain = &target.domain;
+    let username = &target.username;
+    let mut pending_account_ids = BTreeSet::new();
+    let mut max_id: Option<String> = None;
+    loop {
+        let mut query = vec![
+            ("local", "true".to_string()),
+            ("pending", "true".to_string()),
+            ("limit", POLL_PAGE_SIZE.to_string()),
+        ];
+        if let Some(max_id) = max_id.as_ref() {
+            query.push(("max_id", max_id.clone()));
+        }
+        let page = target.api.admin_accounts(&query).await?;
+        let last_page = page.len() < POLL_PAGE_SIZE;
+        max_id = page.last().map(|account| account.id.to_string());
+        pending_account_ids.extend(page.into_iter().map(|account| account.id.to_string()));
+        if last_page || max_id.is_none() {
+            break;
+        }
+    }
+
+    let previous = std::mem::replace(&mut checkpoint.pending_account_ids, pending_account_ids);
+    for account_id in no_longer_pending(&previous, &checkpoint.pending_account_ids) {
+        match target.api.admin_account(&AccountId::new(&account_id)).await {
+            Ok(account) if account.approved => {
+                wait_for_room(event_sender, 1).await?;
+                send(
+                    target,
+                    event_sender,
+                    webhook::Event::AccountApproved {
+                        created_at: OffsetDateTime::now_utc(),
+                        account,
+                    },
+                );
+            }
+            Ok(_) => {}
+            // Rejecting an account deletes it.
+            Err(e) => info!(
+                "{username}@{domain}: Account {account_id} is no longer pending, probably rejected: {e}"
+            ),
+        }
+    }
+    Ok(())
+}
+
+fn no_longer_pending(previous: &BTreeSet<String>, current: &BTreeSet<String>) -> Vec<String> {
+    previous.difference(current).cloned().collect()
+}
+
+/// Wait until the event channel has room for `count` more events,
+/// so that a handler that's behind doesn't have to skip any.
+/// Fails if the handler has stopped, since nothing would handle the events.
+async fn wait_for_room(
+    event_sender: &broadcast::Sender<webhook::Event>,
+    count: usize,
+) -> Result<()> {
+    loop {
+        if event_sender.receiver_count() == 0 {
+            bail!("Event handler has stopped");
+        }
+        if event_sender.len() + count <= EVENT_CHANNEL_SIZE {
+            return Ok(());
+        }
+        tokio::time::sleep(CHANNEL_FULL_PAUSE).await;
+    }
+}
+
+fn send(
+    target: &PollTarget,
+    event_sender: &broadcast::Sender<webhook::Event>,
+    event: webhook::Event,
+) {
+    let domain = &target.domain;
+    let username = &target.username;
+    EVENTS.with_label_values(&[event.name(), domain]).inc();
+    if let Err(e) = event_sender.send(event) {
+        error!("{username}@{domain}: Channel error: {e}");
+        EVENTS_DROPPED.with_label_values(&[domain]).inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::example::placeholder_status;
+    use crate::test_util::{block_on, serve_stub, TempDir};
+    use axum::extract::Query;
+    use axum::routing::get;
+    use axum::{Json, Router};
+    use mastodon_async::Mastodon;
+    use reqwest::Client;
+    use std::collections::HashMap;
+
+    /// Stand-in for a public timeline with statuses numbered from 1 to `newest`.
+    /// Pages newest first, like Mastodon, whether they start from the newest status or just after `min_id`.
+    async fn serve_public_timeline(newest: u64) -> String {
+        let app = Router::new().route(
+            "/api/v1/timelines/public",
+            get(
+                move |Query(query): Query<HashMap<String, String>>| async move {
+                    let limit = query["limit"].parse::<usize>().unwrap();
+                    let ids: Vec<u64> = match query.get("min_id") {
+                        Some(min_id) => {
+                            let min_id = min_id.parse::<u64>().unwrap();
+                            let mut ids: Vec<u64> = (min_id + 1..=newest).take(limit).collect();
+                            ids.reverse();
+                            ids
+                        }
+                        None => (1..=newest).rev().take(limit).collect(),
+                    };
+                    Json(
+                        ids.into_iter()
+                            .map(|id| {
+                                let mut status = placeholder_status();
+                                status["id"] = id.to_string().into();
+                                status
+                            })
+                            .collect::<Vec<_>>(),
+                    )
+                },
+            ),
+        );
+        serve_stub(app).await
+    }
+
+    #[test]
+    fn test_poll() {
+        block_on(async {
+            let mastodon = Mastodon::from(mastodon_async::data::Data {
+                base: serve_public_timeline(50).await.into(),
+                client_id: "".into(),
+                client_secret: "".into(),
+                redirect: "".into(),
+                token: "token".into(),
+            });
+            let target = PollTarget {
+                api: ApiClient::new(&Client::new(), &mastodon),
+                config_dir: PathBuf::new(),
+                domain: "example.test".to_string(),
+                username: "automod".to_string(),
+                polling: Polling {
+                    sources: vec![PollSource::Public],
+                    interval_secs: 60,
+                },
+                health: Health::default(),
+            };
+            let (event_sender, mut event_receiver) = broadcast::channel(EVENT_CHANNEL_SIZE);
+
+            // The first poll only records where the timeline is now.
+            let mut checkpoint = PollCheckpoint::default();
+            poll(&target, PollSource::Public, &mut checkpoint, &event_sender)
+                .await
+                .unwrap();
+            assert_eq!("50", checkpoint.since_ids["public"]);
+            assert!(event_receiver.try_recv().is_err());
+
+            // Later polls page through everything newer, oldest first.
+            checkpoint
+                .since_ids
+                .insert("public".to_string(), "5".to_string());
+            poll(&target, PollSource::Public, &mut checkpoint, &event_sender)
+                .await
+                .unwrap();
+            assert_eq!("50", checkpoint.since_ids["public"]);
+            for id in 6..=50 {
+                let event = event_receiver.try_recv().unwrap();
+                assert_eq!("status.created", event.name());
+                assert_eq!(Some(id.to_string()), event.object_id());
+            }
+            assert!(event_receiver.try_recv().is_err());
+        });
+    }
+
+    #[test]
+    fn test_wait_for_room() {
+        block_on(async {
+            let (event_sender, event_receiver) = broadcast::channel(EVENT_CHANNEL_SIZE);
+            for _ in 0..EVENT_CHANNEL_SIZE {
+                event_sender.send(webhook::Event::Unknown).unwrap();
+            }
+            wait_for_room(&event_sender, 0).await.unwrap();
+
+            drop(event_receiver);
+            assert!(wait_for_room(&event_sender, 1).await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let config_dir = TempDir::new("polling");
+        let path = PollCheckpoint::path(&config_dir, "example.test", "automod");
+        assert_eq!(
+            PollCheckpoint::default(),
+            PollCheckpoint::load(&path).unwrap()
+        );
+
+        let checkpoint = PollCheckpoint {
+            since_ids: BTreeMap::from([
+                ("accounts".to_string(), "110000000000000001".to_string()),
+                ("public:remote".to_string(), "0".to_string()),
+            ]),
+            pending_account_ids: BTreeSet::from(["110000000000000002".to_string()]),
+        };
+        checkpoint.save(&path).unwrap();
+        assert_eq!(checkpoint, PollCheckpoint::load(&path).unwrap());
+    }
+
+    #[test]
+    fn test_no_longer_pending() {
+        let previous = BTreeSet::from(["1".to_string(), "2".to_string(), "3".to_string()]);
+        let current = BTreeSet::from(["3".to_string(), "4".to_string()]);
+        assert_eq!(
+            vec!["1".to_string(), "2".to_string()],
+            no_longer_pending(&previous, &current)
+        );
+    }
+}
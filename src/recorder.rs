@@ -0,0 +1,391 @@
+use crate::config::{StoredPerDomain, Webhook};
+use crate::health::Health;
+use crate::setup::{ensure_registered, ensure_settings};
+use crate::websub::XHubSignature;
+use crate::{handle_events, init_user_handler, webhook};
+use anyhow::{bail, Result};
+use axum::http::HeaderMap;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{create_dir_all, read_dir, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::sync::{broadcast, Mutex};
+use tracing::{error, info};
+
+/// Headers that could let someone forge or replay requests. Recordings get a placeholder instead.
+const REDACTED_HEADERS: &[&str] = &[
+    "authorization",
+    "cookie",
+    "proxy-authorization",
+    "x-hub-signature",
+];
+
+const REDACTED: &str = "[redacted]";
+
+/// One incoming webhook request, as received.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    #[serde(with = "time::serde::rfc3339")]
+    pub received_at: OffsetDateTime,
+    /// Domain whose webhook secret matched the request's signature.
+    pub domain: String,
+    /// Lowercase header names. Repeated headers are joined with commas.
+    pub headers: BTreeMap<String, String>,
+    pub body: String,
+}
+
+impl RecordedRequest {
+    fn new(domain: &str, headers: &HeaderMap, body: &[u8]) -> Self {
+        let mut recorded_headers = BTreeMap::<String, String>::new();
+        for (name, value) in headers.iter() {
+            let name = name.as_str().to_lowercase();
+            let value = if REDACTED_HEADERS.contains(&name.as_str()) {
+                REDACTED.to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).to_string()
+            };
+            recorded_headers
+                .entry(name)
+                .and_modify(|existing| {
+                    existing.push_str(", ");
+                    existing.push_str(&value);
+                })
+                .or_insert(value);
+        }
+        Self {
+            received_at: OffsetDateTime::now_utc(),
+            domain: domain.to_string(),
+            headers: recorded_headers,
+            body: String::from_utf8_lossy(body).to_string(),
+        }
+    }
+
+    /// Webhook event type from the body, such as `status.created`.
+    fn event_name(&self) -> Option<String> {
+        serde_json::from_str::<Value>(&self.body)
+            .ok()?
+            .get("event")?
+            .as_str()
+            .map(str::to_string)
+    }
+}
+
+/// Saves incoming webhook requests to JSONL files in a directory, one per day.
+/// Recordings contain personal data, such as new accounts' email and IP addresses,
+/// so they're only readable by their owner.
+#[derive(Clone, Debug)]
+pub struct Recorder {
+    dir: PathBuf,
+    lock: Arc<Mutex<()>>,
+}
+
+impl Recorder {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            lock: Default::default(),
+        }
+    }
+
+    /// Save a request. Logs errors instead of returning them,
+    /// since recording shouldn't get in the way of handling the request.
+    pub async fn record(&self, domain: &str, headers: &HeaderMap, body: &[u8]) {
+        let _lock = self.lock.lock().await;
+        if let Err(e) = self.append(&RecordedRequest::new(domain, headers, body)) {
+            error!(
+                "{domain}: Couldn't record webhook request to {dir}: {e}",
+                dir = self.dir.to_string_lossy()
+            );
+        }
+    }
+
+    fn append(&self, request: &RecordedRequest) -> Result<()> {
+        create_dir_all(&self.dir)?;
+        let date = request.received_at.date();
+        let path = self.dir.join(format!("webhooks-{date}.jsonl"));
+        let mut line = serde_json::to_string(request)?;
+        line.push('\n');
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        // Recordings have whole posts and accounts, including signup emails and IP addresses.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path)?;
+        file.write_all(line.as_bytes())?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/// Read all recordings in a directory, oldest first.
+fn read_recordings(dir: &Path) -> Result<Vec<RecordedRequest>> {
+    let mut requests = vec![];
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some("jsonl") {
+            continue;
+        }
+        for line in BufReader::new(File::open(&path)?).lines() {
+            // Skip lines that don't parse, such as a partial line from a crash.
+            if let Ok(request) = serde_json::from_str(&line?) {
+                requests.push(request);
+            }
+        }
+    }
+    requests.sort_by_key(|request: &RecordedRequest| request.received_at);
+    Ok(requests)
+}
+
+/// Where `m1000 replay` sends recorded events.
+pub enum ReplayTarget {
+    /// POST them to a running server's webhook endpoint, or the first listen address if `None`.
+    Server { url: Option<String> },
+    /// Handle them in this process as one domain user.
+    InProcess { username: String, shadow: bool },
+}
+
+/// Which recorded events to replay, how fast, and where to.
+pub struct ReplayOptions {
+    /// Only replay these event types, or all if empty.
+    pub events: Vec<String>,
+    /// Only replay events for this domain.
+    pub domain: Option<String>,
+    /// Playback speed relative to the recording. 0 means no pauses at all.
+    pub speed: f64,
+    pub target: ReplayTarget,
+}
+
+/// Replay recorded webhook requests, keeping their original spacing adjusted by the speed factor.
+pub async fn replay_recordings(
+    config_dir: &Path,
+    client: &Client,
+    dir: &Path,
+    options: &ReplayOptions,
+) -> Result<()> {
+    let recordings = read_recordings(dir)?
+        .into_iter()
+        .filter(|recording| {
+            options.domain.is_none() || options.domain.as_ref() == Some(&recording.domain)
+        })
+        .filter(|recording| {
+            options.events.is_empty()
+                || recording
+                    .event_name()
+                    .is_some_and(|name| options.events.contains(&name))
+        })
+        .collect::<Vec<_>>();
+    info!(
+        "Replaying {count} recorded webhook events from {dir}",
+        count = recordings.len(),
+        dir = dir.to_string_lossy()
+    );
+
+    match &options.target {
+        ReplayTarget::Server { url } => {
+            let url = match url {
+                Some(url) => url.clone(),
+                None => {
+                    let settings = ensure_settings(config_dir)?;
+                    format!("http://{addr}/webhook", addr = settings.local_addr()?)
+                }
+            };
+            let mut secrets = HashMap::<String, Vec<u8>>::new();
+            let mut failed = 0;
+            let mut previous = None;
+            for recording in recordings.iter() {
+                pause(previous, recording.received_at, options.speed).await;
+                previous = Some(recording.received_at);
+
+                let secret = match secrets.get(&recording.domain) {
+                    Some(secret) => secret,
+                    None => {
+                        let webhook = Webhook::load(config_dir, &recording.domain)?;
+                        secrets
+                            .entry(recording.domain.clone())
+                            .or_insert(webhook.secret.resolve()?.into_bytes())
+                    }
+                };
+                let body = refresh_created_at(&recording.body)?;
+                let signature = XHubSignature::sign(secret, body.as_bytes());
+                let status = match client
+                    .post(&url)
+                    .header(CONTENT_TYPE, "application/json")
+                    .header("X-Hub-Signature", signature.header_value())
+                    .body(body)
+                    .send()
+                    .await
+                {
+                    Ok(response) => response.status(),
+                    Err(e) => {
+                        // The server might be restarting, so keep going with the rest.
+                        error!(
+                            "{domain}: Couldn't replay {name} event recorded at {received_at}: {e}",
+                            domain = recording.domain,
+                            name = recording.event_name().unwrap_or_default(),
+                            received_at = recording.received_at,
+                        );
+                        failed += 1;
+                        continue;
+                    }
+                };
+                info!(
+                    "{domain}: Replayed {name} event recorded at {received_at}: {status}",
+                    domain = recording.domain,
+                    name = recording.event_name().unwrap_or_default(),
+                    received_at = recording.received_at,
+                );
+            }
+            if failed > 0 {
+                error!(
+                    "Couldn't send {failed} of {count} recorded webhook events to {url}",
+                    count = recordings.len()
+                );
+            }
+        }
+        ReplayTarget::InProcess { username, shadow } => {
+            let Some(domain) = options.domain.as_ref() else {
+                bail!("Replaying in-process needs a domain");
+            };
+            let settings = ensure_settings(config_dir)?;
+            let registered = ensure_registered(config_dir, client, domain, None, None).await?;
+            let mut handler = init_user_handler(
+                config_dir,
+                client,
+                &settings,
+                registered,
+                domain,
+                username,
+                &Health::default(),
+            )
+            .await?;
+            handler.shadow = *shadow;
+
+            // Big enough that we never fall behind, even without pauses.
+            let (event_sender, event_receiver) =
+                broadcast::channel::<webhook::Event>(recordings.len().max(1));
+            let join_handle = tokio::spawn(handle_events(event_receiver, handler));
+            let mut previous = None;
+            for recording in recordings.iter() {
+                pause(previous, recording.received_at, options.speed).await;
+                previous = Some(recording.received_at);
+                match serde_json::from_str::<webhook::Event>(&recording.body) {
+                    Ok(event) => {
+                        event_sender.send(event)?;
+                    }
+                    Err(e) => error!(
+                        "{username}@{domain}: Couldn't decode event recorded at {received_at}: {e}",
+                        received_at = recording.received_at
+                    ),
+                }
+            }
+            // Closing the channel lets the handler finish what it has and exit.
+            drop(event_sender);
+            join_handle.await??;
+        }
+    }
+    Ok(())
+}
+
+/// Wait between replayed events for as long as between the original events, divided by the speed factor.
+async fn pause(previous: Option<OffsetDateTime>, current: OffsetDateTime, speed: f64) {
+    let delay = replay_delay(previous, current, speed);
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+}
+
+fn replay_delay(previous: Option<OffsetDateTime>, current: OffsetDateTime, speed: f64) -> Duration {
+    let Some(previous) = previous else {
+        return Duration::ZERO;
+    };
+    if speed <= 0.0 || current <= previous {
+        return Duration::ZERO;
+    }
+    Duration::from_secs_f64((current - previous).as_seconds_f64() / speed)
+}
+
+/// Set an event's `created_at` to now so that the server's replay protection accepts it.
+fn refresh_created_at(body: &str) -> Result<String> {
+    let mut event: Value = serde_json::from_str(body)?;
+    if let Some(object) = event.as_object_mut() {
+        object.insert(
+            "created_at".to_string(),
+            OffsetDateTime::now_utc().format(&Rfc3339)?.into(),
+        );
+    }
+    Ok(serde_json::to_string(&event)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use axum::headers::Header;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_record() {
+        let dir = TempDir::new("recorder");
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Hub-Signature", HeaderValue::from_static("sha256=00"));
+        headers.insert("Content-Type", HeaderValue::from_static("application/json"));
+        let body = br#"{"event":"status.created","created_at":"2023-05-02T12:00:00Z","object":{}}"#;
+
+        let request = RecordedRequest::new("example.test", &headers, body);
+        assert_eq!(REDACTED, request.headers["x-hub-signature"]);
+        assert_eq!("application/json", request.headers["content-type"]);
+        assert_eq!(Some("status.created".to_string()), request.event_name());
+
+        let recorder = Recorder::new(&dir);
+        recorder.append(&request).unwrap();
+        assert_eq!(vec![request.clone()], read_recordings(&dir).unwrap());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let date = request.received_at.date();
+            let path = dir.join(format!("webhooks-{date}.jsonl"));
+            let mode = std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(0o600, mode);
+        }
+    }
+
+    #[test]
+    fn test_replay_signature() {
+        let body =
+            refresh_created_at(r#"{"event":"status.created","created_at":"2023-05-02T12:00:00Z"}"#)
+                .unwrap();
+        assert!(!body.contains("2023-05-02"));
+
+        let header_value =
+            HeaderValue::from_str(&XHubSignature::sign(b"secret", body.as_bytes()).header_value())
+                .unwrap();
+        let signature = XHubSignature::decode(&mut [&header_value].into_iter()).unwrap();
+        assert!(signature.is_valid(b"secret", body.as_bytes()));
+        assert!(!signature.is_valid(b"other secret", body.as_bytes()));
+    }
+
+    #[test]
+    fn test_replay_delay() {
+        let start = OffsetDateTime::UNIX_EPOCH;
+        let later = start + Duration::from_secs(10);
+        assert_eq!(Duration::ZERO, replay_delay(None, later, 1.0));
+        assert_eq!(
+            Duration::from_secs(5),
+            replay_delay(Some(start), later, 2.0)
+        );
+        assert_eq!(Duration::ZERO, replay_delay(Some(start), later, 0.0));
+        assert_eq!(Duration::ZERO, replay_delay(Some(later), start, 1.0));
+    }
+}
@@ -0,0 +1,313 @@
+use crate::config::ReplayProtection;
+use crate::webhook::Event;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::fs::{create_dir_all, rename, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+use tracing::error;
+
+/// Basename of the recently seen events file for one domain.
+const SEEN_EVENTS_BASENAME: &str = "seen_events";
+
+/// Refuses webhook events that are too old to be anything but a replay,
+/// and recognizes events that we've already accepted.
+#[derive(Clone, Debug)]
+pub struct ReplayGuard {
+    path: PathBuf,
+    settings: ReplayProtection,
+    seen: Arc<Mutex<SeenEvents>>,
+    /// One write to the seen events file at a time.
+    file_lock: Arc<std::sync::Mutex<()>>,
+}
+
+/// What to do with an incoming event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Admission {
+    /// Handle it.
+    Fresh,
+    /// Refuse it: it was created too long ago, or too far in the future.
+    Stale,
+    /// Acknowledge it without handling it again: it's a retry or replay of an event we already accepted.
+    Duplicate,
+}
+
+impl Admission {
+    /// Name for logs and metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Fresh => "fresh",
+            Self::Stale => "stale",
+            Self::Duplicate => "duplicate",
+        }
+    }
+}
+
+/// Identifies one webhook event. Mastodon sends the same body when it retries a delivery.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct SeenEvent {
+    event: String,
+    object_id: String,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+}
+
+/// Recently seen events, oldest first, with a set for fast lookup.
+#[derive(Debug, Default)]
+struct SeenEvents {
+    order: VecDeque<SeenEvent>,
+    set: HashSet<SeenEvent>,
+    /// Lines in the file, which is compacted when it gets too far ahead of `order`.
+    lines: usize,
+}
+
+impl SeenEvent {
+    /// Events without a timestamp or ID, which we don't handle anyway, can't be recognized.
+    fn from_event(event: &Event) -> Option<Self> {
+        Some(Self {
+            event: event.name().to_string(),
+            object_id: event.object_id()?,
+            created_at: event.created_at()?,
+        })
+    }
+}
+
+impl ReplayGuard {
+    /// Load recently seen events for a domain, forgetting any that would be stale by now.
+    pub fn load(
+        config_dir: &Path,
+        domain: &str,
+        settings: Option<&ReplayProtection>,
+    ) -> Result<Self> {
+        let path = config_dir
+            .join(domain)
+            .join(format!("{SEEN_EVENTS_BASENAME}.jsonl"));
+        let settings = settings.cloned().unwrap_or_default();
+
+        let mut seen = SeenEvents::default();
+        if path.exists() {
+            let oldest = OffsetDateTime::now_utc() - Duration::from_secs(settings.max_age_secs);
+            for line in BufReader::new(File::open(&path)?).lines() {
+                seen.lines += 1;
+                // Skip lines that don't parse, such as a partial line from a crash.
+                let Ok(seen_event) = serde_json::from_str::<SeenEvent>(&line?) else {
+                    continue;
+                };
+                if seen_event.created_at >= oldest {
+                    seen.insert(seen_event, settings.cache_size);
+                }
+            }
+        }
+
+        Ok(Self {
+            path,
+            settings,
+            seen: Arc::new(Mutex::new(seen)),
+            file_lock: Default::default(),
+        })
+    }
+
+    /// Decide whether to handle an event. Fresh events are remembered in memory,
+    /// so that a retry arriving meanwhile is a duplicate, but aren't saved until they're [`Self::accepted`].
+    /// Events without a timestamp or ID are always fresh.
+    pub async fn admit(&self, event: &Event) -> Admission {
+        let Some(seen_event) = SeenEvent::from_event(event) else {
+            return Admission::Fresh;
+        };
+        self.admit_seen(seen_event).await
+    }
+
+    /// Save a fresh event once it's been passed on to be handled, so that its retries are recognized even after a restart.
+    pub async fn accepted(&self, event: &Event) {
+        if let Some(seen_event) = SeenEvent::from_event(event) {
+            self.accepted_seen(seen_event).await;
+        }
+    }
+
+    /// Forget a fresh event that couldn't be passed on to be handled, so that Mastodon's retry of it is handled.
+    pub async fn forget(&self, event: &Event) {
+        if let Some(seen_event) = SeenEvent::from_event(event) {
+            self.seen.lock().await.remove(&seen_event);
+        }
+    }
+
+    async fn admit_seen(&self, seen_event: SeenEvent) -> Admission {
+        let age = OffsetDateTime::now_utc() - seen_event.created_at;
+        if age.unsigned_abs() > Duration::from_secs(self.settings.max_age_secs) {
+            return Admission::Stale;
+        }
+
+        let mut seen = self.seen.lock().await;
+        if seen.set.contains(&seen_event) {
+            return Admission::Duplicate;
+        }
+        seen.insert(seen_event, self.settings.cache_size);
+        Admission::Fresh
+    }
+
+    async fn accepted_seen(&self, seen_event: SeenEvent) {
+        // Rewrite the file instead of appending to it if it holds many evicted events.
+        let compacted = {
+            let mut seen = self.seen.lock().await;
+            if seen.lines >= 2 * self.settings.cache_size {
+                seen.lines = seen.order.len();
+                Some(seen.order.iter().cloned().collect::<Vec<_>>())
+            } else {
+                seen.lines += 1;
+                None
+            }
+        };
+
+        // Don't hold up other webhook requests while writing.
+        let path = self.path.clone();
+        let file_lock = self.file_lock.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let _file_lock = file_lock.lock().unwrap();
+            persist(&path, compacted.as_deref(), &seen_event)
+        })
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|result| result);
+        if let Err(e) = result {
+            // The event is still handled. The worst case is handling a retry twice after a restart.
+            error!(
+                "Couldn't write seen events to {path}: {e}",
+                path = self.path.to_string_lossy()
+            );
+        }
+    }
+}
+
+/// Append an event to the seen events file, or replace the file with `compacted` if there is one,
+/// which already includes the event.
+fn persist(path: &Path, compacted: Option<&[SeenEvent]>, seen_event: &SeenEvent) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir)?;
+    }
+    if let Some(compacted) = compacted {
+        let compacted_path = path.with_extension("jsonl.compacting");
+        let mut file = File::create(&compacted_path)?;
+        for seen_event in compacted {
+            writeln!(file, "{}", serde_json::to_string(seen_event)?)?;
+        }
+        file.flush()?;
+        rename(&compacted_path, path)?;
+        return Ok(());
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(seen_event)?)?;
+    file.flush()?;
+    Ok(())
+}
+
+impl SeenEvents {
+    /// Add an event to the in-memory cache, evicting the oldest if it's full.
+    fn insert(&mut self, seen_event: SeenEvent, capacity: usize) {
+        if !self.set.insert(seen_event.clone()) {
+            return;
+        }
+        self.order.push_back(seen_event);
+        while self.order.len() > capacity {
+            if let Some(evicted) = self.order.pop_front() {
+                self.set.remove(&evicted);
+            }
+        }
+    }
+
+    fn remove(&mut self, seen_event: &SeenEvent) {
+        if self.set.remove(seen_event) {
+            self.order.retain(|order_event| order_event != seen_event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{block_on, TempDir};
+
+    fn seen_event(id: &str, created_at: OffsetDateTime) -> SeenEvent {
+        SeenEvent {
+            event: "status.created".to_string(),
+            object_id: id.to_string(),
+            created_at,
+        }
+    }
+
+    #[test]
+    fn test_admit() {
+        let config_dir = TempDir::new("replay-guard");
+        let settings = ReplayProtection {
+            max_age_secs: 60,
+            cache_size: 2,
+        };
+        let now = OffsetDateTime::now_utc();
+
+        let guard = ReplayGuard::load(&config_dir, "example.test", Some(&settings)).unwrap();
+        block_on(async {
+            assert_eq!(
+                Admission::Fresh,
+                guard.admit_seen(seen_event("1", now)).await
+            );
+            // Until the first delivery is accepted or forgotten, a retry is a duplicate.
+            assert_eq!(
+                Admission::Duplicate,
+                guard.admit_seen(seen_event("1", now)).await
+            );
+            guard.accepted_seen(seen_event("1", now)).await;
+            assert_eq!(
+                Admission::Duplicate,
+                guard.admit_seen(seen_event("1", now)).await
+            );
+
+            // An event that couldn't be passed on is handled when it's retried.
+            let mut status = crate::example::placeholder_status();
+            status["id"] = "5".into();
+            let unsent = Event::StatusCreated {
+                created_at: now,
+                status: serde_json::from_value(status).unwrap(),
+            };
+            assert_eq!(Admission::Fresh, guard.admit(&unsent).await);
+            guard.forget(&unsent).await;
+            assert_eq!(Admission::Fresh, guard.admit(&unsent).await);
+            guard.forget(&unsent).await;
+
+            assert_eq!(
+                Admission::Stale,
+                guard
+                    .admit_seen(seen_event("2", now - Duration::from_secs(3600)))
+                    .await
+            );
+        });
+
+        // Seen events survive a restart.
+        let guard = ReplayGuard::load(&config_dir, "example.test", Some(&settings)).unwrap();
+        block_on(async {
+            assert_eq!(
+                Admission::Duplicate,
+                guard.admit_seen(seen_event("1", now)).await
+            );
+            assert_eq!(
+                Admission::Fresh,
+                guard.admit_seen(seen_event("3", now)).await
+            );
+            guard.accepted_seen(seen_event("3", now)).await;
+            // Evicts event 1.
+            assert_eq!(
+                Admission::Fresh,
+                guard.admit_seen(seen_event("4", now)).await
+            );
+            guard.accepted_seen(seen_event("4", now)).await;
+            assert_eq!(
+                Admission::Fresh,
+                guard.admit_seen(seen_event("1", now)).await
+            );
+        });
+    }
+}
@@ -1,29 +1,131 @@
 use crate::config::*;
 use anyhow::{bail, Result};
+use clap::Args;
 use mastodon_async::data::Data;
 use mastodon_async::entities::auth::Scopes;
 use mastodon_async::mastodon::Mastodon;
 use mastodon_async::registration::{Registered, Registration};
 use reqwest::Client;
+use std::io::IsTerminal;
 use std::net::{IpAddr, Ipv6Addr, SocketAddr};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str::FromStr;
 use tracing::info;
 
-/// Run interactive setup for a single domain and username.
-pub async fn setup(config_dir: &Path, client: &Client, domain: &str, username: &str) -> Result<()> {
-    let _ = ensure_settings(config_dir);
-    let _ = ensure_webhook(config_dir, domain, true);
-    let registered = ensure_registered(config_dir, client, domain).await?;
-    let _ = ensure_mastodon(config_dir, registered, domain, username, true).await?;
+/// Secrets for `m1000 setup`, so that it can run without prompting, such as in a container.
+/// Each can be given directly or as the path of a file containing it, by flag or environment variable.
+/// A direct value takes precedence over a file.
+#[derive(Clone, Debug, Default, Args)]
+pub struct SetupArgs {
+    /// Webhook signing secret from the instance's webhook settings.
+    #[arg(long, env = "M1000_WEBHOOK_SECRET", hide_env_values = true)]
+    webhook_secret: Option<String>,
+    /// File containing the webhook signing secret.
+    #[arg(long, env = "M1000_WEBHOOK_SECRET_FILE")]
+    webhook_secret_file: Option<PathBuf>,
+    /// Client ID of an OAuth app already registered with the instance, instead of registering one.
+    #[arg(long, env = "M1000_CLIENT_ID")]
+    client_id: Option<String>,
+    /// Client secret of an OAuth app already registered with the instance.
+    #[arg(long, env = "M1000_CLIENT_SECRET", hide_env_values = true)]
+    client_secret: Option<String>,
+    /// File containing the OAuth app's client secret.
+    #[arg(long, env = "M1000_CLIENT_SECRET_FILE")]
+    client_secret_file: Option<PathBuf>,
+    /// Access token already issued for the bot user, instead of authorizing interactively.
+    /// Needs the same scopes that `m1000 setup` asks for.
+    #[arg(long, env = "M1000_ACCESS_TOKEN", hide_env_values = true)]
+    access_token: Option<String>,
+    /// File containing the bot user's access token.
+    #[arg(long, env = "M1000_ACCESS_TOKEN_FILE")]
+    access_token_file: Option<PathBuf>,
+    /// Fail instead of prompting for anything missing.
+    /// Setup never prompts if standard input isn't a terminal.
+    #[arg(long, env = "M1000_NON_INTERACTIVE")]
+    non_interactive: bool,
+}
+
+/// Secrets from [`SetupArgs`], with files read.
+#[derive(Clone, Debug, Default)]
+struct SetupSecrets {
+    webhook_secret: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    access_token: Option<String>,
+}
+
+impl SetupArgs {
+    fn secrets(&self) -> Result<SetupSecrets> {
+        Ok(SetupSecrets {
+            webhook_secret: read_secret(&self.webhook_secret, &self.webhook_secret_file)?,
+            client_id: self.client_id.clone(),
+            client_secret: read_secret(&self.client_secret, &self.client_secret_file)?,
+            access_token: read_secret(&self.access_token, &self.access_token_file)?,
+        })
+    }
+
+    fn interactive(&self) -> bool {
+        !self.non_interactive && std::io::stdin().is_terminal()
+    }
+}
+
+/// Use a secret if given directly, or read it from a file, ignoring surrounding whitespace.
+fn read_secret(value: &Option<String>, file: &Option<PathBuf>) -> Result<Option<String>> {
+    let secret = match (value, file) {
+        (Some(value), _) => value.trim().to_string(),
+        (None, Some(file)) => std::fs::read_to_string(file)?.trim().to_string(),
+        (None, None) => return Ok(None),
+    };
+    if secret.is_empty() {
+        bail!("Empty secret");
+    }
+    Ok(Some(secret))
+}
+
+/// Run setup for a single domain and username.
+/// Safe to run again: anything already set up is kept unless a different secret is given for it.
+pub async fn setup(
+    config_dir: &Path,
+    client: &Client,
+    domain: &str,
+    username: &str,
+    args: &SetupArgs,
+) -> Result<()> {
+    let secrets = args.secrets()?;
+    let interactive = args.interactive();
+    ensure_settings(config_dir)?;
+    restrict_secret_files(config_dir, domain, username)?;
+    ensure_webhook(
+        config_dir,
+        domain,
+        secrets.webhook_secret.as_deref(),
+        interactive,
+    )?;
+    let registered = ensure_registered(
+        config_dir,
+        client,
+        domain,
+        secrets.client_id.as_deref(),
+        secrets.client_secret.as_deref(),
+    )
+    .await?;
+    let _ = ensure_mastodon(
+        config_dir,
+        registered,
+        domain,
+        username,
+        secrets.access_token.as_deref(),
+        interactive,
+    )
+    .await?;
     let _ = ensure_config(config_dir, domain, username).await?;
     Ok(())
 }
 
 /// Load settings or use and save defaults.
 pub fn ensure_settings(config_dir: &Path) -> Result<Settings> {
-    if let Ok(settings) = Settings::load(config_dir) {
+    if let Some(settings) = Settings::load_if_exists(config_dir)? {
         return Ok(settings);
     }
 
@@ -31,10 +133,20 @@ pub fn ensure_settings(config_dir: &Path) -> Result<Settings> {
         listen: vec![
             SocketAddr::new(IpAddr::from(Ipv6Addr::UNSPECIFIED), DEFAULT_PORT).to_string(),
         ],
+        metrics_listen: vec![],
         rspamd: find_rspamc().map(|rspamc_command| Rspamd {
             rspamc_command: Some(rspamc_command),
+            url: None,
+            password: None,
+            settings_id: None,
+            timeout_secs: Rspamd::default_timeout_secs(),
             learn_from_reports: false,
+            fuzzy_flag: None,
         }),
+        exempt: None,
+        audit: None,
+        replay_protection: None,
+        record: None,
     };
     settings.save(config_dir)?;
     info!(
@@ -62,29 +174,43 @@ fn find_rspamc() -> Option<Vec<String>> {
     None
 }
 
-/// Load an existing webhook signing secret if there is one,
-/// or prompt the user to provide one, then save it.
-pub fn ensure_webhook(config_dir: &Path, domain: &str, interactive: bool) -> Result<Webhook> {
-    if let Ok(webhook) = Webhook::load(config_dir, domain) {
-        return Ok(webhook);
+/// Load an existing webhook signing secret if there is one and no other secret is given,
+/// or use the given one or prompt the user to provide one, then save it.
+pub fn ensure_webhook(
+    config_dir: &Path,
+    domain: &str,
+    secret: Option<&str>,
+    interactive: bool,
+) -> Result<Webhook> {
+    if let Some(webhook) = Webhook::load_if_exists(config_dir, domain)? {
+        if secret.is_none_or(|secret| {
+            webhook
+                .secret
+                .resolve()
+                .is_ok_and(|stored| stored == secret)
+        }) {
+            return Ok(webhook);
+        }
     }
 
-    if !interactive {
+    let secret = if let Some(secret) = secret {
+        secret.to_string()
+    } else if interactive {
+        let mut secret = String::new();
+        println!("Webhook signing secret for {domain}:");
+        let _ = std::io::stdin().read_line(&mut secret)?;
+        secret.trim().to_string()
+    } else {
         bail!(
             "You need to configure a webhook for {domain}. \
             Run `{client_name} setup` to finish setup.",
             client_name = CLIENT_NAME
         );
-    }
-
-    let mut secret = String::new();
-    println!("Webhook signing secret for {domain}:");
-    let _ = std::io::stdin().read_line(&mut secret)?;
-    secret = secret.trim().to_string();
+    };
 
     let webhook = Webhook {
         domain: domain.to_string(),
-        secret,
+        secret: secret.into(),
     };
     webhook.save(config_dir)?;
     info!(
@@ -95,23 +221,54 @@ pub fn ensure_webhook(config_dir: &Path, domain: &str, interactive: bool) -> Res
     Ok(webhook)
 }
 
-/// Load an existing registered app if there is one, or register and save a new one.
+/// Load an existing registered app if there is one and no other app is given,
+/// or use the given app or register a new one, then save it.
 pub async fn ensure_registered(
     config_dir: &Path,
     client: &Client,
     domain: &str,
+    client_id: Option<&str>,
+    client_secret: Option<&str>,
 ) -> Result<Registered> {
     let base = format!("https://{domain}");
-
-    if let Ok(app) = App::load(config_dir, domain) {
-        return Ok(Registered::from_parts(
+    let registered_from_parts = |client_id: &str, client_secret: &str| -> Result<Registered> {
+        Ok(Registered::from_parts(
             &base,
-            &app.client_id,
-            &app.client_secret,
+            client_id,
+            client_secret,
             OOB_REDIRECT_URL,
             Scopes::from_str(REQUIRED_SCOPES.join(" ").as_str())?,
             false,
-        ));
+        ))
+    };
+
+    let given = match (client_id, client_secret) {
+        (Some(client_id), Some(client_secret)) => Some((client_id, client_secret)),
+        (None, None) => None,
+        _ => bail!("An OAuth app's client ID and client secret must be given together"),
+    };
+
+    if let Some(app) = App::load_if_exists(config_dir, domain)? {
+        let client_secret = app.client_secret.resolve()?;
+        if given.is_none_or(|given| given == (app.client_id.as_str(), client_secret.as_str())) {
+            return registered_from_parts(&app.client_id, &client_secret);
+        }
+    }
+
+    if let Some((client_id, client_secret)) = given {
+        let app = App {
+            domain: domain.to_string(),
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string().into(),
+            scopes: Scopes::from_str(&REQUIRED_SCOPES.join(" "))?,
+        };
+        app.save(config_dir)?;
+        info!(
+            "OAuth application for {client_name} saved for {domain}: {path}",
+            client_name = CLIENT_NAME,
+            path = app.path(config_dir,).to_string_lossy(),
+        );
+        return registered_from_parts(client_id, client_secret);
     }
 
     let registered = Registration::new_with_client(base, client.clone())
@@ -126,7 +283,7 @@ pub async fn ensure_registered(
     let app = App {
         domain: domain.to_string(),
         client_id,
-        client_secret,
+        client_secret: client_secret.into(),
         scopes,
     };
     app.save(config_dir)?;
@@ -139,24 +296,45 @@ pub async fn ensure_registered(
     Ok(registered)
 }
 
-/// Load an existing access token if there is one,
-/// or prompt the user to authenticate and get one, then save it.
+/// Load an existing access token if there is one and no other token is given,
+/// or verify the given token or prompt the user to authenticate and get one, then save it.
 pub async fn ensure_mastodon(
     config_dir: &Path,
     registered: Registered,
     domain: &str,
     username: &str,
+    access_token: Option<&str>,
     interactive: bool,
 ) -> Result<Mastodon> {
-    if let Ok(credentials) = Credentials::load(config_dir, &domain.clone(), &username.clone()) {
+    let mastodon_from_token = |access_token: &str| {
         let (_, client_id, client_secret, _, _, _) = registered.clone().into_parts();
-        return Ok(Mastodon::from(Data {
+        Mastodon::from(Data {
             base: format!("https://{domain}").into(),
             client_id: client_id.into(),
             client_secret: client_secret.into(),
             redirect: OOB_REDIRECT_URL.into(),
-            token: credentials.access_token.into(),
-        }));
+            token: access_token.to_string().into(),
+        })
+    };
+
+    if let Some(credentials) = Credentials::load_if_exists(config_dir, domain, username)? {
+        let stored_access_token = credentials.access_token.resolve()?;
+        if access_token.is_none_or(|access_token| access_token == stored_access_token) {
+            return Ok(mastodon_from_token(&stored_access_token));
+        }
+    }
+
+    if let Some(access_token) = access_token {
+        let mastodon = mastodon_from_token(access_token);
+        let account = mastodon.verify_credentials().await?;
+        if account.acct != username {
+            bail!(
+                "Expected an access token for {username}@{domain}, got one for {acct} instead",
+                acct = account.acct
+            );
+        }
+        save_credentials(config_dir, domain, username, &mastodon)?;
+        return Ok(mastodon);
     }
 
     if !interactive {
@@ -192,38 +370,61 @@ pub async fn ensure_mastodon(
         );
     };
 
+    save_credentials(config_dir, domain, username, &mastodon)?;
+    Ok(mastodon)
+}
+
+fn save_credentials(
+    config_dir: &Path,
+    domain: &str,
+    username: &str,
+    mastodon: &Mastodon,
+) -> Result<()> {
     let credentials = Credentials {
         domain: domain.to_string(),
         username: username.to_string(),
-        access_token: mastodon.data.token.to_string(),
+        access_token: mastodon.data.token.to_string().into(),
     };
     credentials.save(config_dir)?;
     info!(
         "Access token saved for {username}@{domain}: {path}",
         path = credentials.path(config_dir,).to_string_lossy(),
     );
-
-    Ok(mastodon)
+    Ok(())
 }
 
 /// Load an existing config file if there is one,
 /// or create and save a demo config.
 pub async fn ensure_config(config_dir: &Path, domain: &str, username: &str) -> Result<Config> {
-    if let Ok(config) = Config::load(config_dir, domain, username) {
+    if let Some(config) = Config::load_if_exists(config_dir, domain, username)? {
         return Ok(config);
     }
 
     let config = Config {
         domain: domain.to_string(),
         username: username.to_string(),
+        exempt: Some(Exempt {
+            staff: true,
+            ..Default::default()
+        }),
+        domain_policy: None,
+        report_template: None,
+        concurrency: None,
+        webhooks: true,
+        streaming: None,
+        polling: None,
         rules: vec![Rule {
             name: "no orange website".to_string(),
             report: Some(Report {
                 rule_ids: vec![],
                 spam: false,
                 forward: false,
+                template: None,
             }),
             restrict: None,
+            registration: None,
+            block: None,
+            warn: None,
             patterns: vec![RulePattern::Post {
                 post: PostPattern::Text {
                     text: TextPattern::Link {
@@ -233,6 +434,7 @@ pub async fn ensure_config(config_dir: &Path, domain: &str, username: &str) -> R
                     },
                 },
             }],
+            examples: None,
         }],
     };
     config.save(config_dir)?;
@@ -243,3 +445,27 @@ pub async fn ensure_config(config_dir: &Path, domain: &str, username: &str) -> R
 
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_read_secret() {
+        let dir = TempDir::new("setup");
+        let path = dir.join("secret");
+        std::fs::write(&path, "file secret\n").unwrap();
+
+        assert_eq!(None, read_secret(&None, &None).unwrap());
+        assert_eq!(
+            Some("file secret".to_string()),
+            read_secret(&None, &Some(path.clone())).unwrap()
+        );
+        assert_eq!(
+            Some("flag secret".to_string()),
+            read_secret(&Some("flag secret".to_string()), &Some(path.clone())).unwrap()
+        );
+        assert!(read_secret(&Some(" ".to_string()), &None).is_err());
+    }
+}
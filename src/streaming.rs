@@ -0,0 +1,306 @@
+use crate::config::{NamedStream, Stream, Streaming};
+use crate::health::Health;
+use crate::interop::api::{backoff, ApiClient};
+use crate::metrics::{EVENTS, EVENTS_DROPPED, STREAM_RECONNECTS};
+use crate::webhook;
+use anyhow::{anyhow, Result};
+use futures::{SinkExt, StreamExt};
+use mastodon_async::entities::{AccountId, ReportId};
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::json;
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+use tokio::sync::broadcast;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::AUTHORIZATION;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{error, info, warn};
+
+/// Mastodon's streaming server pings every 30 seconds, so a connection this quiet is probably dead.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+
+/// A connection that stayed up at least this long resets the reconnect backoff.
+const STABLE_CONNECTION: Duration = Duration::from_secs(60);
+
+/// Everything a streaming connection needs for one domain user.
+pub struct StreamSource {
+    pub api: ApiClient,
+    pub token: String,
+    pub domain: String,
+    pub username: String,
+    pub streaming: Streaming,
+    pub health: Health,
+}
+
+/// A message from the streaming API. Most payloads are JSON encoded as a string.
+#[derive(Deserialize)]
+struct StreamMessage {
+    event: String,
+    #[serde(default)]
+    payload: Option<String>,
+}
+
+/// The parts of a notification entity that we need.
+#[derive(Deserialize)]
+struct NotificationSummary {
+    #[serde(rename = "type")]
+    kind: String,
+    account: EntityId,
+    #[serde(default)]
+    report: Option<EntityId>,
+}
+
+#[derive(Deserialize)]
+struct EntityId {
+    id: String,
+}
+
+/// Subscribe to a domain user's streams and send events from them to `event_sender`,
+/// reconnecting with backoff whenever the connection drops or can't be made. Never returns.
+pub async fn stream_events(
+    source: StreamSource,
+    event_sender: broadcast::Sender<webhook::Event>,
+) -> Result<()> {
+    let domain = &source.domain;
+    let username = &source.username;
+    let mut url = source.streaming.url.clone();
+    let mut attempt = 0;
+    loop {
+        let started_at = Instant::now();
+        let result = async {
+            let base = match url.as_ref() {
+                Some(base) => base.clone(),
+                None => url.insert(source.api.streaming_url().await?).clone(),
+            };
+            stream_once(&source, &base, &event_sender).await
+        }
+        .await;
+        match result.as_ref() {
+            Ok(()) => warn!("{username}@{domain}: Streaming API connection closed"),
+            Err(e) => error!("{username}@{domain}: Streaming API error: {e}"),
+        }
+        source.health.stream_result(
+            &format!("{username}@{domain}"),
+            &result.and_then(|()| Err::<(), _>(anyhow!("Connection closed"))),
+        );
+        STREAM_RECONNECTS
+            .with_label_values(&[domain, username])
+            .inc();
+
+        if started_at.elapsed() >= STABLE_CONNECTION {
+            attempt = 0;
+        }
+        attempt += 1;
+        let delay = backoff(attempt, rand::thread_rng().gen());
+        info!(
+            "{username}@{domain}: Reconnecting to streaming API in {secs:.1} seconds",
+            secs = delay.as_secs_f64()
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Connect to the streaming API, subscribe to streams, and handle messages until the connection ends.
+async fn stream_once(
+    source: &StreamSource,
+    base: &str,
+    event_sender: &broadcast::Sender<webhook::Event>,
+) -> Result<()> {
+    let domain = &source.domain;
+    let username = &source.username;
+    let mut request = format!("{base}/api/v1/streaming", base = base.trim_end_matches('/'))
+        .into_client_request()?;
+    request.headers_mut().insert(
+        AUTHORIZATION,
+        format!("Bearer {token}", token = source.token).parse()?,
+    );
+    let (mut socket, _) = tokio_tungstenite::connect_async(request).await?;
+    for stream in source.streaming.streams.iter() {
+        socket
+            .send(Message::Text(subscribe_message(stream).to_string()))
+            .await?;
+    }
+    info!("{username}@{domain}: Connected to streaming API");
+    source
+        .health
+        .stream_result(&format!("{username}@{domain}"), &Ok(()));
+
+    loop {
+        let message = tokio::time::timeout(IDLE_TIMEOUT, socket.next())
+            .await
+            .map_err(|_| anyhow!("No messages for {IDLE_TIMEOUT:?}"))?;
+        let text = match message {
+            None | Some(Ok(Message::Close(_))) => return Ok(()),
+            Some(Ok(Message::Text(text))) => text,
+            // Pings are answered automatically.
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e.into()),
+        };
+        let event = match to_event(&source.api, &text).await {
+            Ok(Some(event)) => event,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("{username}@{domain}: Couldn't handle streaming API message: {e}: {text}");
+                continue;
+            }
+        };
+        EVENTS.with_label_values(&[event.name(), domain]).inc();
+        if let Err(e) = event_sender.send(event) {
+            error!("{username}@{domain}: Channel error: {e}");
+            EVENTS_DROPPED.with_label_values(&[domain]).inc();
+        }
+    }
+}
+
+fn subscribe_message(stream: &Stream) -> serde_json::Value {
+    match stream {
+        Stream::Named(NamedStream::Public) => json!({"type": "subscribe", "stream": "public"}),
+        Stream::Named(NamedStream::PublicLocal) => {
+            json!({"type": "subscribe", "stream": "public:local"})
+        }
+        Stream::Named(NamedStream::PublicRemote) => {
+            json!({"type": "subscribe", "stream": "public:remote"})
+        }
+        // Admin notifications for new signups and reports go to the user's own notification stream.
+        Stream::Named(NamedStream::Admin) => {
+            json!({"type": "subscribe", "stream": "user:notification"})
+        }
+        Stream::Hashtag { hashtag } => {
+            json!({"type": "subscribe", "stream": "hashtag", "tag": hashtag})
+        }
+    }
+}
+
+/// Turn a streaming API message into the same event that a webhook would have sent,
+/// fetching the admin view of accounts and reports that notifications mention.
+/// Returns `None` for messages we don't handle, like deletions and other kinds of notification.
+async fn to_event(api: &ApiClient, text: &str) -> Result<Option<webhook::Event>> {
+    let message: StreamMessage = serde_json::from_str(text)?;
+    let Some(payload) = message.payload else {
+        return Ok(None);
+    };
+    let created_at = OffsetDateTime::now_utc();
+    Ok(match message.event.as_str() {
+        "update" => Some(webhook::Event::StatusCreated {
+            created_at,
+            status: serde_json::from_str(&payload)?,
+        }),
+        "status.update" => Some(webhook::Event::StatusUpdated {
+            created_at,
+            status: serde_json::from_str(&payload)?,
+        }),
+        "notification" => {
+            let notification: NotificationSummary = serde_json::from_str(&payload)?;
+            match (notification.kind.as_str(), notification.report) {
+                ("admin.sign_up", _) => Some(webhook::Event::AccountCreated {
+                    created_at,
+                    account: api
+                        .admin_account(&AccountId::new(notification.account.id))
+                        .await?,
+                }),
+                ("admin.report", Some(report)) => Some(webhook::Event::ReportCreated {
+                    created_at,
+                    report: api.admin_report(&ReportId::new(report.id)).await?,
+                }),
+                _ => None,
+            }
+        }
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::example::placeholder_status;
+    use crate::test_util::block_on;
+    use mastodon_async::Mastodon;
+    use reqwest::Client;
+    use tokio::net::TcpListener;
+
+    /// Stand-in for Mastodon's streaming server: checks the token and subscription,
+    /// sends one status and one message we don't handle, then closes the connection.
+    // The handshake callback's error type is set by tungstenite.
+    #[allow(clippy::result_large_err)]
+    async fn serve_one_connection(listener: TcpListener) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut socket = tokio_tungstenite::accept_hdr_async(
+            stream,
+            |request: &tokio_tungstenite::tungstenite::handshake::server::Request, response| {
+                assert_eq!(
+                    "Bearer token",
+                    request.headers().get(AUTHORIZATION).unwrap()
+                );
+                assert_eq!("/api/v1/streaming", request.uri().path());
+                Ok(response)
+            },
+        )
+        .await
+        .unwrap();
+
+        let Some(Ok(Message::Text(subscribe))) = socket.next().await else {
+            panic!("Expected a subscribe message");
+        };
+        assert_eq!(
+            json!({"type": "subscribe", "stream": "hashtag", "tag": "casino"}),
+            serde_json::from_str::<serde_json::Value>(&subscribe).unwrap()
+        );
+
+        let messages = [
+            json!({"stream": ["hashtag", "casino"], "event": "delete", "payload": "2"}),
+            json!({
+                "stream": ["hashtag", "casino"],
+                "event": "update",
+                "payload": placeholder_status().to_string(),
+            }),
+        ];
+        for message in messages {
+            socket
+                .send(Message::Text(message.to_string()))
+                .await
+                .unwrap();
+        }
+        socket.close(None).await.unwrap();
+    }
+
+    #[test]
+    fn test_stream_once() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = tokio::spawn(serve_one_connection(listener));
+
+            let mastodon = Mastodon::from(mastodon_async::data::Data {
+                base: "https://example.test".into(),
+                client_id: "".into(),
+                client_secret: "".into(),
+                redirect: "".into(),
+                token: "token".into(),
+            });
+            let source = StreamSource {
+                api: ApiClient::new(&Client::new(), &mastodon),
+                token: "token".to_string(),
+                domain: "example.test".to_string(),
+                username: "automod".to_string(),
+                streaming: Streaming {
+                    streams: vec![Stream::Hashtag {
+                        hashtag: "casino".to_string(),
+                    }],
+                    url: None,
+                },
+                health: Health::default(),
+            };
+            let (event_sender, mut event_receiver) = broadcast::channel(16);
+            stream_once(&source, &format!("ws://{addr}"), &event_sender)
+                .await
+                .unwrap();
+            server.await.unwrap();
+
+            let event = event_receiver.try_recv().unwrap();
+            assert_eq!("status.created", event.name());
+            assert_eq!(Some("1".to_string()), event.object_id());
+            assert!(event_receiver.try_recv().is_err());
+        });
+    }
+}
@@ -0,0 +1,43 @@
+use anyhow::{Context, Result};
+use minijinja::Environment;
+use serde::Serialize;
+
+/// Check a template for syntax errors, so we can catch them at startup
+/// instead of when we try to use the template.
+pub fn validate(source: &str, what: &str) -> Result<()> {
+    Environment::new()
+        .template_from_str(source)
+        .with_context(|| format!("Invalid template for {what}"))?;
+    Ok(())
+}
+
+/// Render a template with a serializable context.
+pub fn render<S: Serialize>(source: &str, context: S) -> Result<String> {
+    Ok(Environment::new().render_str(source, context)?)
+}
+
+/// Shorten text to at most `max_chars` characters, ending with an ellipsis if anything was cut.
+/// Mastodon counts characters, not bytes.
+pub fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let mut truncated = text
+        .chars()
+        .take(max_chars.saturating_sub(1))
+        .collect::<String>();
+    truncated.push('…');
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!("short", truncate("short", 5));
+        assert_eq!("shor…", truncate("shorter", 5));
+        assert_eq!("ééé…", truncate("éééééé", 4));
+    }
+}
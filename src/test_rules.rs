@@ -0,0 +1,307 @@
+use crate::config::Config;
+use crate::event::action::rule_evidence;
+use crate::interop::rspamd::RspamdResult;
+use crate::pattern::{Matcher, RuleMatcherInput};
+use crate::webhook::Event;
+use crate::CompiledConfig;
+use anyhow::{anyhow, Result};
+use mastodon_async::entities::admin;
+use mastodon_async::entities::status::Status;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// Corpus items may have this key listing the names of the rules that should match them.
+/// Items with the key are labeled, even if the list is empty.
+const EXPECTED_RULES_KEY: &str = "expected_rules";
+
+/// Corpus items may have this key to stand in for the `rspamd` action, since we don't scan offline.
+const RSPAMD_ACTION_KEY: &str = "rspamd_action";
+
+/// Corpus items may have this key to stand in for a whole `rspamd` result, with score and symbols.
+const RSPAMD_RESULT_KEY: &str = "rspamd_result";
+
+/// One status or account from a corpus file.
+struct CorpusItem {
+    /// 1-based line number in the corpus file.
+    line: usize,
+    description: String,
+    input: RuleMatcherInput,
+    expected: Option<BTreeSet<String>>,
+}
+
+/// How one rule did against the corpus.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct RuleStats {
+    /// Matches on all items, labeled or not.
+    hits: usize,
+    true_positives: usize,
+    false_positives: usize,
+    false_negatives: usize,
+}
+
+impl RuleStats {
+    /// `expected` is `None` for unlabeled items.
+    fn record(&mut self, matched: bool, expected: Option<bool>) {
+        if matched {
+            self.hits += 1;
+        }
+        match (matched, expected) {
+            (true, Some(true)) => self.true_positives += 1,
+            (true, Some(false)) => self.false_positives += 1,
+            (false, Some(true)) => self.false_negatives += 1,
+            _ => {}
+        }
+    }
+
+    fn precision(&self) -> Option<f64> {
+        ratio(
+            self.true_positives,
+            self.true_positives + self.false_positives,
+        )
+    }
+
+    fn recall(&self) -> Option<f64> {
+        ratio(
+            self.true_positives,
+            self.true_positives + self.false_negatives,
+        )
+    }
+}
+
+fn ratio(numerator: usize, denominator: usize) -> Option<f64> {
+    if denominator == 0 {
+        None
+    } else {
+        Some(numerator as f64 / denominator as f64)
+    }
+}
+
+fn format_ratio(ratio: Option<f64>) -> String {
+    ratio
+        .map(|ratio| format!("{ratio:.3}"))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// Run a config's rules against a JSONL corpus and print which rules match each item,
+/// then a table of hits per rule, with precision and recall for labeled items.
+/// Doesn't touch the network or need credentials.
+pub fn test_rules(config_path: &Path, corpus_path: &Path) -> Result<()> {
+    let config = CompiledConfig::try_from(&Config::load_file(config_path)?)?;
+    let mut stats = config
+        .rules
+        .iter()
+        .map(|rule| (rule.name.clone(), RuleStats::default()))
+        .collect::<BTreeMap<_, _>>();
+    let mut items = 0;
+    let mut labeled_items = 0;
+
+    for (index, line) in BufReader::new(File::open(corpus_path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Some(item) = parse_item(index + 1, &line)
+            .map_err(|e| anyhow!("Line {line}: {e}", line = index + 1))?
+        else {
+            println!(
+                "line {line}: skipped: not a status or account",
+                line = index + 1
+            );
+            continue;
+        };
+        items += 1;
+        if item.expected.is_some() {
+            labeled_items += 1;
+        }
+
+        let mut matched = vec![];
+        for rule in config.rules.iter() {
+            let is_match = rule
+                .matchers
+                .iter()
+                .any(|matcher| matcher.is_match(&item.input));
+            let expected = item
+                .expected
+                .as_ref()
+                .map(|expected| expected.contains(&rule.name));
+            if let Some(rule_stats) = stats.get_mut(&rule.name) {
+                rule_stats.record(is_match, expected);
+            }
+            if is_match {
+                matched.push(rule);
+            }
+        }
+
+        println!(
+            "line {line}: {description}: {rules}",
+            line = item.line,
+            description = item.description,
+            rules = if matched.is_empty() {
+                "no rules matched".to_string()
+            } else {
+                matched
+                    .iter()
+                    .map(|rule| rule.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            }
+        );
+        for rule in matched.iter() {
+            let evidence = rule_evidence(rule, &item.input);
+            if !evidence.is_empty() {
+                println!(
+                    "    {name}: {evidence}",
+                    name = rule.name,
+                    evidence = evidence.join(", ")
+                );
+            }
+        }
+        if let Some(expected) = item.expected.as_ref() {
+            let matched = matched
+                .iter()
+                .map(|rule| rule.name.clone())
+                .collect::<BTreeSet<_>>();
+            for name in expected.difference(&matched) {
+                println!("    MISSED: {name}");
+            }
+            for name in matched.difference(expected) {
+                println!("    UNEXPECTED: {name}");
+            }
+        }
+    }
+
+    println!();
+    println!("{items} items, {labeled_items} labeled");
+    let name_width = stats.keys().map(String::len).max().unwrap_or(0).max(4);
+    println!(
+        "{:name_width$}  {:>6}  {:>6}  {:>6}  {:>6}  {:>9}  {:>6}",
+        "rule", "hits", "tp", "fp", "fn", "precision", "recall"
+    );
+    for rule in config.rules.iter() {
+        let Some(rule_stats) = stats.get(&rule.name) else {
+            continue;
+        };
+        println!(
+            "{:name_width$}  {:>6}  {:>6}  {:>6}  {:>6}  {:>9}  {:>6}",
+            rule.name,
+            rule_stats.hits,
+            rule_stats.true_positives,
+            rule_stats.false_positives,
+            rule_stats.false_negatives,
+            format_ratio(rule_stats.precision()),
+            format_ratio(rule_stats.recall()),
+        );
+    }
+
+    Ok(())
+}
+
+/// Parse one line of a corpus: a Mastodon status, an admin account, or a recorded webhook payload.
+/// Returns `None` for webhook events that our rules don't apply to, such as reports.
+fn parse_item(line: usize, json: &str) -> Result<Option<CorpusItem>> {
+    let value: Value = serde_json::from_str(json)?;
+    let expected = value
+        .get(EXPECTED_RULES_KEY)
+        .map(|expected| serde_json::from_value::<BTreeSet<String>>(expected.clone()))
+        .transpose()?;
+    let rspamd_result = match value.get(RSPAMD_RESULT_KEY) {
+        Some(result) => Some(serde_json::from_value::<RspamdResult>(result.clone())?),
+        None => value
+            .get(RSPAMD_ACTION_KEY)
+            .and_then(Value::as_str)
+            .map(|action| RspamdResult {
+                action: action.to_string(),
+                ..Default::default()
+            }),
+    };
+
+    let (description, mut input) = if value.get("event").is_some() {
+        match serde_json::from_value::<Event>(value)? {
+            Event::StatusCreated { status, .. } | Event::StatusUpdated { status, .. } => {
+                describe_status(&status)
+            }
+            Event::AccountApproved { account, .. }
+            | Event::AccountCreated { account, .. }
+            | Event::AccountUpdated { account, .. } => describe_account(&account),
+            _ => return Ok(None),
+        }
+    } else if value.get("content").is_some() {
+        describe_status(&serde_json::from_value::<Status>(value)?)
+    } else {
+        describe_account(&serde_json::from_value::<admin::Account>(value)?)
+    };
+    if let Some(rspamd_result) = rspamd_result {
+        input.rspamd(rspamd_result);
+    }
+
+    Ok(Some(CorpusItem {
+        line,
+        description,
+        input,
+        expected,
+    }))
+}
+
+fn describe_status(status: &Status) -> (String, RuleMatcherInput) {
+    (
+        format!(
+            "status {id} by {acct}",
+            id = status.id,
+            acct = status.account.acct
+        ),
+        RuleMatcherInput::from(status),
+    )
+}
+
+fn describe_account(account: &admin::Account) -> (String, RuleMatcherInput) {
+    (
+        format!(
+            "account {id} ({acct})",
+            id = account.id,
+            acct = account.account.acct
+        ),
+        RuleMatcherInput::from(account),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let mut stats = RuleStats::default();
+        stats.record(true, Some(true));
+        stats.record(true, Some(true));
+        stats.record(true, Some(false));
+        stats.record(false, Some(true));
+        stats.record(false, Some(false));
+        stats.record(true, None);
+
+        assert_eq!(4, stats.hits);
+        assert_eq!(Some(2.0 / 3.0), stats.precision());
+        assert_eq!(Some(2.0 / 3.0), stats.recall());
+        assert_eq!(None, RuleStats::default().precision());
+    }
+
+    #[test]
+    fn test_parse_rspamd_result() {
+        use crate::config::RulePattern;
+        use crate::pattern::CompileMatcher;
+
+        let mut value = crate::example::placeholder_status();
+        value[RSPAMD_RESULT_KEY] = serde_json::json!({
+            "action": "add header",
+            "score": 9.0,
+            "symbols": {"URIBL_BLACK": {"score": 7.5}},
+        });
+        let item = parse_item(1, &value.to_string()).unwrap().unwrap();
+        let pattern: RulePattern =
+            serde_yaml::from_str("rspamd: {symbol: {name: URIBL_BLACK, score: {at_least: 5}}}")
+                .unwrap();
+        assert!(pattern.compile().unwrap().is_match(&item.input));
+    }
+}
@@ -0,0 +1,62 @@
+use axum::Router;
+use std::future::Future;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// Empty directory for one test to write files in. Deleted when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// `name` keeps tests running in parallel from sharing a directory.
+    pub fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("m1000-{name}-test-{pid}", pid = std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).expect("Couldn't create temp dir");
+        Self(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Run a test's async code on a single-threaded runtime.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Couldn't build runtime")
+        .block_on(future)
+}
+
+/// Serve a stub HTTP server on a random local port until the runtime shuts down.
+/// Returns its base URL, without a trailing slash.
+pub async fn serve_stub(app: Router) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Couldn't bind stub server");
+    let addr = listener
+        .local_addr()
+        .expect("Couldn't get stub server address");
+    tokio::spawn(
+        axum::Server::from_tcp(listener)
+            .expect("Couldn't start stub server")
+            .serve(app.into_make_service()),
+    );
+    format!("http://{addr}")
+}
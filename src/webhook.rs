@@ -1,6 +1,7 @@
 use mastodon_async::entities::{
     admin::{Account, Report},
     status::Status,
+    AccountId,
 };
 use serde::{Deserialize, Serialize};
 use time::{serde::iso8601, OffsetDateTime};
@@ -68,3 +69,89 @@ pub enum Event {
     #[serde(other)]
     Unknown,
 }
+
+impl Event {
+    /// Mastodon's name for this event type.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::AccountApproved { .. } => "account.approved",
+            Self::AccountCreated { .. } => "account.created",
+            Self::AccountUpdated { .. } => "account.updated",
+            Self::ReportCreated { .. } => "report.created",
+            Self::ReportUpdated { .. } => "report.updated",
+            Self::StatusCreated { .. } => "status.created",
+            Self::StatusUpdated { .. } => "status.updated",
+            Self::Unknown => "unknown",
+        }
+    }
+
+    /// The account that this event is about, so that events for the same account can be handled in order.
+    /// For reports, this is the reported account.
+    pub fn account_id(&self) -> Option<&AccountId> {
+        match self {
+            Self::AccountApproved { account, .. }
+            | Self::AccountCreated { account, .. }
+            | Self::AccountUpdated { account, .. } => Some(&account.id),
+            Self::ReportCreated { report, .. } | Self::ReportUpdated { report, .. } => {
+                Some(&report.target_account.id)
+            }
+            Self::StatusCreated { status, .. } | Self::StatusUpdated { status, .. } => {
+                Some(&status.account.id)
+            }
+            Self::Unknown => None,
+        }
+    }
+
+    /// When Mastodon created this event. Retries of the same event have the same timestamp.
+    pub fn created_at(&self) -> Option<OffsetDateTime> {
+        match self {
+            Self::AccountApproved { created_at, .. }
+            | Self::AccountCreated { created_at, .. }
+            | Self::AccountUpdated { created_at, .. }
+            | Self::ReportCreated { created_at, .. }
+            | Self::ReportUpdated { created_at, .. }
+            | Self::StatusCreated { created_at, .. }
+            | Self::StatusUpdated { created_at, .. } => Some(*created_at),
+            Self::Unknown => None,
+        }
+    }
+
+    /// ID of the account, report, or status that this event is about.
+    pub fn object_id(&self) -> Option<String> {
+        match self {
+            Self::AccountApproved { account, .. }
+            | Self::AccountCreated { account, .. }
+            | Self::AccountUpdated { account, .. } => Some(account.id.to_string()),
+            Self::ReportCreated { report, .. } | Self::ReportUpdated { report, .. } => {
+                Some(report.id.to_string())
+            }
+            Self::StatusCreated { status, .. } | Self::StatusUpdated { status, .. } => {
+                Some(status.id.to_string())
+            }
+            Self::Unknown => None,
+        }
+    }
+
+    /// Identifies what happened, the same way whether the event came from a webhook, the streaming API, or polling,
+    /// so that something that arrives from more than one of them is only handled once.
+    /// Edits are told apart by when they happened. Other updates can't be, so they don't have a key.
+    pub fn source_key(&self) -> Option<String> {
+        match self {
+            Self::AccountApproved { .. }
+            | Self::AccountCreated { .. }
+            | Self::ReportCreated { .. }
+            | Self::StatusCreated { .. } => Some(format!(
+                "{name}:{object_id}",
+                name = self.name(),
+                object_id = self.object_id()?
+            )),
+            Self::StatusUpdated { status, .. } => Some(format!(
+                "{name}:{object_id}:{edited_at:?}",
+                name = self.name(),
+                object_id = status.id,
+                edited_at = status.edited_at.as_ref()?
+            )),
+            Self::AccountUpdated { .. } | Self::ReportUpdated { .. } | Self::Unknown => None,
+        }
+    }
+}
@@ -15,6 +15,24 @@ pub struct XHubSignature {
 }
 
 impl XHubSignature {
+    /// Sign a body the way Mastodon does, for replaying recorded webhook events.
+    pub fn sign(secret: &[u8], body: &[u8]) -> Self {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(secret).expect("HMAC can take key of any size");
+        mac.update(body);
+        Self {
+            algorithm: XHubSignatureAlgorithm::Sha256,
+            signature: mac.finalize().into_bytes().to_vec(),
+        }
+    }
+
+    /// Value for the `X-Hub-Signature` header.
+    pub fn header_value(&self) -> String {
+        let signature = hex::encode(&self.signature);
+        let algorithm: &str = self.algorithm.into();
+        format!("{algorithm}={signature}")
+    }
+
     pub fn is_valid(&self, secret: &[u8], body: &[u8]) -> bool {
         match self.algorithm {
             XHubSignatureAlgorithm::Sha1 => {
@@ -85,10 +103,7 @@ impl Header for XHubSignature {
     }
 
     fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
-        let signature = hex::encode(&self.signature);
-        let algorithm: &str = self.algorithm.into();
-        let formatted = format!("{algorithm}={signature}");
-        if let Ok(header_value) = HeaderValue::from_str(&formatted) {
+        if let Ok(header_value) = HeaderValue::from_str(&self.header_value()) {
             values.extend([header_value].into_iter());
         }
     }